@@ -26,6 +26,15 @@ impl Aabb {
 
     true
   }
+
+  pub fn surface_area(&self) -> f32 {
+    let d = self.max - self.min;
+    2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+  }
+
+  pub fn centroid(&self) -> Vec3 {
+    0.5 * (self.min + self.max)
+  }
 }
 
 pub fn surrounding_box(box0: &Aabb, box1: &Aabb) -> Aabb {
@@ -1,168 +1,461 @@
 extern crate rand;
 use rand::Rng;
+use std::f32;
 use std::fmt;
+use std::mem;
 use std::cmp::Ordering;
 
 use ::aabb::{Aabb, surrounding_box};
 use ::ray::Ray;
+use ::vec3::Vec3;
 use ::material::HitRecord;
 use ::geometry::Hitable;
 
+// Relative costs of visiting a node and intersecting a hitable, used by the
+// surface area heuristic and for estimating the cost of a tree.
+const TRAVERSAL_COST: f32 = 0.125;
+const INTERSECTION_COST: f32 = 1.0;
+
+/// How a `BvhTree` decides where to split its hitables. Leaves hold at most
+/// `max_leaf_size` hitables.
+#[derive(Copy, Clone, Debug)]
+pub enum BvhBuildMethod {
+  /// Split at the median along a random axis.
+  RandomMedian { max_leaf_size: usize },
+  /// Split where the surface area heuristic estimates the lowest cost, evaluating
+  /// `bins` candidate splits per axis.
+  Sah { bins: usize, max_leaf_size: usize }
+}
+
+/// Bounding volume hierarchy over a list of hitables.
+///
+/// Nodes are stored in depth first order, so the first child of an interior
+/// node always directly follows it, and the tree is traversed without recursion.
+/// The hitables are sorted so that each leaf refers to a contiguous range of them.
+///
+/// The tree owns its hitables, and is itself a `Hitable`, so a tree can be
+/// placed inside another tree, or shared between scenes through an `Arc`.
 #[derive(Debug)]
-pub struct BvhTree<'a> {
-  nodes: Vec<BvhNode<'a>>,
-  root: NodeId
+pub struct BvhTree {
+  nodes: Vec<BvhNode>,
+  hitables: Vec<Box<Hitable>>,
+  // Indices of the hitables that are lights
+  lights: Vec<usize>
 }
 
 #[derive(Debug)]
-struct BvhNode<'a> {
-  left: Option<NodeId>,
-  right: Option<NodeId>,
+struct BvhNode {
   aabb: Option<Aabb>,
-  hitable: Option<&'a Box<Hitable>>
+  // For interior nodes, index of the second child; for leaves, index of the first hitable
+  offset: u32,
+  // Number of hitables in a leaf, zero for interior nodes
+  count: u16,
+  // Axis the children of an interior node were split along, the first child
+  // being the one towards -axis
+  axis: u8
 }
 
+impl BvhNode {
+  fn is_leaf(&self) -> bool {
+    self.count > 0
+  }
+}
+
+/// Size and estimated traversal cost of a `BvhTree`.
 #[derive(Copy, Clone, Debug)]
-pub struct NodeId {
-  index: usize
+pub struct BvhStats {
+  pub nodes: usize,
+  pub leaves: usize,
+  pub max_depth: usize,
+  /// Expected cost of tracing a random ray through the tree, from the
+  /// surface area heuristic, in units of hitable intersection tests.
+  pub expected_cost: f32,
+  /// Bytes used by the tree's nodes, not counting the hitables themselves.
+  pub memory: usize
 }
 
-impl<'a> BvhTree<'a> {
-  fn hit(&self, id: NodeId, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
-    let node = &self.nodes[id.index];
+impl Hitable for BvhTree {
+  fn bounding_box(&self) -> Option<Aabb> {
+    self.nodes.first().and_then(|root| root.aabb)
+  }
+
+  fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
+    let mut closest: Option<HitRecord> = None;
+    let mut closest_t = tmax;
 
-    if node.aabb.is_none() || node.aabb.is_some() && node.aabb.unwrap().hit(r, tmin, tmax) {
-      match node.hitable {
-        Some(ref hitable) => return hitable.hit(r, tmin, tmax),
-        None => { }
+    self.traverse(r, |node| {
+      if !node.aabb.map_or(true, |b| b.hit(r, tmin, closest_t)) {
+        return Traversal::Skip;
       }
 
-      let mut hit_left: Option<HitRecord> = None;
-      let mut hit_right: Option<HitRecord> = None;
-
-      if let Some(ref left_index) = node.left {
-        hit_left = self.hit(*left_index, r, tmin, tmax);
+      for hitable in self.leaf_hitables(node) {
+        if let Some(hit) = hitable.hit(r, tmin, closest_t) {
+          closest_t = hit.t;
+          closest = Some(hit);
+        }
       }
 
-      if let Some(ref right_index) = node.right {
-        hit_right = self.hit(*right_index, r, tmin, tmax);
+      Traversal::Continue
+    });
+
+    closest
+  }
+
+  fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    let mut found = false;
+
+    self.traverse(r, |node| {
+      if !node.aabb.map_or(true, |b| b.hit(r, tmin, tmax)) {
+        return Traversal::Skip;
       }
 
-      match hit_left {
-        Some(left) => {
-          match hit_right {
-            Some(right) => if left.t < right.t { return hit_left; } else { return hit_right; },
-            None => return hit_left
+      found = self.leaf_hitables(node).iter().any(|hitable| hitable.any_hit(r, tmin, tmax));
+      if found { Traversal::Stop } else { Traversal::Continue }
+    });
+
+    found
+  }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    if self.lights.is_empty() {
+      return 0.0;
+    }
+
+    self.lights.iter().map(|&i| self.hitables[i].pdf_value(o, v)).sum::<f32>() / self.lights.len() as f32
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    match self.random_light() {
+      Some(light) => light.random(o),
+      None => Vec3::new(1.0, 0.0, 0.0)
+    }
+  }
+
+  fn is_light(&self) -> bool {
+    !self.lights.is_empty()
+  }
+}
+
+enum Traversal {
+  Continue,
+  Skip,
+  Stop
+}
+
+impl BvhTree {
+  // Visits nodes depth first, nearest child first, as long as `visit` returns
+  // `Traversal::Continue`. Children of a node are skipped if it returns `Traversal::Skip`.
+  fn traverse<F>(&self, r: &Ray, mut visit: F) where F: FnMut(&BvhNode) -> Traversal {
+    if self.nodes.is_empty() {
+      return;
+    }
+
+    let direction_is_negative = [r.direction[0] < 0.0, r.direction[1] < 0.0, r.direction[2] < 0.0];
+    let mut stack: Vec<usize> = Vec::with_capacity(32);
+    let mut current = 0;
+
+    loop {
+      let node = &self.nodes[current];
+      match visit(node) {
+        Traversal::Stop => return,
+        Traversal::Continue if !node.is_leaf() => {
+          if direction_is_negative[node.axis as usize] {
+            stack.push(current + 1);
+            current = node.offset as usize;
+          } else {
+            stack.push(node.offset as usize);
+            current += 1;
           }
+          continue;
         },
-        None => {}
+        _ => {}
       }
 
-      match hit_right {
-        Some(_right) => return hit_right,
-        None => {}
+      match stack.pop() {
+        Some(next) => current = next,
+        None => return
       }
     }
+  }
+
+  fn leaf_hitables(&self, node: &BvhNode) -> &[Box<Hitable>] {
+    if !node.is_leaf() {
+      return &[];
+    }
 
-    None
+    let first = node.offset as usize;
+    &self.hitables[first..first + node.count as usize]
   }
-}
 
-impl<'a> Hitable for BvhTree<'a> {
-  fn bounding_box(&self) -> Option<Aabb> {
-    self.nodes[self.root.index].aabb
+  pub fn new(l: Vec<Box<Hitable>>) -> BvhTree {
+    BvhTree::with_method(l, BvhBuildMethod::RandomMedian { max_leaf_size: 1 })
   }
 
-  fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
-    self.hit(self.root, r, tmin, tmax)
+  pub fn with_method(mut l: Vec<Box<Hitable>>, method: BvhBuildMethod) -> BvhTree {
+    let mut nodes = Vec::with_capacity(2 * l.len());
+    // An empty tree has no nodes at all, and is never hit
+    if !l.is_empty() {
+      match method {
+        BvhBuildMethod::RandomMedian { max_leaf_size } =>
+          build(&mut nodes, &mut l, 0, clamp_leaf_size(max_leaf_size)),
+        BvhBuildMethod::Sah { bins, max_leaf_size } =>
+          build_sah(&mut nodes, &mut l, 0, bins.max(2), clamp_leaf_size(max_leaf_size))
+      };
+    }
+
+    nodes.shrink_to_fit();
+    let lights = (0..l.len()).filter(|&i| l[i].is_light()).collect();
+
+    BvhTree { nodes, hitables: l, lights }
+  }
+
+  /// All hitables in the tree's leaves.
+  pub fn hitables(&self) -> &[Box<Hitable>] {
+    &self.hitables
+  }
+
+  /// Takes the tree apart, giving back its hitables, for example to build another tree over them.
+  pub fn into_hitables(self) -> Vec<Box<Hitable>> {
+    self.hitables
   }
-}
 
-impl<'a> BvhTree<'a> {
-  pub fn new(l: &'a mut [Box<Hitable>]) -> BvhTree<'a> {
-    let mut tree = BvhTree { nodes: Vec::new(), root: NodeId { index: 0 } };
-    tree.root = tree.build(l);
+  /// The hitables that are lights.
+  pub fn lights(&self) -> Vec<&Box<Hitable>> {
+    self.lights.iter().map(|&i| &self.hitables[i]).collect()
+  }
 
-    tree
+  /// Number of emitting hitables in the tree.
+  pub fn light_count(&self) -> usize {
+    self.lights.len()
   }
 
-  fn build(&mut self, l: &'a mut [Box<Hitable>]) -> NodeId {
-    let axis = rand::thread_rng().gen_range::<i32>(0, 3);
+  /// A random one of the tree's lights, each being equally likely to be picked,
+  /// if there are any lights.
+  pub fn random_light(&self) -> Option<&Box<Hitable>> {
+    if self.lights.is_empty() {
+      return None;
+    }
+
+    Some(&self.hitables[self.lights[rand::thread_rng().gen_range(0, self.lights.len())]])
+  }
 
-    match axis {
-      0 => l.sort_by(|a, b| box_x_compare(a, b)),
-      1 => l.sort_by(|a, b| box_y_compare(a, b)),
-      2 => l.sort_by(|a, b| box_z_compare(a, b)),
-      _ => panic!("Unexpected axis")
+  pub fn stats(&self) -> BvhStats {
+    let mut stats = BvhStats { nodes: self.nodes.len(), leaves: 0, max_depth: 0, expected_cost: 0.0, memory: self.memory() };
+    let root_area = self.bounding_box().map_or(0.0, |b| b.surface_area());
+    let mut stack = if self.nodes.is_empty() { vec![] } else { vec![(0, 1)] };
+
+    while let Some((index, depth)) = stack.pop() {
+      let node = &self.nodes[index];
+      // The chance of a ray hitting the root also hitting this node
+      let probability = match node.aabb {
+        Some(b) if root_area > 0.0 => b.surface_area() / root_area,
+        _ => 1.0
+      };
+
+      stats.max_depth = stats.max_depth.max(depth);
+      if node.is_leaf() {
+        stats.leaves += 1;
+        stats.expected_cost += probability * INTERSECTION_COST * node.count as f32;
+      } else {
+        stats.expected_cost += probability * TRAVERSAL_COST;
+        stack.push((index + 1, depth + 1));
+        stack.push((node.offset as usize, depth + 1));
+      }
     }
 
-    let left: NodeId;
-    let right: NodeId;
+    stats
+  }
+
+  fn memory(&self) -> usize {
+    mem::size_of::<BvhTree>() + self.nodes.capacity() * mem::size_of::<BvhNode>()
+  }
+}
+
+// Leaf sizes are stored in 16 bits, and a leaf holds at least one hitable
+fn clamp_leaf_size(max_leaf_size: usize) -> usize {
+  max_leaf_size.max(1).min(u16::max_value() as usize)
+}
+
+// Builds the tree for hitables `l`, which start at index `offset` of all the
+// tree's hitables, returning the index of its root node.
+fn build(nodes: &mut Vec<BvhNode>, l: &mut [Box<Hitable>], offset: usize, max_leaf_size: usize) -> usize {
+  if l.len() <= max_leaf_size {
+    return new_leaf(nodes, l, offset);
+  }
+
+  let axis = rand::thread_rng().gen_range::<usize>(0, 3);
+
+  match axis {
+    0 => l.sort_by(|a, b| box_x_compare(a, b)),
+    1 => l.sort_by(|a, b| box_y_compare(a, b)),
+    2 => l.sort_by(|a, b| box_z_compare(a, b)),
+    _ => panic!("Unexpected axis")
+  }
+
+  let index = new_interior(nodes);
+  let half_len = l.len() / 2;
+  let (left_hitables, right_hitables) = l.split_at_mut(half_len);
+
+  build(nodes, left_hitables, offset, max_leaf_size);
+  let second_child = build(nodes, right_hitables, offset + half_len, max_leaf_size);
+
+  finish_interior(nodes, index, second_child, axis)
+}
 
-    if l.len() == 1 {
-      return self.new_leaf(&l[0]);
-    } else if l.len() == 2 {
-      left = self.new_leaf(&l[0]);
-      right = self.new_leaf(&l[1]);
-    } else {
-      let half_len = l.len() / 2;
-      let (left_hitables, right_hitables) = l.split_at_mut(half_len);
+fn build_sah(nodes: &mut Vec<BvhNode>, l: &mut [Box<Hitable>], offset: usize, bins: usize, max_leaf_size: usize) -> usize {
+  if l.len() == 1 {
+    return new_leaf(nodes, l, offset);
+  }
 
-      left = self.build(left_hitables);
-      right = self.build(right_hitables);
+  let boxes: Vec<Aabb> = l.iter().map(|h| h.bounding_box().expect("No bounding box in BvhTree::build_sah")).collect();
+  let bounds = boxes.iter().skip(1).fold(boxes[0], |b, h| surrounding_box(&b, h));
+  let centroids = boxes.iter().skip(1).fold(
+    Aabb { min: boxes[0].centroid(), max: boxes[0].centroid() },
+    |b, h| surrounding_box(&b, &Aabb { min: h.centroid(), max: h.centroid() }));
+
+  // Find the cheapest split, as (cost, axis, number of hitables to the left)
+  let mut best: Option<(f32, usize, usize)> = None;
+  for axis in 0..3 {
+    let extent = centroids.max[axis] - centroids.min[axis];
+    if extent <= 0.0 {
+      continue;
     }
 
-    if let Some(left_box) = self.nodes[left.index].aabb {
-      if let Some(right_box) = self.nodes[right.index].aabb {
-        return self.new_node(surrounding_box(&left_box, &right_box), Some(left), Some(right))
+    let mut counts = vec![0; bins];
+    let mut bin_boxes: Vec<Option<Aabb>> = vec![None; bins];
+    for b in boxes.iter() {
+      let bin = (((b.centroid()[axis] - centroids.min[axis]) / extent * bins as f32) as usize).min(bins - 1);
+      counts[bin] += 1;
+      bin_boxes[bin] = Some(match bin_boxes[bin] {
+        Some(bin_box) => surrounding_box(&bin_box, b),
+        None => *b
+      });
+    }
+
+    for split in 1..bins {
+      let (left_count, left_area) = binned_area(&counts[..split], &bin_boxes[..split]);
+      let (right_count, right_area) = binned_area(&counts[split..], &bin_boxes[split..]);
+      if left_count == 0 || right_count == 0 {
+        continue;
+      }
+
+      let cost = TRAVERSAL_COST + INTERSECTION_COST *
+        (left_area * left_count as f32 + right_area * right_count as f32) / bounds.surface_area();
+      if best.map_or(true, |(best_cost, _, _)| cost < best_cost) {
+        best = Some((cost, axis, left_count));
       }
     }
+  }
 
-    panic!("No bounding box in BvhNode::build");
+  let leaf_cost = INTERSECTION_COST * l.len() as f32;
+  if l.len() <= max_leaf_size && best.map_or(true, |(cost, _, _)| leaf_cost <= cost) {
+    return new_leaf(nodes, l, offset);
   }
 
-  fn new_leaf(&mut self, hitable: &'a Box<Hitable>) -> NodeId {
-    let next_index = self.nodes.len();
+  let (axis, split_index) = match best {
+    Some((_, axis, left_count)) => {
+      l.sort_by(|a, b| centroid_compare(a, b, axis));
+      (axis, left_count)
+    },
+    // All centroids coincide, so any split is as good as another
+    None => (0, l.len() / 2)
+  };
+
+  let index = new_interior(nodes);
+  let (left_hitables, right_hitables) = l.split_at_mut(split_index);
+  build_sah(nodes, left_hitables, offset, bins, max_leaf_size);
+  let second_child = build_sah(nodes, right_hitables, offset + split_index, bins, max_leaf_size);
+
+  finish_interior(nodes, index, second_child, axis)
+}
 
-    self.nodes.push(BvhNode {
-      left: None,
-      right: None,
-      aabb: hitable.bounding_box(),
-      hitable: Some(hitable)
-    });
+// Adds an interior node, to be finished once its children have been built
+fn new_interior(nodes: &mut Vec<BvhNode>) -> usize {
+  let next_index = nodes.len();
 
-    return NodeId { index: next_index };
-  }
+  nodes.push(BvhNode {
+    aabb: None,
+    offset: 0,
+    count: 0,
+    axis: 0
+  });
 
-  fn new_node(&mut self, aabb: Aabb, left: Option<NodeId>, right: Option<NodeId>) -> NodeId {
-    let next_index = self.nodes.len();
+  next_index
+}
 
-    self.nodes.push(BvhNode {
-      left,
-      right,
-      aabb: Some(aabb),
-      hitable: None
-    });
+fn finish_interior(nodes: &mut Vec<BvhNode>, index: usize, second_child: usize, axis: usize) -> usize {
+  if let Some(left_box) = nodes[index + 1].aabb {
+    if let Some(right_box) = nodes[second_child].aabb {
+      let node = &mut nodes[index];
+      node.aabb = Some(surrounding_box(&left_box, &right_box));
+      node.offset = second_child as u32;
+      node.axis = axis as u8;
 
-    return NodeId { index: next_index };
+      return index;
+    }
   }
 
-  fn number_hittables(&self, id: NodeId) -> usize {
-    let node = &self.nodes[id.index];
-    let local_hitable = if node.hitable.is_some() { 1 } else { 0 };
-    let count_left = if let Some(left_index) = node.left { self.number_hittables(left_index) } else { 0 };
-    let count_right = if let Some(right_index) = node.right { self.number_hittables(right_index) } else { 0 };
+  panic!("No bounding box in BvhNode::build");
+}
 
-    local_hitable + count_left + count_right
+fn new_leaf(nodes: &mut Vec<BvhNode>, hitables: &[Box<Hitable>], offset: usize) -> usize {
+  let next_index = nodes.len();
+  let aabb = hitables.iter().skip(1).fold(hitables[0].bounding_box(), |b, h| {
+    match (b, h.bounding_box()) {
+      (Some(b), Some(h)) => Some(surrounding_box(&b, &h)),
+      _ => None
+    }
+  });
+
+  nodes.push(BvhNode {
+    aabb,
+    offset: offset as u32,
+    count: hitables.len() as u16,
+    axis: 0
+  });
+
+  next_index
+}
+
+impl fmt::Display for BvhTree {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "BVH with {:?} hitables and {:?} nodes, using {:.1} KiB", self.hitables.len(), self.nodes.len(), self.memory() as f32 / 1024.0)
   }
 }
 
-impl<'a> fmt::Display for BvhTree<'a> { 
+impl fmt::Display for BvhStats {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "BVH with {:?} hitables and {:?} nodes", self.number_hittables(self.root), self.nodes.len())
+    write!(f, "{} nodes, {} leaves, depth {}, expected cost {:.2}, {:.1} KiB",
+      self.nodes, self.leaves, self.max_depth, self.expected_cost, self.memory as f32 / 1024.0)
   }
 }
 
+// Number of hitables and surface area of the box around a range of SAH bins
+fn binned_area(counts: &[usize], boxes: &[Option<Aabb>]) -> (usize, f32) {
+  let count = counts.iter().sum();
+  let bounds = boxes.iter().fold(None, |acc: Option<Aabb>, b| match (acc, *b) {
+    (Some(acc), Some(b)) => Some(surrounding_box(&acc, &b)),
+    (None, b) => b,
+    (acc, None) => acc
+  });
+
+  (count, bounds.map_or(0.0, |b| b.surface_area()))
+}
+
+fn centroid_compare(a: &Box<Hitable>, b: &Box<Hitable>, axis: usize) -> Ordering {
+  if let Some(box_left) = a.bounding_box() {
+    if let Some(box_right) = b.bounding_box() {
+      if let Some(cmp) = box_left.centroid()[axis].partial_cmp(&box_right.centroid()[axis]) {
+        return cmp;
+      } else {
+        panic!("Can't compare");
+      }
+    }
+  }
+
+  panic!("No bounding box in BvhNode::new");
+}
+
 fn box_x_compare(a: &Box<Hitable>, b: &Box<Hitable>) -> Ordering {
   if let Some(box_left) = a.bounding_box() {
     if let Some(box_right) = b.bounding_box() {
@@ -204,3 +497,113 @@ fn box_z_compare(a: &Box<Hitable>, b: &Box<Hitable>) -> Ordering {
 
   panic!("No bounding box in BvhNode::new");
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use ::geometry::Sphere;
+  use ::material::{Lambertian, ConstantTexture};
+
+  // A few large spheres next to a dense cluster of tiny ones, which a
+  // median split handles badly
+  fn uneven_spheres() -> Vec<Box<Hitable>> {
+    let material = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) });
+    let mut spheres: Vec<Box<Hitable>> = Vec::new();
+    for i in 0..4 {
+      spheres.push(Box::new(Sphere { center: Vec3::new(i as f32 * 10.0, 0.0, 0.0), radius: 4.0, material: material.clone() }));
+    }
+    for i in 0..200 {
+      let x = 100.0 + (i % 10) as f32 * 0.1;
+      let y = (i / 10) as f32 * 0.1;
+      spheres.push(Box::new(Sphere { center: Vec3::new(x, y, 0.0), radius: 0.04, material: material.clone() }));
+    }
+
+    spheres
+  }
+
+  fn closest_t(hit: Option<HitRecord>) -> Option<f32> {
+    hit.map(|h| h.t)
+  }
+
+  fn assert_same_hits_as_list(method: BvhBuildMethod) {
+    let reference = uneven_spheres();
+    let tree = BvhTree::with_method(uneven_spheres(), method);
+
+    for i in 0..400 {
+      let target = Vec3::new((i % 110) as f32, (i / 110) as f32 * 0.5, 0.0);
+      let r = Ray::new(Vec3::new(50.0, 1.0, 30.0), target - Vec3::new(50.0, 1.0, 30.0));
+      assert_eq!(closest_t(reference.hit(&r, 0.001, f32::MAX)), closest_t(tree.hit(&r, 0.001, f32::MAX)));
+    }
+  }
+
+  #[test]
+  fn sah_tree_finds_same_hits_as_list() {
+    assert_same_hits_as_list(BvhBuildMethod::Sah { bins: 16, max_leaf_size: 4 });
+  }
+
+  #[test]
+  fn median_tree_with_large_leaves_finds_same_hits_as_list() {
+    assert_same_hits_as_list(BvhBuildMethod::RandomMedian { max_leaf_size: 8 });
+  }
+
+  #[test]
+  fn nested_trees_find_same_hits_as_list() {
+    let reference = uneven_spheres();
+    let mut spheres = uneven_spheres();
+    let cluster = spheres.split_off(4);
+    spheres.push(Box::new(BvhTree::with_method(cluster, BvhBuildMethod::Sah { bins: 16, max_leaf_size: 4 })));
+    let tree = BvhTree::new(spheres);
+
+    assert_eq!(5, tree.hitables().len());
+    for i in 0..400 {
+      let target = Vec3::new((i % 110) as f32, (i / 110) as f32 * 0.5, 0.0);
+      let r = Ray::new(Vec3::new(50.0, 1.0, 30.0), target - Vec3::new(50.0, 1.0, 30.0));
+      assert_eq!(closest_t(reference.hit(&r, 0.001, f32::MAX)), closest_t(tree.hit(&r, 0.001, f32::MAX)));
+    }
+  }
+
+  #[test]
+  fn leaves_hold_ranges_of_hitables() {
+    let tree = BvhTree::with_method(uneven_spheres(), BvhBuildMethod::RandomMedian { max_leaf_size: 8 });
+    let stats = tree.stats();
+
+    // 204 hitables halved until at most 8 remain gives 32 leaves
+    assert_eq!(32, stats.leaves);
+    assert_eq!(63, stats.nodes);
+  }
+
+  #[test]
+  fn any_hit_agrees_with_hit() {
+    let tree = BvhTree::new(uneven_spheres());
+
+    for i in 0..400 {
+      let target = Vec3::new((i % 110) as f32, (i / 110) as f32 * 0.5, 0.0);
+      let r = Ray::new(Vec3::new(50.0, 1.0, 30.0), target - Vec3::new(50.0, 1.0, 30.0));
+      for &tmax in [0.5, 0.99, f32::MAX].iter() {
+        assert_eq!(tree.hit(&r, 0.001, tmax).is_some(), tree.any_hit(&r, 0.001, tmax));
+      }
+    }
+  }
+
+  #[test]
+  fn empty_tree_is_never_hit() {
+    for &method in [BvhBuildMethod::RandomMedian { max_leaf_size: 4 }, BvhBuildMethod::Sah { bins: 16, max_leaf_size: 4 }].iter() {
+      let tree = BvhTree::with_method(vec![], method);
+      let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+
+      assert!(tree.hit(&r, 0.001, f32::MAX).is_none());
+      assert!(!tree.any_hit(&r, 0.001, f32::MAX));
+      assert!(tree.bounding_box().is_none());
+      assert_eq!(0, tree.stats().leaves);
+    }
+  }
+
+  #[test]
+  fn sah_tree_is_cheaper_than_median() {
+    let median_cost = BvhTree::with_method(uneven_spheres(), BvhBuildMethod::RandomMedian { max_leaf_size: 4 }).stats().expected_cost;
+    let sah_cost = BvhTree::with_method(uneven_spheres(), BvhBuildMethod::Sah { bins: 16, max_leaf_size: 4 }).stats().expected_cost;
+
+    assert!(sah_cost < median_cost, "SAH cost {} not below median cost {}", sah_cost, median_cost);
+  }
+}
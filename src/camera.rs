@@ -2,7 +2,7 @@ extern crate rand;
 
 use std::f32;
 use ::vec3::*;
-use ::ray::Ray;
+use ::ray::{Ray, RayDifferentials};
 
 #[derive(Debug)]
 pub struct Camera {
@@ -17,6 +17,8 @@ pub struct Camera {
 }
 
 impl Camera {
+  /// A camera at `lookfrom`, looking at `lookat`, with a vertical field of view of `vfov` degrees.
+  /// A non-zero `aperture` gives depth of field, with objects at `focus_dist` in focus.
   pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, vfov: f32, aspect: f32, aperture: f32, focus_dist: f32) -> Camera {
     let theta = vfov * f32::consts::PI / 180.0;
     let half_height = f32::tan(theta / 2.0);
@@ -38,11 +40,24 @@ impl Camera {
     }
   }
 
+  /// A ray through the point `s`, `t` of the image, each running from 0 to 1.
+  /// The ray's differentials are per unit of `s` and `t`, so they need to be
+  /// scaled by the size of a pixel.
   pub fn get_ray(&self, s: f32, t: f32) -> Ray {
     let rd = self.lens_radius * random_in_unit_disk();
     let offset = rd.x() * self.u + rd.y() * self.v;
+    let zero = Vec3::new(0.0, 0.0, 0.0);
 
-    Ray::new(self.origin + offset, self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset)
+    Ray {
+      origin: self.origin + offset,
+      direction: self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
+      differentials: Some(RayDifferentials {
+        origin_dx: zero,
+        direction_dx: self.horizontal,
+        origin_dy: zero,
+        direction_dy: self.vertical
+      })
+    }
   }
 }
 
@@ -0,0 +1,105 @@
+extern crate rand;
+
+use std::sync::Arc;
+
+use ::vec3::Vec3;
+use ::mat44::Mat44;
+use ::geometry::*;
+use ::material::*;
+
+/// The final scene from Ray Tracing in One Weekend: lots of small, randomly
+/// placed spheres around three larger ones.
+pub fn random_scene() -> Vec<Box<Hitable>> {
+  let scene_c = Vec3::new(4.0, 0.0, 2.0);
+
+  let checker = CheckerTexture { odd: Box::new(ConstantTexture::new(0.2, 0.3, 0.1)), even: Box::new(ConstantTexture::new(0.9, 0.9, 0.9)) };
+
+  let mut models: Vec<Box<Hitable>> = vec![
+    Box::new(Sphere { center: Vec3::new(0.0, -1000.0, 0.0), radius: 1000.0, material: Arc::new(Lambertian { albedo: Box::new(checker) }) }),
+    Box::new(Sphere { center: Vec3::new(0.0, 1.0, 0.0), radius: 1.0, material: Arc::new(Dielectric { ref_idx: 1.5 }) }),
+    Box::new(Sphere { center: Vec3::new(-4.0, 1.0, 0.0), radius: 1.0, material: Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.4, 0.2, 0.1)) }) }),
+    Box::new(Sphere { center: Vec3::new(4.0, 1.0, 0.0), radius: 1.0, material: Arc::new(Metal { albedo: Box::new(ConstantTexture::new(0.7, 0.6, 0.5)), fuzz: 0.0 }) }),
+  ];
+
+  for a in -11..11 {
+    for b in -11..11 {
+      let center = Vec3::new((a as f32) + 0.9 * rand::random::<f32>(), 0.2, (b as f32) + 0.9 * rand::random::<f32>());
+
+      if (center - scene_c).length() > 0.9 {
+        let choose_mat = rand::random::<f32>();
+        let material: Arc<Material>;
+
+        if choose_mat < 0.8 {
+          material = Arc::new(Lambertian {
+            albedo: Box::new(ConstantTexture::new(
+                          rand::random::<f32>() * rand::random::<f32>(),
+                          rand::random::<f32>() * rand::random::<f32>(),
+                          rand::random::<f32>() * rand::random::<f32>()))
+          });
+        } else if choose_mat < 0.95 {
+          material = Arc::new(Metal {
+            albedo: Box::new(ConstantTexture::new(
+              0.5 * (1.0 + rand::random::<f32>()),
+              0.5 * (1.0 + rand::random::<f32>()),
+              0.5 * (1.0 + rand::random::<f32>()))),
+            fuzz: 0.5 * rand::random::<f32>(),
+          })
+        } else {
+          material = Arc::new(Dielectric { ref_idx: 1.5 })
+        }
+
+        models.push(Box::new(Sphere { center, radius: 0.2, material }));
+      }
+
+    }
+  }
+
+  let world: Vec<Box<Hitable>> = models.into_iter().map(|s| s as Box<Hitable>).collect();
+  world
+}
+
+/// The Cornell box from Ray Tracing the Next Week, with a glass sphere filled
+/// with a blue medium and a thin fog filling the room.
+pub fn cornell_box() -> Vec<Box<Hitable>> {
+  let red = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.65, 0.05, 0.05)) });
+  let white: Arc<Material> = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.73, 0.73, 0.73)) });
+  let green: Arc<Material> = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.12, 0.45, 0.15)) });
+  let light = Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(16.0, 16.0, 16.0)) });
+  let dielectric: Arc<Material> = Arc::new(Dielectric { ref_idx: 1.8 });
+  let subsurface: Vec<Box<Hitable>> = vec![
+    Box::new(Sphere {center: Vec3::new(0.0, 0.0, 0.0), radius: 60.0, material: Arc::clone(&dielectric) }),
+    Box::new(ConstantMedium::new(
+      Box::new(Sphere {center: Vec3::new(0.0, 0.0, 0.0), radius: 60.0, material: Arc::clone(&white) }),
+      0.2,
+      Box::new(ConstantTexture::new(0.2, 0.4, 0.9))))
+  ];
+
+  vec![
+    Box::new(FlipNormals { hitable: Box::new(YzRect { y0: 0.0, y1: 555.0, z0: 0.0, z1: 555.0, k: 555.0, material: green }) }),
+    Box::new(YzRect { y0: 0.0, y1: 555.0, z0: 0.0, z1: 555.0, k: 0.0, material: red }),
+    Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 800.0, material: light }),
+    Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 0.0, material: Arc::clone(&white) }),
+    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 0.0, x1: 213.0, z0: 0.0, z1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
+    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 343.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
+    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 0.0, z1: 227.0, k: 555.0, material: Arc::clone(&white) }) }),
+    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 332.0, z1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
+    Box::new(FlipNormals { hitable: Box::new(XyRect { x0: 0.0, x1: 555.0, y0: 0.0, y1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
+    Box::new(Transform::new(
+      Box::new(new_box(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 165.0, 165.0), Arc::clone(&white))),
+      Mat44::translate(Vec3::new(130.0, 0.0, 65.0)) * Mat44::rotate(-18.0, Vec3::new(0.0, 1.0, 0.0))
+    )),
+    Box::new(Transform::new(
+      Box::new(new_box(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 330.0, 165.0), Arc::clone(&white))),
+      Mat44::translate(Vec3::new(265.0, 0.0, 295.0)) * Mat44::rotate(15.0, Vec3::new(0.0, 1.0, 0.0))
+    )),
+    Box::new(Transform::new(
+      Box::new(subsurface),
+      Mat44::translate(Vec3::new(130.0, 225.0, 65.0)) * Mat44::rotate(-18.0, Vec3::new(0.0, 1.0, 0.0)) *
+      Mat44::translate(Vec3::new(82.5, 0.0, 82.5))
+    )),
+    Box::new(ConstantMedium::new(
+      Box::new(Sphere {center: Vec3::new(0.0, 0.0, 0.0), radius: 700.0, material: Arc::clone(&white) }),
+      0.0002,
+      Box::new(ConstantTexture::new(1., 1., 1.))))
+  ]
+}
@@ -0,0 +1,280 @@
+extern crate rand;
+
+use std::f32;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use ::vec3::*;
+use ray::Ray;
+use image_texture::{Image, ImageEncoding};
+use scene::SceneEnvironment;
+
+/// How environment images are read, whichever way they are loaded: HDR and
+/// PFM files as linear values, and anything else as sRGB colors.
+pub const ENVIRONMENT_ENCODING: ImageEncoding = ImageEncoding::Srgb;
+
+/// An environment lit by an equirectangular (latitude-longitude) image, as
+/// captured HDR environments come. The image's center looks along -z, its
+/// top row straight up.
+///
+/// Directions are sampled in proportion to the brightness of the image, so
+/// small bright parts of it, like the sun or studio lights, light the scene
+/// without much noise.
+pub struct EnvironmentMap {
+  image: Arc<Image>,
+  width: usize,
+  height: usize,
+  /// Rotation of the image about the y axis, in degrees
+  rotation: f32,
+  pub intensity: f32,
+  // Cumulative sums of the pixels' sampling weights, one row after the
+  // other, and of the rows' total weights
+  columns: Vec<f32>,
+  rows: Vec<f32>
+}
+
+impl EnvironmentMap {
+  pub fn new(image: Arc<Image>) -> EnvironmentMap {
+    let (width, height) = (image.width, image.height);
+
+    // Pixels closer to the poles cover less of the sphere, so they are less
+    // likely to be picked
+    let mut columns = Vec::with_capacity(width * height);
+    let mut rows = Vec::with_capacity(height);
+    let mut total = 0.0;
+    for y in 0..height {
+      let sin_theta = (f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+      let mut row_total = 0.0;
+      for x in 0..width {
+        row_total += luminance(&image.pixel(x, y)).max(0.0) * sin_theta;
+        columns.push(row_total);
+      }
+      total += row_total;
+      rows.push(total);
+    }
+
+    EnvironmentMap {
+      image,
+      width,
+      height,
+      rotation: 0.0,
+      intensity: 1.0,
+      columns,
+      rows
+    }
+  }
+
+  /// An environment of `width` by `height` pixels, each the color `f` gives
+  /// in the direction of its center, which is handy for sampling analytic
+  /// environments.
+  pub fn tabulate<F: Fn(&Vec3) -> Vec3>(width: usize, height: usize, f: F) -> EnvironmentMap {
+    let pixels = (0..width * height).map(|i| {
+      let (x, y) = (i % width, i / width);
+      f(&equirectangular_direction((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32))
+    }).collect();
+
+    EnvironmentMap::new(Arc::new(Image::new(width, height, pixels)))
+  }
+
+  /// Loads the environment image, which should be a Radiance HDR or PFM file
+  /// to light a scene well.
+  pub fn load(path: &Path) -> io::Result<EnvironmentMap> {
+    Ok(EnvironmentMap::new(Arc::new(Image::load(path, ENVIRONMENT_ENCODING)?)))
+  }
+
+  /// Turns the environment about the y axis, from -z towards +x.
+  pub fn with_rotation(self, degrees: f32) -> EnvironmentMap {
+    EnvironmentMap { rotation: degrees, ..self }
+  }
+
+  pub fn with_intensity(self, intensity: f32) -> EnvironmentMap {
+    EnvironmentMap { intensity, ..self }
+  }
+
+  // Image coordinates of a unit direction, with v running from the top down
+  fn uv(&self, d: &Vec3) -> (f32, f32) {
+    let phi = d.x().atan2(-d.z()) - self.rotation.to_radians();
+    let u = phi / (2.0 * f32::consts::PI) + 0.5;
+    (u - u.floor(), d.x().hypot(d.z()).atan2(d.y()) / f32::consts::PI)
+  }
+
+  fn direction(&self, u: f32, v: f32) -> Vec3 {
+    equirectangular_direction(u + self.rotation / 360.0, v)
+  }
+
+  // Interpolates between the four closest pixels, wrapping around
+  // horizontally but not over the poles
+  fn bilinear(&self, u: f32, v: f32) -> Vec3 {
+    let x = u * self.width as f32 - 0.5;
+    let y = (v * self.height as f32 - 0.5).max(0.0).min((self.height - 1) as f32);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let x0 = (x0 as i64).rem_euclid(self.width as i64) as usize;
+    let x1 = (x0 + 1) % self.width;
+    let y0 = y0 as usize;
+    let y1 = (y0 + 1).min(self.height - 1);
+
+    (1.0 - ty) * ((1.0 - tx) * self.image.pixel(x0, y0) + tx * self.image.pixel(x1, y0)) +
+      ty * ((1.0 - tx) * self.image.pixel(x0, y1) + tx * self.image.pixel(x1, y1))
+  }
+
+  fn total(&self) -> f32 {
+    *self.rows.last().unwrap()
+  }
+
+  // The weight of a single pixel
+  fn weight(&self, x: usize, y: usize) -> f32 {
+    let i = y * self.width + x;
+    if x == 0 { self.columns[i] } else { self.columns[i] - self.columns[i - 1] }
+  }
+}
+
+impl SceneEnvironment for EnvironmentMap {
+  fn color(&self, r: &Ray) -> Vec3 {
+    let (u, v) = self.uv(&unit_vector(r.direction));
+    self.intensity * self.bilinear(u, v)
+  }
+
+  fn is_light(&self) -> bool {
+    self.total() > 0.0
+  }
+
+  fn sample(&self) -> Option<Vec3> {
+    if !self.is_light() {
+      return None;
+    }
+
+    // A row in proportion to its weight, then a pixel in the row in
+    // proportion to the pixel's weight
+    let y = pick(&self.rows, rand::random::<f32>() * self.total());
+    let row = &self.columns[y * self.width..(y + 1) * self.width];
+    let row_start = if y == 0 { 0.0 } else { self.rows[y - 1] };
+    let x = pick(row, rand::random::<f32>() * (self.rows[y] - row_start));
+
+    let u = (x as f32 + rand::random::<f32>()) / self.width as f32;
+    let v = (y as f32 + rand::random::<f32>()) / self.height as f32;
+    Some(self.direction(u, v))
+  }
+
+  fn pdf(&self, direction: &Vec3) -> f32 {
+    if !self.is_light() {
+      return 0.0;
+    }
+
+    let d = unit_vector(*direction);
+    let (u, v) = self.uv(&d);
+    let sin_theta = d.x().hypot(d.z());
+    if sin_theta <= 0.0 {
+      return 0.0;
+    }
+
+    let x = ((u * self.width as f32) as usize).min(self.width - 1);
+    let y = ((v * self.height as f32) as usize).min(self.height - 1);
+    // The density over the image, spread over the sphere it is wrapped around
+    let image_pdf = self.weight(x, y) * (self.width * self.height) as f32 / self.total();
+    image_pdf / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta)
+  }
+}
+
+// The direction at image coordinates `u` and `v`, with v running from the top down
+fn equirectangular_direction(u: f32, v: f32) -> Vec3 {
+  let phi = (u - 0.5) * 2.0 * f32::consts::PI;
+  let theta = v * f32::consts::PI;
+  Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+}
+
+/// The brightness of a linear sRGB color, as perceived by the eye.
+pub fn luminance(c: &Vec3) -> f32 {
+  0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+// The index of the first of the increasing cumulative weights above `value`,
+// skipping entries of zero weight
+fn pick(cumulative: &[f32], value: f32) -> usize {
+  let i = match cumulative.binary_search_by(|w| if *w <= value { ::std::cmp::Ordering::Less } else { ::std::cmp::Ordering::Greater }) {
+    Ok(i) | Err(i) => i
+  };
+  i.min(cumulative.len() - 1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A mostly dim environment with a bright spot
+  fn spot_environment() -> EnvironmentMap {
+    let (width, height) = (16, 8);
+    let mut pixels = vec![Vec3::new(0.1, 0.1, 0.1); width * height];
+    pixels[2 * width + 11] = Vec3::new(50.0, 40.0, 30.0);
+    pixels[5 * width + 3] = Vec3::new(0.0, 0.0, 0.0);
+    EnvironmentMap::new(Arc::new(Image::new(width, height, pixels))).with_rotation(30.0).with_intensity(2.0)
+  }
+
+  #[test]
+  fn looks_up_the_image_center_along_minus_z() {
+    let mut pixels = vec![Vec3::new(0.0, 0.0, 0.0); 4 * 2];
+    pixels[1] = Vec3::new(1.0, 0.0, 0.0);
+    pixels[2] = Vec3::new(1.0, 0.0, 0.0);
+    let env = EnvironmentMap::new(Arc::new(Image::new(4, 2, pixels))).with_intensity(3.0);
+    let o = Vec3::new(0.0, 0.0, 0.0);
+
+    let color = env.color(&Ray::new(o, Vec3::new(0.0, 1.0, -1.0)));
+    assert!((color - Vec3::new(3.0, 0.0, 0.0)).length() < 1e-4, "got {:?}", color);
+    assert_eq!(0.0, env.color(&Ray::new(o, Vec3::new(0.0, -0.5, 1.0))).length());
+
+    let turned = env.with_rotation(180.0);
+    let color = turned.color(&Ray::new(o, Vec3::new(0.0, 1.0, 1.0)));
+    assert!((color - Vec3::new(3.0, 0.0, 0.0)).length() < 1e-4, "got {:?}", color);
+  }
+
+  #[test]
+  fn pdf_integrates_to_one() {
+    let env = spot_environment();
+    // Integrating over the sphere in the image's own coordinates
+    let n = 256;
+    let mut sum = 0.0;
+    for i in 0..n {
+      for j in 0..2 * n {
+        let v = (i as f32 + 0.5) / n as f32;
+        let u = (j as f32 + 0.5) / (2 * n) as f32;
+        let sin_theta = (v * f32::consts::PI).sin();
+        let area = 2.0 * f32::consts::PI * f32::consts::PI * sin_theta / (2 * n * n) as f32;
+        sum += env.pdf(&env.direction(u, v)) * area;
+      }
+    }
+    assert!((sum - 1.0).abs() < 0.01, "pdf integrates to {}", sum);
+  }
+
+  #[test]
+  fn samples_follow_pdf_and_favour_the_bright_spot() {
+    let env = spot_environment();
+    let n = 20000;
+    let mut in_spot = 0;
+    for _ in 0..n {
+      let d = env.sample().unwrap();
+      assert!((d.length() - 1.0).abs() < 1e-4);
+      assert!(env.pdf(&d) > 0.0, "sampled a direction of no density {:?}", d);
+
+      let (u, v) = env.uv(&d);
+      let (x, y) = ((u * 16.0) as usize, (v * 8.0) as usize);
+      assert!((x, y) != (3, 5), "sampled a black pixel");
+      if (x, y) == (11, 2) {
+        in_spot += 1;
+      }
+    }
+
+    // The bright pixel holds nearly all of the weight
+    let expected = env.weight(11, 2) / env.total();
+    let fraction = in_spot as f32 / n as f32;
+    assert!((fraction - expected).abs() < 0.02, "{} of samples in the spot, expected {}", fraction, expected);
+  }
+
+  #[test]
+  fn black_environment_is_not_a_light() {
+    let env = EnvironmentMap::new(Arc::new(Image::new(2, 2, vec![Vec3::new(0.0, 0.0, 0.0); 4])));
+    assert!(!env.is_light());
+    assert!(env.sample().is_none());
+    assert_eq!(0.0, env.pdf(&Vec3::new(0.0, 1.0, 0.0)));
+  }
+}
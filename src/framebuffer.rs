@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use png::HasParameters;
+
+use ::vec3::Vec3;
+use ::hdr;
+use ::hdr::ExrPixelType;
+use ::tonemap::DisplayTransform;
+
+/// A rendered image, stored as rows of linear RGB pixels, top row first.
+/// Values are not limited to the 0 to 1 range, so they keep all of the light
+/// when written to high dynamic range formats.
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<Vec3>
+}
+
+impl Framebuffer {
+  pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Framebuffer {
+    assert_eq!(width * height, pixels.len(), "Framebuffer size does not match its dimensions");
+    Framebuffer { width, height, pixels }
+  }
+
+  /// The linear RGB value of the pixel at column `x` and row `y`, counted from the top left corner.
+  pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+    self.pixels[y * self.width + x]
+  }
+
+  /// The image as 8-bit sRGB values, shown on a display through `transform`.
+  pub fn to_rgb8(&self, transform: &DisplayTransform) -> Vec<u8> {
+    self.pixels.iter()
+      .flat_map(|p| transform.to_srgb8(*p).to_vec())
+      .collect()
+  }
+
+  /// Writes an 8-bit sRGB PNG file, with the light exposed and tone mapped
+  /// by `transform`.
+  pub fn write_png<P: AsRef<Path>>(&self, path: P, transform: &DisplayTransform) -> io::Result<()> {
+    write_rgb8_png(path, self.width, self.height, &self.to_rgb8(transform))
+  }
+
+  /// Writes an uncompressed OpenEXR file, with 16 or 32 bit floats.
+  pub fn write_exr<P: AsRef<Path>>(&self, path: P, pixel_type: ExrPixelType) -> io::Result<()> {
+    self.write_with(path, |w, f| hdr::write_exr(w, f.width, f.height, &f.pixels, pixel_type))
+  }
+
+  /// Writes a portable float map.
+  pub fn write_pfm<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    self.write_with(path, |w, f| hdr::write_pfm(w, f.width, f.height, &f.pixels))
+  }
+
+  /// Writes a run length encoded Radiance RGBE file.
+  pub fn write_hdr<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    self.write_with(path, |w, f| hdr::write_hdr(w, f.width, f.height, &f.pixels))
+  }
+
+  fn write_with<P, F>(&self, path: P, write: F) -> io::Result<()>
+    where P: AsRef<Path>, F: Fn(&mut BufWriter<File>, &Framebuffer) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write(&mut w, self)?;
+    w.flush()
+  }
+}
+
+/// Writes 8-bit RGB values, rows top first, as a PNG file.
+pub fn write_rgb8_png<P: AsRef<Path>>(path: P, width: usize, height: usize, data: &[u8]) -> io::Result<()> {
+  let file = File::create(path)?;
+  let w = &mut BufWriter::new(file);
+
+  let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+  encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+  let mut writer = encoder.write_header()?;
+
+  writer.write_image_data(data)?;
+  Ok(())
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use ::material::*;
+use ::vec3::Vec3;
+use ::mat44::Mat44;
+use ::ray::Ray;
+use ::aabb::Aabb;
+use ::bvh::BvhTree;
+use ::geometry::*;
+
+/// A copy of shared geometry, placed in the scene through a transform just
+/// like `Transform`, optionally with a material replacing the geometry's own.
+///
+/// Instances only refer to the geometry, so adding more of them doesn't copy it.
+pub struct Instance {
+  transformed: Transform,
+  material: Option<Arc<Material>>
+}
+
+impl Instance {
+  pub fn new(geometry: Arc<BvhTree>, transform: Mat44) -> Instance {
+    Instance { transformed: Transform::new(Box::new(geometry), transform), material: None }
+  }
+
+  pub fn with_material(geometry: Arc<BvhTree>, transform: Mat44, material: Arc<Material>) -> Instance {
+    Instance { transformed: Transform::new(Box::new(geometry), transform), material: Some(material) }
+  }
+}
+
+impl Hitable for Instance {
+  fn bounding_box(&self) -> Option<Aabb> {
+    self.transformed.bounding_box()
+  }
+
+  fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
+    let mut hit = self.transformed.hit(r, tmin, tmax)?;
+    if let Some(ref material) = self.material {
+      hit.material = &**material;
+    }
+
+    Some(hit)
+  }
+
+  fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    self.transformed.any_hit(r, tmin, tmax)
+  }
+
+  // With a material override, the geometry's lights are not necessarily
+  // lights anymore, and emissive overrides are only found by chance.
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    if self.material.is_some() { 0.0 } else { self.transformed.pdf_value(o, v) }
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    self.transformed.random(o)
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_none() && self.transformed.is_light()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn light() -> Arc<Material> {
+    Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(1.0, 1.0, 1.0)) })
+  }
+
+  fn shared_light() -> Arc<BvhTree> {
+    let rect: Vec<Box<Hitable>> = vec![Box::new(XyRect { x0: -0.5, x1: 0.5, y0: -0.5, y1: 0.5, k: 0.0, material: light() })];
+    Arc::new(BvhTree::new(rect))
+  }
+
+  #[test]
+  fn instances_share_geometry() {
+    let geometry = shared_light();
+    let instances: Vec<Box<Hitable>> = (0..3)
+      .map(|i| Box::new(Instance::new(geometry.clone(), Mat44::translate(Vec3::new(2.0 * i as f32, 0.0, -1.0)))) as Box<Hitable>)
+      .collect();
+
+    assert_eq!(4, Arc::strong_count(&geometry));
+    for i in 0..3 {
+      let x = 2.0 * i as f32;
+      let hit = instances.hit(&Ray::new(Vec3::new(x, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+      assert!((hit.p - Vec3::new(x, 0.0, -1.0)).length() < 1e-5);
+      assert!(hit.material.is_emissive());
+    }
+    assert!(instances.hit(&Ray::new(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).is_none());
+  }
+
+  #[test]
+  fn any_hit_agrees_with_hit() {
+    let white: Arc<Material> = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) });
+    let spheres: Vec<Box<Hitable>> = (0..20)
+      .map(|i| Box::new(Sphere { center: Vec3::new(i as f32, 0.0, 0.0), radius: 0.3, material: Arc::clone(&white) }) as Box<Hitable>)
+      .collect();
+    let transform = Mat44::rotate(0.7, Vec3::new(0.0, 1.0, 0.0)) * Mat44::translate(Vec3::new(-10.0, 0.0, 5.0));
+    let instance = Instance::new(Arc::new(BvhTree::new(spheres)), transform);
+
+    let mut hits = 0;
+    for i in 0..200 {
+      let target = Vec3::new((i % 40) as f32 * 0.5 - 10.0, (i / 40) as f32 * 0.2 - 0.4, -8.0);
+      let r = Ray::new(Vec3::new(0.0, 0.0, 10.0), target - Vec3::new(0.0, 0.0, 10.0));
+      for &tmax in [0.5, 0.9, f32::MAX].iter() {
+        let hit = instance.hit(&r, 0.001, tmax).is_some();
+        assert_eq!(hit, instance.any_hit(&r, 0.001, tmax));
+        hits += hit as usize;
+      }
+    }
+    // Some rays hit and some miss
+    assert!(hits > 0 && hits < 600, "{} hits", hits);
+  }
+
+  #[test]
+  fn material_override_replaces_geometry_material() {
+    let white = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) });
+    let instance = Instance::with_material(shared_light(), Mat44::identity(), white);
+    let hit = instance.hit(&Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+
+    assert!(!hit.material.is_emissive());
+    assert!(!instance.is_light());
+    assert!(Instance::new(shared_light(), Mat44::identity()).is_light());
+  }
+}
@@ -1,26 +1,56 @@
 extern crate rand;
 extern crate tobj;
 
+use rand::Rng;
+
 pub mod triangle;
+pub mod instance;
 
 use std::f32;
 use std::fmt;
+use std::io;
 use std::sync::Arc;
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 use ::material::{Material, HitRecord, Isotropic, Texture};
 
-use ::vec3::{Vec3};
+use ::vec3::{Vec3, unit_vector};
+use ::onb::Onb;
 use ::mat44::Mat44;
-use ::ray::Ray;
+use ::ray::{Ray, RayDifferentials};
 use ::aabb::{Aabb, surrounding_box};
 use ::material::*;
 use triangle::Triangle;
+use ::image_texture::{Image, ImageTexture, ImageEncoding, TextureCache};
 
-pub trait Hitable : Sync {
+/// Anything a ray can hit.
+pub trait Hitable : Sync + Send {
+  /// The closest intersection with `r` for a ray parameter between `tmin` and `tmax`, if any.
   fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord>;
+  /// Axis aligned box bounding the whole hitable, or `None` if it is unbounded.
   fn bounding_box(&self) -> Option<Aabb>;
+
+  /// Whether `r` hits anything between `tmin` and `tmax`, without finding the closest hit.
+  fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    self.hit(r, tmin, tmax).is_some()
+  }
+
+  /// The probability density, per unit solid angle, of `random` returning
+  /// direction `v` when called with origin `o`.
+  fn pdf_value(&self, _o: &Vec3, _v: &Vec3) -> f32 {
+    0.0
+  }
+
+  /// A random direction from `o` towards a point on the hitable.
+  fn random(&self, _o: &Vec3) -> Vec3 {
+    Vec3::new(1.0, 0.0, 0.0)
+  }
+
+  /// Whether the hitable emits light, and should be sampled directly by the renderer.
+  fn is_light(&self) -> bool {
+    false
+  }
 }
 
 impl fmt::Debug for Hitable {
@@ -69,6 +99,52 @@ impl Hitable for Vec<Box<Hitable>> {
 
     Some(result)
   }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    let lights: Vec<&Box<Hitable>> = self.iter().filter(|h| h.is_light()).collect();
+    if lights.is_empty() {
+      return 0.0;
+    }
+
+    lights.iter().map(|h| h.pdf_value(o, v)).sum::<f32>() / lights.len() as f32
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    let lights: Vec<&Box<Hitable>> = self.iter().filter(|h| h.is_light()).collect();
+    let index = rand::thread_rng().gen_range(0, lights.len());
+    lights[index].random(o)
+  }
+
+  fn is_light(&self) -> bool {
+    self.iter().any(|h| h.is_light())
+  }
+}
+
+/// Shares a hitable, such as a `BvhTree`, between several places in a scene.
+impl<T: Hitable + ?Sized> Hitable for Arc<T> {
+  fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
+    (**self).hit(r, tmin, tmax)
+  }
+
+  fn bounding_box(&self) -> Option<Aabb> {
+    (**self).bounding_box()
+  }
+
+  fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    (**self).any_hit(r, tmin, tmax)
+  }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    (**self).pdf_value(o, v)
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    (**self).random(o)
+  }
+
+  fn is_light(&self) -> bool {
+    (**self).is_light()
+  }
 }
 
 pub struct Sphere {
@@ -77,6 +153,50 @@ pub struct Sphere {
   pub material: Arc<Material>
 }
 
+impl Sphere {
+  fn hit_record(&self, r: &Ray, t: f32) -> HitRecord<'_> {
+    let p = r.point_at_parameter(t);
+    // A negative radius turns the normal inwards, but not the texture
+    let radius = self.radius.abs();
+    let outward = (p - self.center) / radius;
+    let normal = (p - self.center) / self.radius;
+    let (u, v) = sphere_uv(&outward);
+
+    // Derivatives of the point with respect to longitude and latitude,
+    // scaled by how fast u and v change with them
+    let phi = outward.z().atan2(outward.x());
+    let theta = outward.y().max(-1.0).min(1.0).asin();
+    let dpdu = (2.0 * f32::consts::PI * radius) * Vec3::new(outward.z(), 0.0, -outward.x());
+    let dpdv = (f32::consts::PI * radius) * Vec3::new(
+      -theta.sin() * phi.cos(),
+      theta.cos(),
+      -theta.sin() * phi.sin());
+
+    HitRecord {
+      t,
+      p,
+      normal,
+      geometric_normal: normal,
+      material: &*self.material,
+      u,
+      v,
+      dpdu,
+      dpdv,
+      uv_derivatives: None
+    }
+  }
+}
+
+/// Longitude and latitude of a point on the unit sphere, both between 0 and 1.
+/// `u` goes around the y axis, starting and ending at -x, and `v` from the
+/// bottom pole to the top one.
+pub fn sphere_uv(p: &Vec3) -> (f32, f32) {
+  let phi = p.z().atan2(p.x());
+  let theta = p.y().max(-1.0).min(1.0).asin();
+
+  (1.0 - (phi + f32::consts::PI) / (2.0 * f32::consts::PI), (theta + f32::consts::FRAC_PI_2) / f32::consts::PI)
+}
+
 impl Hitable for Sphere {
   fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
     let oc = r.origin - self.center;
@@ -87,34 +207,13 @@ impl Hitable for Sphere {
 
     if discriminant > 0. {
       let mut t = (-b - discriminant.sqrt()) / a;
-
       if t < tmax && t > tmin {
-        let p = r.point_at_parameter(t);
-
-        return Some(HitRecord {
-          t,
-          p,
-          normal: (p - self.center) / self.radius,
-          material: &*self.material,
-          // TODO: texture coords
-          u: 0.0,
-          v: 0.0
-        });
+        return Some(self.hit_record(r, t));
       }
 
       t = (-b + discriminant.sqrt()) / a;
       if t < tmax && t > tmin {
-        let p = r.point_at_parameter(t);
-
-        return Some(HitRecord {
-          t,
-          p,
-          normal: (p - self.center) / self.radius,
-          material: &*self.material,
-          // TODO: texture coords
-          u: 0.0,
-          v: 0.0
-        });
+        return Some(self.hit_record(r, t));
       }
     }
 
@@ -127,6 +226,59 @@ impl Hitable for Sphere {
       max: self.center + Vec3::new(self.radius, self.radius, self.radius),
     })
   }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    let distance_squared = (self.center - *o).squared_length();
+    if distance_squared <= self.radius * self.radius {
+      return 0.0;
+    }
+
+    match self.hit(&Ray::new(*o, *v), 0.001, f32::MAX) {
+      Some(_) => {
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        let solid_angle = 2.0 * f32::consts::PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+      },
+      None => 0.0
+    }
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    let direction = self.center - *o;
+    let distance_squared = direction.squared_length();
+    let uvw = Onb::build_from_w(direction);
+    uvw.local(random_to_sphere(self.radius, distance_squared))
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
+}
+
+// Random direction within the cone from the origin towards a sphere of
+// `radius` at `distance_squared`, with the cone's axis along z.
+fn random_to_sphere(radius: f32, distance_squared: f32) -> Vec3 {
+  let r1 = rand::random::<f32>();
+  let r2 = rand::random::<f32>();
+  let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).max(0.0).sqrt() - 1.0);
+  let phi = 2.0 * f32::consts::PI * r1;
+  let x = phi.cos() * (1.0 - z * z).sqrt();
+  let y = phi.sin() * (1.0 - z * z).sqrt();
+
+  Vec3::new(x, y, z)
+}
+
+// Converts the area density of picking the point `rec` on a surface of
+// `area` to a density per solid angle as seen along `v`.
+fn area_light_pdf(rec: Option<HitRecord>, v: &Vec3, area: f32) -> f32 {
+  match rec {
+    Some(rec) => {
+      let distance_squared = rec.t * rec.t * v.squared_length();
+      let cosine = (v.dot(unit_vector(rec.normal)) / v.length()).abs();
+      distance_squared / (cosine * area)
+    },
+    None => 0.0
+  }
 }
 
 pub struct XyRect {
@@ -164,9 +316,30 @@ impl Hitable for XyRect {
       t,
       material: &*self.material,
       p: r.point_at_parameter(t),
-      normal: Vec3::new(0.0, 0.0, 1.0)
+      normal: Vec3::new(0.0, 0.0, 1.0),
+      geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+      dpdu: Vec3::new(self.x1 - self.x0, 0.0, 0.0),
+      dpdv: Vec3::new(0.0, self.y1 - self.y0, 0.0),
+      uv_derivatives: None
     })
   }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+    area_light_pdf(self.hit(&Ray::new(*o, *v), 0.001, f32::MAX), v, area)
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    let random_point = Vec3::new(
+      self.x0 + rand::random::<f32>() * (self.x1 - self.x0),
+      self.y0 + rand::random::<f32>() * (self.y1 - self.y0),
+      self.k);
+    random_point - *o
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
 }
 
 pub struct XzRect {
@@ -204,9 +377,30 @@ impl Hitable for XzRect {
       t,
       material: &*self.material,
       p: r.point_at_parameter(t),
-      normal: Vec3::new(0.0, 1.0, 0.0)
+      normal: Vec3::new(0.0, 1.0, 0.0),
+      geometric_normal: Vec3::new(0.0, 1.0, 0.0),
+      dpdu: Vec3::new(self.x1 - self.x0, 0.0, 0.0),
+      dpdv: Vec3::new(0.0, 0.0, self.z1 - self.z0),
+      uv_derivatives: None
     })
   }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    let area = (self.x1 - self.x0) * (self.z1 - self.z0);
+    area_light_pdf(self.hit(&Ray::new(*o, *v), 0.001, f32::MAX), v, area)
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    let random_point = Vec3::new(
+      self.x0 + rand::random::<f32>() * (self.x1 - self.x0),
+      self.k,
+      self.z0 + rand::random::<f32>() * (self.z1 - self.z0));
+    random_point - *o
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
 }
 
 pub struct YzRect {
@@ -244,9 +438,30 @@ impl Hitable for YzRect {
       t,
       material: &*self.material,
       p: r.point_at_parameter(t),
-      normal: Vec3::new(1.0, 0.0, 0.0)
+      normal: Vec3::new(1.0, 0.0, 0.0),
+      geometric_normal: Vec3::new(1.0, 0.0, 0.0),
+      dpdu: Vec3::new(0.0, self.y1 - self.y0, 0.0),
+      dpdv: Vec3::new(0.0, 0.0, self.z1 - self.z0),
+      uv_derivatives: None
     })
   }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+    area_light_pdf(self.hit(&Ray::new(*o, *v), 0.001, f32::MAX), v, area)
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    let random_point = Vec3::new(
+      self.k,
+      self.y0 + rand::random::<f32>() * (self.y1 - self.y0),
+      self.z0 + rand::random::<f32>() * (self.z1 - self.z0));
+    random_point - *o
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
 }
 
 pub struct FlipNormals {
@@ -262,13 +477,31 @@ impl Hitable for FlipNormals {
     match self.hitable.hit(r, tmin, tmax) {
       Some(mut hit) => {
         hit.normal = -hit.normal;
+        hit.geometric_normal = -hit.geometric_normal;
         return Some(hit);
       },
       None => None
     }
   }
+
+  fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    self.hitable.any_hit(r, tmin, tmax)
+  }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    self.hitable.pdf_value(o, v)
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    self.hitable.random(o)
+  }
+
+  fn is_light(&self) -> bool {
+    self.hitable.is_light()
+  }
 }
 
+/// The six sides of an axis aligned box between corners `p0` and `p1`.
 pub fn new_box(p0: Vec3, p1: Vec3, material: Arc<Material>) -> Vec<Box<Hitable>> {
   vec![
     Box::new(XyRect { x0: p0.x(), x1: p1.x(), y0: p0.y(), y1: p1.y(), k: p1.z(), material: Arc::clone(&material)}),
@@ -280,6 +513,8 @@ pub fn new_box(p0: Vec3, p1: Vec3, material: Arc<Material>) -> Vec<Box<Hitable>>
   ]
 }
 
+/// Places a hitable in the scene through a transform. Note that `transform`
+/// maps world space onto the hitable's object space, not the other way around.
 pub struct Transform {
   pub hitable: Box<Hitable>,
   pub transform: Mat44,
@@ -294,6 +529,20 @@ impl Transform {
       inverse_transform: transform.inverse()
     }
   }
+
+  // `r` in the hitable's object space
+  fn object_ray(&self, r: &Ray) -> Ray {
+    Ray {
+      origin: self.transform * r.origin,
+      direction: self.transform.mul_as_33(r.direction),
+      differentials: r.differentials.map(|d| RayDifferentials {
+        origin_dx: self.transform.mul_as_33(d.origin_dx),
+        direction_dx: self.transform.mul_as_33(d.direction_dx),
+        origin_dy: self.transform.mul_as_33(d.origin_dy),
+        direction_dy: self.transform.mul_as_33(d.direction_dy)
+      })
+    }
+  }
 }
 
 impl Hitable for Transform {
@@ -330,22 +579,39 @@ impl Hitable for Transform {
   }
 
   fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
-    let transformed_r = Ray {
-      origin: self.transform * r.origin, 
-      direction: self.transform.mul_as_33(r.direction)
-    };
-
-    match self.hitable.hit(&transformed_r, tmin, tmax) {
+    match self.hitable.hit(&self.object_ray(r), tmin, tmax) {
       Some(mut hit) => {
         hit.p = self.inverse_transform * hit.p;
         hit.normal = self.inverse_transform.mul_as_33(hit.normal);
+        hit.geometric_normal = self.inverse_transform.mul_as_33(hit.geometric_normal);
+        hit.dpdu = self.inverse_transform.mul_as_33(hit.dpdu);
+        hit.dpdv = self.inverse_transform.mul_as_33(hit.dpdv);
         Some(hit)
       },
       None => None
     }
   }
+
+  fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    self.hitable.any_hit(&self.object_ray(r), tmin, tmax)
+  }
+
+  // Solid angles are only preserved by rigid transforms, which is all
+  // Mat44 can build today.
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    self.hitable.pdf_value(&(self.transform * *o), &self.transform.mul_as_33(*v))
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    self.inverse_transform.mul_as_33(self.hitable.random(&(self.transform * *o)))
+  }
+
+  fn is_light(&self) -> bool {
+    self.hitable.is_light()
+  }
 }
 
+/// A volume of constant density, like smoke or fog, filling `boundary`.
 pub struct ConstantMedium {
   boundary: Box<Hitable>,
   density: f32,
@@ -395,9 +661,13 @@ impl Hitable for ConstantMedium {
             t,
             p: r.point_at_parameter(t),
             normal: Vec3::new(1.0, 0.0, 0.0), // Arbitrary
+            geometric_normal: Vec3::new(1.0, 0.0, 0.0),
             material: &self.phase_function,
             u: 0.0,
-            v: 0.0
+            v: 0.0,
+            dpdu: Vec3::new(0.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, 0.0),
+            uv_derivatives: None
           })
         }
       }
@@ -407,20 +677,41 @@ impl Hitable for ConstantMedium {
   }
 }
 
-pub fn obj_to_hitable(path: &Path) -> Vec<Box<Hitable>> {
-  let obj = self::tobj::load_obj(path);
-  let (models, mtls) = obj.unwrap();
+/// Loads the triangles of a Wavefront OBJ file, with materials from its MTL file.
+///
+/// The MTL file's `map_Kd` gives the diffuse color, `map_Ks` the color of
+/// metals and `map_d` the opacity, from PNG images looked up relative to the
+/// OBJ file.
+pub fn obj_to_hitable(path: &Path) -> io::Result<Vec<Box<Hitable>>> {
+  obj_to_hitable_with_cache(path, &TextureCache::new())
+}
+
+/// Loads an OBJ file like `obj_to_hitable`, taking texture maps from `cache`
+/// when they have already been loaded.
+pub fn obj_to_hitable_with_cache(path: &Path, cache: &TextureCache) -> io::Result<Vec<Box<Hitable>>> {
+  let (models, mtls) = self::tobj::load_obj(path).map_err(|e| match e {
+    self::tobj::LoadError::OpenFileFailed => io::Error::new(io::ErrorKind::NotFound, e.to_string()),
+    _ => io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+  })?;
   let mut world: Vec<Box<Hitable>> = Vec::new();
+  let mut maps = TextureMaps { base_dir: path.parent().unwrap_or(Path::new("")), cache, missing: HashSet::new() };
 
   let default_mat: Arc<Material> = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.6, 0.6, 0.6)) });
   let materials: Vec<Arc<Material>> = mtls.iter().map(|m| {
+    let diffuse = ConstantTexture::new(m.diffuse[0], m.diffuse[1], m.diffuse[2]);
     let mat: Arc<Material> = match m.illumination_model {
       Some(7) => Arc::new(Dielectric { ref_idx: m.optical_density }),
-      Some(5) => Arc::new(Metal { albedo: Vec3::new(m.diffuse[0], m.diffuse[1], m.diffuse[2]), fuzz: 1. / m.shininess }),
-      _ => Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(m.diffuse[0], m.diffuse[1], m.diffuse[2])) })
+      _ if PBR_PARAMS.iter().any(|p| m.unknown_param.contains_key(*p)) =>
+        Arc::new(principled(m, &mut maps, diffuse)),
+      Some(5) => Arc::new(Metal { albedo: maps.color(&m.specular_texture, diffuse), fuzz: 1. / m.shininess }),
+      _ => Arc::new(Lambertian { albedo: maps.color(&m.diffuse_texture, diffuse) })
     };
+    let mat = maps.normal_map(m, mat);
 
-    mat
+    match maps.image(&m.dissolve_texture, ImageEncoding::Alpha) {
+      Some(alpha) => Arc::new(AlphaCutout { material: mat, alpha: Box::new(ImageTexture::new(alpha)) }),
+      None => mat
+    }
   }).collect();
 
   for m in models.iter() {
@@ -438,17 +729,240 @@ pub fn obj_to_hitable(path: &Path) -> Vec<Box<Hitable>> {
         None => Arc::clone(&default_mat)
       };
 
-      let tri: Triangle;
+      let mut tri: Triangle;
       if mesh.normals.len() > 0 {
-        let normal = Vec3::new(mesh.normals[i0 * 3], mesh.normals[i0 * 3 + 1], mesh.normals[i0 * 3 + 2]);
-        tri = Triangle::new_with_normal(v0, v1, v2, normal, mat)
+        let normal = |i: usize| Vec3::new(mesh.normals[i * 3], mesh.normals[i * 3 + 1], mesh.normals[i * 3 + 2]);
+        tri = Triangle::new_with_normals(v0, v1, v2, [normal(i0), normal(i1), normal(i2)], mat)
       } else {
         tri = Triangle::new(v0, v1, v2, Arc::clone(&mat));
       }
 
+      if mesh.texcoords.len() > 0 {
+        let texcoord = |i: usize| [mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1]];
+        tri = tri.with_texcoords([texcoord(i0), texcoord(i1), texcoord(i2)]);
+      }
+
       world.push(Box::new(tri));
     }
   }
 
-  world
+  Ok(world)
+}
+
+// Parameters of the physically based MTL extension, in either value or map form
+const PBR_PARAMS: [&str; 10] = ["Pr", "Pm", "Ps", "Pc", "Pcr", "map_Pr", "map_Pm", "map_Ps", "map_Pc", "map_Pcr"];
+
+fn principled(m: &tobj::Material, maps: &mut TextureMaps, diffuse: ConstantTexture) -> Principled {
+  let mut material = Principled::new(maps.color(&m.diffuse_texture, diffuse));
+  if let Some(roughness) = maps.scalar(m, "Pr") {
+    material = material.with_roughness(roughness);
+  }
+  if let Some(metallic) = maps.scalar(m, "Pm") {
+    material = material.with_metallic(metallic);
+  }
+  if let Some(sheen) = maps.scalar(m, "Ps") {
+    material = material.with_sheen(sheen);
+  }
+  if let Some(clearcoat) = maps.scalar(m, "Pc") {
+    let roughness = maps.scalar(m, "Pcr").unwrap_or_else(|| Box::new(ConstantTexture::new(0.1, 0.1, 0.1)));
+    material = material.with_clearcoat(clearcoat, roughness);
+  }
+  if m.optical_density > 1.0 {
+    material.ior = m.optical_density;
+  }
+
+  material
+}
+
+// Texture maps referred to by an MTL file
+struct TextureMaps<'a> {
+  base_dir: &'a Path,
+  cache: &'a TextureCache,
+  // Maps that failed to load, to only warn about them once
+  missing: HashSet<String>
+}
+
+impl<'a> TextureMaps<'a> {
+  // The color map `name`, or `color` if there is none
+  fn color(&mut self, name: &str, color: ConstantTexture) -> Box<Texture> {
+    match self.image(name, ImageEncoding::Srgb) {
+      Some(image) => Box::new(ImageTexture::new(image)),
+      None => Box::new(color)
+    }
+  }
+
+  // `material` with the normal map or bump map of `m` applied, if it has one
+  fn normal_map(&mut self, m: &tobj::Material, material: Arc<Material>) -> Arc<Material> {
+    let param = |names: &[&str]| names.iter().filter_map(|name| m.unknown_param.get(*name)).next().cloned();
+
+    if let Some(normals) = param(&["norm", "map_norm"]).and_then(|p| self.map(&p)) {
+      return Arc::new(NormalMap::tangent_space(material, Box::new(ImageTexture::new(normals))));
+    }
+
+    if let Some(p) = param(&["bump", "map_bump", "map_Bump"]) {
+      if let Some(height) = self.map(&p) {
+        // The -bm option multiplies the heights
+        let words: Vec<&str> = p.split_whitespace().collect();
+        let scale = words.windows(2)
+          .find(|w| w[0] == "-bm")
+          .and_then(|w| w[1].parse::<f32>().ok())
+          .unwrap_or(1.0);
+        return Arc::new(NormalMap::bump(material, Box::new(ImageTexture::new(height)), scale));
+      }
+    }
+
+    material
+  }
+
+  // The linear map named by the last word of an MTL map statement, after its options
+  fn map(&mut self, statement: &str) -> Option<Arc<Image>> {
+    statement.split_whitespace().last().and_then(|file| self.image(file, ImageEncoding::Linear))
+  }
+
+  // The parameter `name` of `m`, preferring its map if it has one. Maps may
+  // be preceded by options, so the file name is the last word.
+  fn scalar(&mut self, m: &tobj::Material, name: &str) -> Option<Box<Texture>> {
+    let map = m.unknown_param.get(&format!("map_{}", name)).and_then(|p| self.map(p));
+    if let Some(image) = map {
+      return Some(Box::new(ImageTexture::new(image)));
+    }
+
+    m.unknown_param.get(name)
+      .and_then(|p| p.trim().parse::<f32>().ok())
+      .map(|v| Box::new(ConstantTexture::new(v, v, v)) as Box<Texture>)
+  }
+
+  fn image(&mut self, name: &str, encoding: ImageEncoding) -> Option<Arc<Image>> {
+    if name.is_empty() || self.missing.contains(name) {
+      return None;
+    }
+
+    let path = self.base_dir.join(name);
+    match self.cache.load(&path, encoding) {
+      Ok(image) => Some(image),
+      Err(e) => {
+        eprintln!("Could not load texture map {}: {}", path.display(), e);
+        self.missing.insert(name.to_string());
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sphere() -> Sphere {
+    Sphere { center: Vec3::new(0.0, 0.0, 0.0), radius: 2.0, material: Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) }) }
+  }
+
+  #[test]
+  fn sphere_hits_have_spherical_uvs() {
+    let s = sphere();
+    let front = s.hit(&Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0)), 0.001, f32::MAX).unwrap();
+    let top = s.hit(&Ray::new(Vec3::new(0.1, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.001, f32::MAX).unwrap();
+
+    assert!((front.u - 0.5).abs() < 1e-5 && (front.v - 0.5).abs() < 1e-5);
+    assert!(top.v > 0.95);
+
+    // The tangents follow u and v, and span the surface with the normal pointing out
+    assert!((front.dpdu - Vec3::new(0.0, 0.0, -4.0 * f32::consts::PI)).length() < 1e-4);
+    assert!((front.dpdv - Vec3::new(0.0, 2.0 * f32::consts::PI, 0.0)).length() < 1e-4);
+    assert!(front.dpdu.cross(front.dpdv).dot(front.normal) > 0.0);
+
+    let r = Ray::new(Vec3::new(5.0, 0.7, -0.4), Vec3::new(-1.0, 0.0, 0.0));
+    let hit = s.hit(&r, 0.001, f32::MAX).unwrap();
+    let nearby = s.hit(&Ray::new(r.origin + 1e-3 * hit.dpdu, r.direction), 0.001, f32::MAX).unwrap();
+    assert!(((nearby.u - hit.u) - 1e-3).abs() < 1e-4);
+  }
+
+  // Only answers whether it is hit at all, like a BVH's early out
+  struct AnyHitOnly;
+
+  impl Hitable for AnyHitOnly {
+    fn hit(&self, _r: &Ray, _tmin: f32, _tmax: f32) -> Option<HitRecord> {
+      panic!("closest hit searched for when any hit would do")
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+      None
+    }
+
+    fn any_hit(&self, _r: &Ray, _tmin: f32, _tmax: f32) -> bool {
+      true
+    }
+  }
+
+  #[test]
+  fn wrappers_forward_any_hit() {
+    let r = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+    let transformed = Transform::new(Box::new(AnyHitOnly), Mat44::translate(Vec3::new(1.0, 0.0, 0.0)));
+    let flipped = FlipNormals { hitable: Box::new(AnyHitOnly) };
+
+    assert!(transformed.any_hit(&r, 0.001, f32::MAX));
+    assert!(flipped.any_hit(&r, 0.001, f32::MAX));
+  }
+
+  #[test]
+  fn transform_carries_sphere_tangents_to_world_space() {
+    let rotation = Mat44::rotate(f32::consts::FRAC_PI_2, Vec3::new(0.0, 1.0, 0.0));
+    let transformed = Transform::new(Box::new(sphere()), rotation);
+    let r = Ray::new(Vec3::new(0.3, 0.2, 5.0), Vec3::new(0.0, 0.0, -1.0));
+    let hit = transformed.hit(&r, 0.001, f32::MAX).unwrap();
+    let untransformed = sphere();
+    let local = untransformed.hit(&Ray::new(rotation * r.origin, rotation.mul_as_33(r.direction)), 0.001, f32::MAX).unwrap();
+
+    assert!((hit.u - local.u).abs() < 1e-5 && (hit.v - local.v).abs() < 1e-5);
+    assert!(hit.dpdu.dot(hit.normal).abs() < 1e-3 && hit.dpdv.dot(hit.normal).abs() < 1e-3);
+    assert!((hit.dpdu.length() - local.dpdu.length()).abs() < 1e-3);
+    assert!(hit.dpdu.cross(hit.dpdv).dot(hit.normal) > 0.0);
+  }
+
+  #[test]
+  fn mtl_pbr_parameters_make_principled_materials() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("rt_in_1_week_pbr_{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("quads.mtl"), "newmtl gold\nKd 0.8 0.6 0.2\nPm 1.0\nPr 0.1\n\nnewmtl plain\nKd 0.8 0.6 0.2\n").unwrap();
+    fs::write(dir.join("quads.obj"), "mtllib quads.mtl\nv 0 0 0\nv 1 0 0\nv 1 0 1\nv 3 0 0\nv 4 0 0\nv 4 0 1\n\
+      o gold\nusemtl gold\nf 1 3 2\no plain\nusemtl plain\nf 4 6 5\n").unwrap();
+    let triangles = obj_to_hitable(&dir.join("quads.obj")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Away from the mirror direction, the metal reflects next to nothing
+    let (wi, wo) = (Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 0.0));
+    let eval = |x: f32| {
+      let hit = triangles.hit(&Ray::new(Vec3::new(x, 1.0, 0.2), Vec3::new(0.0, -1.0, 0.0)), 0.001, f32::MAX).unwrap();
+      hit.material.eval(&hit, &wi, &wo).x()
+    };
+    assert!(eval(0.7) < 0.01);
+    assert!(eval(3.7) > 0.1);
+  }
+
+  #[test]
+  fn mtl_normal_maps_tilt_shading_normals() {
+    use std::env;
+    use std::fs;
+    use ::framebuffer::write_rgb8_png;
+
+    let dir = env::temp_dir().join(format!("rt_in_1_week_norm_{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // A single normal of (0.6, 0, 0.8) in tangent space
+    write_rgb8_png(&dir.join("tilt.png"), 1, 1, &[204, 128, 230]).unwrap();
+    fs::write(dir.join("tri.mtl"), "newmtl tilted\nKd 0.5 0.5 0.5\nnorm -bm 1 tilt.png\n").unwrap();
+    fs::write(dir.join("tri.obj"), "mtllib tri.mtl\nv 0 0 0\nv 1 0 0\nv 0 0 -1\nusemtl tilted\nf 1 2 3\n").unwrap();
+    let triangles = obj_to_hitable(&dir.join("tri.obj")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let hit = triangles.hit(&Ray::new(Vec3::new(0.2, 1.0, -0.2), Vec3::new(0.0, -1.0, 0.0)), 0.001, f32::MAX).unwrap();
+    let wo = Vec3::new(0.0, 1.0, 0.0);
+    let lit = |wi: Vec3| hit.material.eval(&hit, &wi, &wo).x();
+
+    // Without texture coordinates, u runs along the first edge, here x
+    assert!((lit(Vec3::new(0.6, 0.8, 0.0)) - 0.5 / f32::consts::PI).abs() < 1e-2);
+    assert!(lit(Vec3::new(0.6, 0.8, 0.0)) > lit(Vec3::new(0.0, 1.0, 0.0)) + 0.01);
+  }
 }
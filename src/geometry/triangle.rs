@@ -1,7 +1,10 @@
+extern crate rand;
+
+use std::f32;
 use std::sync::Arc;
 
 use ::material::*;
-use ::vec3::{Vec3};
+use ::vec3::{Vec3, unit_vector};
 use ::ray::Ray;
 use ::aabb::Aabb;
 use ::geometry::*;
@@ -11,16 +14,50 @@ pub struct Triangle {
   v1: Vec3,
   v2: Vec3,
   normal: Vec3,
+  // Normals at v0, v1 and v2, interpolated over the triangle for smooth shading
+  vertex_normals: Option<[Vec3; 3]>,
+  // Surface coordinates at v0, v1 and v2, the barycentric coordinates are used if there are none
+  texcoords: Option<[[f32; 2]; 3]>,
   material: Arc<Material>
 }
 
 impl Triangle {
   pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Arc<Material>) -> Triangle {
-    Triangle { v0, v1, v2, normal: (v1 - v0).cross(v2 - v0), material }
+    Triangle { v0, v1, v2, normal: (v1 - v0).cross(v2 - v0), vertex_normals: None, texcoords: None, material }
+  }
+
+  /// A smooth shaded triangle, with the shading normal interpolated from
+  /// the normals at each vertex.
+  pub fn new_with_normals(v0: Vec3, v1: Vec3, v2: Vec3, normals: [Vec3; 3], material: Arc<Material>) -> Triangle {
+    Triangle { v0, v1, v2, normal: (v1 - v0).cross(v2 - v0), vertex_normals: Some(normals), texcoords: None, material }
   }
 
-  pub fn new_with_normal(v0: Vec3, v1: Vec3, v2: Vec3, normal: Vec3, material: Arc<Material>) -> Triangle {
-    Triangle { v0, v1, v2, normal, material }
+  /// The triangle with surface coordinates `u` and `v` interpolated from the
+  /// ones given for each vertex, for texturing.
+  pub fn with_texcoords(self, texcoords: [[f32; 2]; 3]) -> Triangle {
+    Triangle { texcoords: Some(texcoords), ..self }
+  }
+}
+
+impl Triangle {
+  // How points on the triangle move with the texture coordinates
+  fn texture_derivatives(&self) -> (Vec3, Vec3) {
+    let (e1, e2) = (self.v1 - self.v0, self.v2 - self.v0);
+    match self.texcoords {
+      Some(c) => {
+        let (du1, dv1) = (c[1][0] - c[0][0], c[1][1] - c[0][1]);
+        let (du2, dv2) = (c[2][0] - c[0][0], c[2][1] - c[0][1]);
+        let det = du1 * dv2 - dv1 * du2;
+        if det.abs() < 1e-12 {
+          // Degenerate texture coordinates
+          let zero = Vec3::new(0.0, 0.0, 0.0);
+          return (zero, zero);
+        }
+
+        ((dv2 * e1 - dv1 * e2) / det, (du1 * e2 - du2 * e1) / det)
+      },
+      None => (e1, e2)
+    }
   }
 }
 
@@ -68,14 +105,114 @@ impl Hitable for Triangle {
     }
 
     let p = r.point_at_parameter(t);
+    let (tex_u, tex_v) = match self.texcoords {
+      Some(c) => (
+        (1.0 - u - v) * c[0][0] + u * c[1][0] + v * c[2][0],
+        (1.0 - u - v) * c[0][1] + u * c[1][1] + v * c[2][1]),
+      None => (u, v)
+    };
+
+    if self.material.opacity(tex_u, tex_v, &p) < 0.5 {
+      return None
+    }
+
+    let (normal, geometric_normal) = match self.vertex_normals {
+      Some(n) => {
+        let shading_normal = unit_vector((1.0 - u - v) * n[0] + u * n[1] + v * n[2]);
+        // Keep the geometric normal on the same side as the shading normal,
+        // in case the vertex normals disagree with the winding order
+        if shading_normal.dot(self.normal) < 0.0 { (shading_normal, -self.normal) } else { (shading_normal, self.normal) }
+      },
+      None => (self.normal, self.normal)
+    };
+
+    let (dpdu, dpdv) = self.texture_derivatives();
 
     return Some(HitRecord {
-      u,
-      v,
+      u: tex_u,
+      v: tex_v,
+      dpdu,
+      dpdv,
+      uv_derivatives: None,
       t,
       p,
-      normal: self.normal,
+      normal,
+      geometric_normal,
       material: &*self.material
     })
   }
+
+  fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+    let area = 0.5 * (self.v1 - self.v0).cross(self.v2 - self.v0).length();
+    match self.hit(&Ray::new(*o, *v), 0.001, f32::MAX) {
+      Some(mut rec) => {
+        // The density depends on the actual surface, not the shading normal
+        rec.normal = rec.geometric_normal;
+        ::geometry::area_light_pdf(Some(rec), v, area)
+      },
+      None => 0.0
+    }
+  }
+
+  fn random(&self, o: &Vec3) -> Vec3 {
+    let su = rand::random::<f32>().sqrt();
+    let b0 = 1.0 - su;
+    let b1 = rand::random::<f32>() * su;
+    let random_point = b0 * self.v0 + b1 * self.v1 + (1.0 - b0 - b1) * self.v2;
+    random_point - *o
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn white() -> Arc<Material> {
+    Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) })
+  }
+
+  #[test]
+  fn interpolates_vertex_normals() {
+    let tilted = unit_vector(Vec3::new(1.0, 0.0, 1.0));
+    let up = Vec3::new(0.0, 0.0, 1.0);
+    let tri = Triangle::new_with_normals(
+      Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+      [up, tilted, up], white());
+
+    let at_v0 = tri.hit(&Ray::new(Vec3::new(0.01, 0.01, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+    let halfway = tri.hit(&Ray::new(Vec3::new(0.5, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+
+    assert!(at_v0.normal.x() < 0.01);
+    assert!((halfway.normal - unit_vector(up + tilted)).length() < 0.01);
+    assert!(unit_vector(halfway.geometric_normal).dot(up) > 0.9999);
+  }
+
+  #[test]
+  fn interpolates_texcoords_and_cuts_away_transparent_parts() {
+    // Opaque for u below one half
+    let mask = ::image_texture::Image::new(2, 1, vec![Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.0, 0.0, 0.0)]);
+    let alpha = ::image_texture::ImageTexture::new(Arc::new(mask)).with_filter(::image_texture::Filter::Nearest);
+    let material = Arc::new(AlphaCutout { material: white(), alpha: Box::new(alpha) });
+    let tri = Triangle::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material)
+      .with_texcoords([[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]);
+
+    let hit = tri.hit(&Ray::new(Vec3::new(0.2, 0.3, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+    assert!((hit.u - 0.2).abs() < 1e-5 && (hit.v - 0.3).abs() < 1e-5);
+    assert!(tri.hit(&Ray::new(Vec3::new(0.7, 0.1, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).is_none());
+  }
+
+  #[test]
+  fn geometric_normal_follows_vertex_normals() {
+    let down = Vec3::new(0.0, 0.0, -1.0);
+    let tri = Triangle::new_with_normals(
+      Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+      [down, down, down], white());
+    let hit = tri.hit(&Ray::new(Vec3::new(0.2, 0.2, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+
+    assert!(hit.geometric_normal.dot(down) > 0.0);
+  }
 }
@@ -0,0 +1,547 @@
+//! Reading and writing high dynamic range images: Radiance RGBE (`.hdr`) and
+//! portable float map (`.pfm`) files, holding linear colors that are not
+//! limited to the 0 to 1 range, and writing OpenEXR (`.exr`) files.
+
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+use ::vec3::Vec3;
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Decodes a Radiance RGBE image into its width, height and linear pixels,
+/// top row first. Both flat and run length encoded scanlines are supported.
+pub fn read_hdr<R: BufRead>(mut reader: R) -> io::Result<(usize, usize, Vec<Vec3>)> {
+  let magic = read_line(&mut reader)?;
+  if !magic.starts_with("#?") {
+    return Err(invalid("not a Radiance HDR file"));
+  }
+
+  // Header variables up to an empty line, of which only the format matters
+  loop {
+    let line = read_line(&mut reader)?;
+    if line.is_empty() {
+      break;
+    }
+    if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+      return Err(invalid(&format!("unsupported HDR pixel format {}", &line[7..])));
+    }
+  }
+
+  // Only the standard orientation, rows from top to bottom of left to right pixels
+  let resolution = read_line(&mut reader)?;
+  let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+    ["-Y", h, "+X", w] => (h.parse::<usize>().ok(), w.parse::<usize>().ok()),
+    _ => (None, None)
+  };
+  let (width, height) = match (width, height) {
+    (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
+    _ => return Err(invalid(&format!("unsupported HDR resolution {}", resolution)))
+  };
+
+  let mut pixels = Vec::with_capacity(width * height);
+  let mut scanline = vec![[0u8; 4]; width];
+  for _ in 0..height {
+    read_scanline(&mut reader, &mut scanline)?;
+    pixels.extend(scanline.iter().map(rgbe_to_color));
+  }
+
+  Ok((width, height, pixels))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+  let mut bytes = Vec::new();
+  if reader.read_until(b'\n', &mut bytes)? == 0 {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "HDR header ended early"));
+  }
+
+  Ok(String::from_utf8_lossy(&bytes).trim_end_matches(&['\n', '\r'][..]).to_string())
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+  let mut byte = [0u8];
+  reader.read_exact(&mut byte)?;
+  Ok(byte[0])
+}
+
+fn read_scanline<R: Read>(reader: &mut R, scanline: &mut [[u8; 4]]) -> io::Result<()> {
+  let width = scanline.len();
+  let mut first = [0u8; 4];
+  reader.read_exact(&mut first)?;
+
+  // Run length encoded scanlines start with two 2s and their width, and are
+  // only used for widths from 8 to 32767
+  let encoded = first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0 && (8..0x8000).contains(&width);
+  if !encoded {
+    scanline[0] = first;
+    for pixel in scanline[1..].iter_mut() {
+      reader.read_exact(pixel)?;
+    }
+    return Ok(());
+  }
+
+  if ((first[2] as usize) << 8 | first[3] as usize) != width {
+    return Err(invalid("HDR scanline width does not match the image"));
+  }
+
+  // Each channel is stored on its own, as runs of a repeated byte and
+  // stretches of literal bytes
+  for channel in 0..4 {
+    let mut x = 0;
+    while x < width {
+      let count = read_byte(reader)? as usize;
+      let (count, run) = if count > 128 { (count - 128, true) } else { (count, false) };
+      if count == 0 || x + count > width {
+        return Err(invalid("bad HDR scanline run length"));
+      }
+
+      if run {
+        let value = read_byte(reader)?;
+        for pixel in scanline[x..x + count].iter_mut() {
+          pixel[channel] = value;
+        }
+      } else {
+        for pixel in scanline[x..x + count].iter_mut() {
+          pixel[channel] = read_byte(reader)?;
+        }
+      }
+      x += count;
+    }
+  }
+
+  Ok(())
+}
+
+// The color of a pixel in shared exponent format, the way Radiance decodes it
+fn rgbe_to_color(rgbe: &[u8; 4]) -> Vec3 {
+  if rgbe[3] == 0 {
+    return Vec3::new(0.0, 0.0, 0.0);
+  }
+
+  let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+  Vec3::new((rgbe[0] as f32 + 0.5) * f, (rgbe[1] as f32 + 0.5) * f, (rgbe[2] as f32 + 0.5) * f)
+}
+
+/// Decodes a portable float map, in color (`PF`) or gray (`Pf`), into its
+/// width, height and pixels, top row first.
+pub fn read_pfm<R: BufRead>(mut reader: R) -> io::Result<(usize, usize, Vec<Vec3>)> {
+  let channels = match &read_token(&mut reader)?[..] {
+    "PF" => 3,
+    "Pf" => 1,
+    _ => return Err(invalid("not a PFM file"))
+  };
+  let width = read_token(&mut reader)?.parse::<usize>().map_err(|_| invalid("bad PFM width"))?;
+  let height = read_token(&mut reader)?.parse::<usize>().map_err(|_| invalid("bad PFM height"))?;
+  // The scale's sign gives the byte order, negative for little endian
+  let scale = read_token(&mut reader)?.parse::<f32>().map_err(|_| invalid("bad PFM scale"))?;
+  if width == 0 || height == 0 || scale == 0.0 {
+    return Err(invalid("empty PFM image"));
+  }
+
+  let mut data = vec![0u8; width * height * channels * 4];
+  reader.read_exact(&mut data)?;
+  let values: Vec<f32> = data.chunks(4).map(|b| {
+    let bytes = [b[0], b[1], b[2], b[3]];
+    let bits = if scale < 0.0 { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) };
+    f32::from_bits(bits)
+  }).collect();
+
+  // Rows are stored from the bottom up
+  let mut pixels = Vec::with_capacity(width * height);
+  for y in (0..height).rev() {
+    for x in 0..width {
+      let i = (y * width + x) * channels;
+      pixels.push(if channels == 3 {
+        Vec3::new(values[i], values[i + 1], values[i + 2])
+      } else {
+        Vec3::new(values[i], values[i], values[i])
+      });
+    }
+  }
+
+  Ok((width, height, pixels))
+}
+
+// A header token, consuming the single whitespace character following it
+fn read_token<R: BufRead>(reader: &mut R) -> io::Result<String> {
+  let mut token = String::new();
+  loop {
+    let byte = read_byte(reader)?;
+    if (byte as char).is_ascii_whitespace() {
+      if token.is_empty() {
+        continue;
+      }
+      return Ok(token);
+    }
+    token.push(byte as char);
+    if token.len() > 32 {
+      return Err(invalid("bad PFM header"));
+    }
+  }
+}
+
+/// Encodes linear `pixels`, top row first, as a Radiance RGBE image, with
+/// run length encoded scanlines where the format allows them.
+pub fn write_hdr<W: Write>(w: &mut W, width: usize, height: usize, pixels: &[Vec3]) -> io::Result<()> {
+  write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+
+  let encoded = (8..0x8000).contains(&width);
+  let mut channel = vec![0u8; width];
+  for row in pixels.chunks(width) {
+    let scanline: Vec<[u8; 4]> = row.iter().map(color_to_rgbe).collect();
+    if !encoded {
+      for rgbe in scanline.iter() {
+        w.write_all(rgbe)?;
+      }
+      continue;
+    }
+
+    w.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+    for c in 0..4 {
+      for (x, rgbe) in scanline.iter().enumerate() {
+        channel[x] = rgbe[c];
+      }
+      write_runs(w, &channel)?;
+    }
+  }
+
+  Ok(())
+}
+
+// Runs of at least four equal bytes as runs, and the bytes in between as literals
+fn write_runs<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+  let mut x = 0;
+  while x < bytes.len() {
+    // Where the next run long enough to be worth it starts
+    let (mut run_start, mut run_length) = (x, 0);
+    while run_start < bytes.len() {
+      run_length = 1;
+      while run_start + run_length < bytes.len() && run_length < 127 && bytes[run_start + run_length] == bytes[run_start] {
+        run_length += 1;
+      }
+      if run_length >= 4 {
+        break;
+      }
+      run_start += run_length;
+    }
+
+    while x < run_start {
+      let count = (run_start - x).min(128);
+      w.write_all(&[count as u8])?;
+      w.write_all(&bytes[x..x + count])?;
+      x += count;
+    }
+
+    if run_start < bytes.len() {
+      w.write_all(&[128 + run_length as u8, bytes[run_start]])?;
+      x += run_length;
+    }
+  }
+
+  Ok(())
+}
+
+// A color in shared exponent format, with negative values clamped to zero
+fn color_to_rgbe(color: &Vec3) -> [u8; 4] {
+  let (r, g, b) = (color[0].max(0.0), color[1].max(0.0), color[2].max(0.0));
+  let max = r.max(g).max(b);
+  if max.is_nan() || max < 1e-32 {
+    return [0, 0, 0, 0];
+  }
+  if !max.is_finite() {
+    return [255, 255, 255, 255];
+  }
+
+  // The exponent making max / 2^exponent fall between 0.5 and 1
+  let mut exponent = max.log2().floor() as i32 + 1;
+  if max / 2f32.powi(exponent) >= 1.0 {
+    exponent += 1;
+  } else if max / 2f32.powi(exponent) < 0.5 {
+    exponent -= 1;
+  }
+  if exponent > 127 {
+    return [255, 255, 255, 255];
+  }
+
+  let scale = 256.0 / 2f32.powi(exponent);
+  [(r * scale) as u8, (g * scale) as u8, (b * scale) as u8, (exponent + 128) as u8]
+}
+
+/// Encodes linear `pixels`, top row first, as a little endian color
+/// portable float map.
+pub fn write_pfm<W: Write>(w: &mut W, width: usize, height: usize, pixels: &[Vec3]) -> io::Result<()> {
+  write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+
+  // Rows are stored from the bottom up
+  for row in pixels.chunks(width).rev() {
+    for p in row {
+      for k in 0..3 {
+        w.write_all(&p[k].to_le_bytes())?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// How the color channels of OpenEXR files are stored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExrPixelType {
+  /// 16-bit floats, plenty for colors and half the size
+  Half,
+  /// 32-bit floats
+  Float
+}
+
+/// Encodes linear `pixels`, top row first, as an uncompressed scanline
+/// OpenEXR image with R, G and B channels.
+pub fn write_exr<W: Write>(w: &mut W, width: usize, height: usize, pixels: &[Vec3], pixel_type: ExrPixelType) -> io::Result<()> {
+  let (type_code, bytes_per_value) = match pixel_type {
+    ExrPixelType::Half => (1i32, 2),
+    ExrPixelType::Float => (2i32, 4)
+  };
+
+  // Magic number, then version 2 of the format for single part scanline images
+  w.write_all(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0])?;
+
+  // Channels are listed, and stored, in alphabetical order
+  let mut channels = Vec::new();
+  for name in &["B", "G", "R"] {
+    channels.extend_from_slice(name.as_bytes());
+    channels.push(0);
+    channels.extend_from_slice(&type_code.to_le_bytes());
+    // Not linear perceptually, three reserved bytes and no subsampling
+    channels.extend_from_slice(&[0, 0, 0, 0]);
+    channels.extend_from_slice(&1i32.to_le_bytes());
+    channels.extend_from_slice(&1i32.to_le_bytes());
+  }
+  channels.push(0);
+
+  let mut window = Vec::new();
+  for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+    window.extend_from_slice(&v.to_le_bytes());
+  }
+
+  let mut header = Vec::new();
+  write_exr_attribute(&mut header, "channels", "chlist", &channels);
+  write_exr_attribute(&mut header, "compression", "compression", &[0]);
+  write_exr_attribute(&mut header, "dataWindow", "box2i", &window);
+  write_exr_attribute(&mut header, "displayWindow", "box2i", &window);
+  write_exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+  write_exr_attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+  write_exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+  write_exr_attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes());
+  header.push(0);
+  w.write_all(&header)?;
+
+  // Offsets of every scanline from the start of the file, each stored as
+  // its row number, its size and then its channels one after the other
+  let line_size = width * 3 * bytes_per_value;
+  let first_line = (8 + header.len() + 8 * height) as u64;
+  for y in 0..height {
+    w.write_all(&(first_line + (y * (8 + line_size)) as u64).to_le_bytes())?;
+  }
+
+  for (y, row) in pixels.chunks(width).enumerate() {
+    w.write_all(&(y as i32).to_le_bytes())?;
+    w.write_all(&(line_size as i32).to_le_bytes())?;
+    for &k in &[2, 1, 0] {
+      for p in row {
+        match pixel_type {
+          ExrPixelType::Half => w.write_all(&f32_to_half(p[k]).to_le_bytes())?,
+          ExrPixelType::Float => w.write_all(&p[k].to_le_bytes())?
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn write_exr_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+  header.extend_from_slice(name.as_bytes());
+  header.push(0);
+  header.extend_from_slice(kind.as_bytes());
+  header.push(0);
+  header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+  header.extend_from_slice(value);
+}
+
+// The closest 16-bit float, rounding halfway cases up
+fn f32_to_half(value: f32) -> u16 {
+  let bits = value.to_bits();
+  let sign = ((bits >> 16) & 0x8000) as u16;
+  let exponent = ((bits >> 23) & 0xff) as i32;
+  let mantissa = bits & 0x7f_ffff;
+
+  if exponent == 0xff {
+    // Infinity stays infinity, and NaN stays NaN
+    return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+  }
+
+  let half_exponent = exponent - 127 + 15;
+  if half_exponent >= 31 {
+    return sign | 0x7c00;
+  }
+  if half_exponent <= 0 {
+    // Too small for a normal half float, but maybe not for a subnormal one
+    if half_exponent < -10 {
+      return sign;
+    }
+    let mantissa = mantissa | 0x80_0000;
+    let shift = (14 - half_exponent) as u32;
+    return sign | ((mantissa >> shift) + ((mantissa >> (shift - 1)) & 1)) as u16;
+  }
+
+  // A carry from rounding correctly moves on into the exponent
+  sign | (((half_exponent as u32) << 10 | mantissa >> 13) + ((mantissa >> 12) & 1)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn assert_color(expected: Vec3, actual: Vec3) {
+    assert!((expected - actual).length() < 1e-3 * expected.length().max(1.0), "expected {:?}, got {:?}", expected, actual);
+  }
+
+  #[test]
+  fn reads_flat_and_run_length_encoded_hdr() {
+    // A flat 2x1 image
+    let mut flat = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+    flat.extend_from_slice(&[128, 64, 0, 129, 0, 0, 0, 0]);
+    let (width, height, pixels) = read_hdr(Cursor::new(flat)).unwrap();
+    assert_eq!((2, 1), (width, height));
+    assert_color(Vec3::new(128.5 / 128.0, 64.5 / 128.0, 0.5 / 128.0), pixels[0]);
+    assert_color(Vec3::new(0.0, 0.0, 0.0), pixels[1]);
+
+    // An encoded 8x2 image: red is a run, the other channels literals
+    let mut encoded = b"#?RGBE\n\n-Y 2 +X 8\n".to_vec();
+    for y in 0..2u8 {
+      encoded.extend_from_slice(&[2, 2, 0, 8]);
+      encoded.extend_from_slice(&[128 + 8, 200]);
+      encoded.push(8);
+      encoded.extend((0..8).map(|x| x * 10 + y));
+      encoded.extend_from_slice(&[128 + 8, 0]);
+      encoded.extend_from_slice(&[128 + 8, 130]);
+    }
+    let (width, height, pixels) = read_hdr(Cursor::new(encoded)).unwrap();
+    assert_eq!((8, 2), (width, height));
+    assert_color(Vec3::new(200.5 / 64.0, 31.5 / 64.0, 0.5 / 64.0), pixels[8 + 3]);
+  }
+
+  #[test]
+  fn rejects_hdr_with_unsupported_orientation() {
+    let flipped = b"#?RADIANCE\n\n+Y 1 +X 1\n\0\0\0\0".to_vec();
+    assert!(read_hdr(Cursor::new(flipped)).is_err());
+  }
+
+  #[test]
+  fn reads_pfm_in_either_byte_order_bottom_row_first() {
+    let mut little = b"PF\n1 2\n-1.0\n".to_vec();
+    for v in &[1.0f32, 2.0, 3.0, 40.0, 50.0, 60.0] {
+      little.extend_from_slice(&v.to_le_bytes());
+    }
+    let (width, height, pixels) = read_pfm(Cursor::new(little)).unwrap();
+    assert_eq!((1, 2), (width, height));
+    assert_color(Vec3::new(40.0, 50.0, 60.0), pixels[0]);
+    assert_color(Vec3::new(1.0, 2.0, 3.0), pixels[1]);
+
+    let mut big = b"Pf\n2 1\n1.0\n".to_vec();
+    for v in &[0.25f32, 1e3] {
+      big.extend_from_slice(&v.to_be_bytes());
+    }
+    let (_, _, pixels) = read_pfm(Cursor::new(big)).unwrap();
+    assert_color(Vec3::new(1e3, 1e3, 1e3), pixels[1]);
+  }
+
+  // A test image with runs, gradients and a wide range of values
+  fn test_pixels(width: usize, height: usize) -> Vec<Vec3> {
+    (0..width * height).map(|i| {
+      let (x, y) = (i % width, i / width);
+      if y == 0 {
+        Vec3::new(0.25, 0.5, 1.0)
+      } else {
+        Vec3::new(x as f32 * 0.37, 1e3 / (1.0 + x as f32), (y * x) as f32 * 1e-3)
+      }
+    }).collect()
+  }
+
+  #[test]
+  fn hdr_round_trips_with_and_without_run_length_encoding() {
+    for &(width, height) in &[(300, 3), (5, 2)] {
+      let pixels = test_pixels(width, height);
+      let mut file = Vec::new();
+      write_hdr(&mut file, width, height, &pixels).unwrap();
+
+      let (w, h, read) = read_hdr(Cursor::new(file)).unwrap();
+      assert_eq!((width, height), (w, h));
+      for (expected, actual) in pixels.iter().zip(read.iter()) {
+        // Shared exponents keep about 8 bits of the largest channel
+        let tolerance = 0.01 * expected[0].max(expected[1]).max(expected[2]);
+        for k in 0..3 {
+          assert!((expected[k] - actual[k]).abs() <= tolerance, "expected {:?}, got {:?}", expected, actual);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn run_length_encoding_shrinks_flat_scanlines() {
+    let pixels = vec![Vec3::new(0.5, 0.5, 0.5); 200];
+    let mut file = Vec::new();
+    write_hdr(&mut file, 200, 1, &pixels).unwrap();
+    assert!(file.len() < 100, "{} bytes", file.len());
+  }
+
+  #[test]
+  fn pfm_round_trips_exactly() {
+    let pixels = test_pixels(7, 4);
+    let mut file = Vec::new();
+    write_pfm(&mut file, 7, 4, &pixels).unwrap();
+
+    let (width, height, read) = read_pfm(Cursor::new(file)).unwrap();
+    assert_eq!((7, 4), (width, height));
+    assert!(pixels.iter().zip(read.iter()).all(|(a, b)| a.e == b.e));
+  }
+
+  #[test]
+  fn converts_floats_to_halves() {
+    assert_eq!(0x3c00, f32_to_half(1.0));
+    assert_eq!(0xb800, f32_to_half(-0.5));
+    assert_eq!(0x7bff, f32_to_half(65504.0));
+    assert_eq!(0x7c00, f32_to_half(1e6));
+    assert_eq!(0x0001, f32_to_half(2f32.powi(-24)));
+    assert_eq!(0x0000, f32_to_half(1e-10));
+    assert_eq!(0x3555, f32_to_half(1.0 / 3.0));
+    assert_eq!(0x7e00, f32_to_half(f32::NAN));
+  }
+
+  #[test]
+  fn writes_exr_scanlines_where_the_offsets_point() {
+    let pixels = test_pixels(3, 2);
+    for &(pixel_type, bytes) in &[(ExrPixelType::Half, 2), (ExrPixelType::Float, 4)] {
+      let mut file = Vec::new();
+      write_exr(&mut file, 3, 2, &pixels, pixel_type).unwrap();
+      assert_eq!(&[0x76, 0x2f, 0x31, 0x01], &file[..4]);
+
+      let line_size = 3 * 3 * bytes;
+      let read_u32 = |i: usize| u32::from_le_bytes([file[i], file[i + 1], file[i + 2], file[i + 3]]) as usize;
+      // The offsets are the last thing before the scanlines
+      let offsets = file.len() - 2 * (8 + line_size) - 16;
+      let second = read_u32(offsets + 8);
+      assert_eq!(1, read_u32(second));
+      assert_eq!(line_size, read_u32(second + 4));
+
+      // Red comes last of the channels, and the second pixel in it is (0.37, ...)
+      let red = second + 8 + 2 * 3 * bytes + bytes;
+      match pixel_type {
+        ExrPixelType::Half => assert_eq!(f32_to_half(0.37), u16::from_le_bytes([file[red], file[red + 1]])),
+        ExrPixelType::Float => assert_eq!(0.37f32.to_bits() as usize, read_u32(red))
+      }
+    }
+  }
+}
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use ::vec3::Vec3;
+use ::hdr;
+use ::material::{Texture, HitRecord};
+
+/// How the values stored in an image file are to be interpreted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ImageEncoding {
+  /// sRGB encoded colors, as in most color maps, converted to linear values when loaded.
+  Srgb,
+  /// Values used as they are, as in bump maps.
+  Linear,
+  /// The alpha channel as a gray image, or the brightness of an image without
+  /// alpha channel, as in opacity maps.
+  Alpha
+}
+
+/// Linear RGB pixels of an image, which `ImageTexture`s look up colors in,
+/// together with its mipmap: copies of the image halving in size down to a
+/// single pixel.
+pub struct Image {
+  pub width: usize,
+  pub height: usize,
+  // The image itself first, followed by ever smaller copies
+  levels: Vec<Level>
+}
+
+struct Level {
+  width: usize,
+  height: usize,
+  // Rows of pixels, top row first
+  pixels: Vec<Vec3>
+}
+
+impl Level {
+  fn pixel(&self, x: usize, y: usize) -> Vec3 {
+    self.pixels[y * self.width + x]
+  }
+
+  // The level at half the size, each pixel averaging the up to four pixels it covers
+  fn downsample(&self) -> Level {
+    let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+      for x in 0..width {
+        let (x0, y0) = (2 * x, 2 * y);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        pixels.push(0.25 * (self.pixel(x0, y0) + self.pixel(x1, y0) + self.pixel(x0, y1) + self.pixel(x1, y1)));
+      }
+    }
+
+    Level { width, height, pixels }
+  }
+}
+
+impl Image {
+  pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Image {
+    assert_eq!(width * height, pixels.len(), "Image size does not match its dimensions");
+    assert!(width > 0 && height > 0, "Image is empty");
+
+    let mut levels = vec![Level { width, height, pixels }];
+    while levels.last().map_or(false, |l| l.width > 1 || l.height > 1) {
+      let next = levels.last().unwrap().downsample();
+      levels.push(next);
+    }
+
+    Image { width, height, levels }
+  }
+
+  /// Loads a PNG image, or a Radiance HDR or PFM image by their extension.
+  /// High dynamic range images hold linear colors, so they are never
+  /// converted from sRGB.
+  pub fn load(path: &Path, encoding: ImageEncoding) -> io::Result<Image> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    let (width, height, rgba) = match extension.as_ref().map(|e| &e[..]) {
+      Some("hdr") => read_high_dynamic_range(hdr::read_hdr(BufReader::new(File::open(path)?))?),
+      Some("pfm") => read_high_dynamic_range(hdr::read_pfm(BufReader::new(File::open(path)?))?),
+      _ => read_png(path)?
+    };
+    let encoding = match (encoding, extension.as_ref().map(|e| &e[..])) {
+      (ImageEncoding::Srgb, Some("hdr")) | (ImageEncoding::Srgb, Some("pfm")) => ImageEncoding::Linear,
+      _ => encoding
+    };
+    if width == 0 || height == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "empty image"));
+    }
+
+    let has_alpha = rgba.iter().any(|p| p[3] < 1.0);
+    let pixels = rgba.iter().map(|p| match encoding {
+      ImageEncoding::Srgb => Vec3::new(srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2])),
+      ImageEncoding::Linear => Vec3::new(p[0], p[1], p[2]),
+      ImageEncoding::Alpha => {
+        let a = if has_alpha { p[3] } else { (p[0] + p[1] + p[2]) / 3.0 };
+        Vec3::new(a, a, a)
+      }
+    }).collect();
+
+    Ok(Image::new(width, height, pixels))
+  }
+
+  /// The pixel at column `x` and row `y`, counted from the top left corner.
+  pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+    self.levels[0].pixel(x, y)
+  }
+
+  /// Number of mipmap levels, including the full size image.
+  pub fn mip_levels(&self) -> usize {
+    self.levels.len()
+  }
+}
+
+/// How texture coordinates outside of the 0 to 1 range are mapped onto an image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WrapMode {
+  /// The image repeats.
+  Repeat,
+  /// The image's edge pixels stretch out.
+  Clamp,
+  /// The image repeats, with every other copy mirrored.
+  Mirror
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+  /// The color of the closest pixel.
+  Nearest,
+  /// Interpolates between the four closest pixels.
+  Bilinear,
+  /// Interpolates bilinearly in the two mipmap levels closest to the size of
+  /// the pixel's footprint on the surface, when it is known.
+  Trilinear
+}
+
+/// A texture looked up in an image by surface coordinates, with `u` running
+/// from the left edge to the right and `v` from the bottom edge to the top.
+///
+/// Textures share their image, so the same image can be used with different
+/// settings without being loaded again.
+pub struct ImageTexture {
+  image: Arc<Image>,
+  pub wrap: WrapMode,
+  pub filter: Filter
+}
+
+impl ImageTexture {
+  /// A trilinearly filtered texture, repeating the image.
+  pub fn new(image: Arc<Image>) -> ImageTexture {
+    ImageTexture { image, wrap: WrapMode::Repeat, filter: Filter::Trilinear }
+  }
+
+  pub fn with_wrap(self, wrap: WrapMode) -> ImageTexture {
+    ImageTexture { wrap, ..self }
+  }
+
+  pub fn with_filter(self, filter: Filter) -> ImageTexture {
+    ImageTexture { filter, ..self }
+  }
+
+  pub fn image(&self) -> &Image {
+    &self.image
+  }
+
+  fn texel(&self, level: &Level, x: i64, y: i64) -> Vec3 {
+    level.pixel(wrap(x, level.width, self.wrap), wrap(y, level.height, self.wrap))
+  }
+
+  fn bilinear(&self, level: &Level, u: f32, v: f32) -> Vec3 {
+    // Pixel centers are at half integer coordinates
+    let x = u * level.width as f32 - 0.5;
+    let y = (1.0 - v) * level.height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    (1.0 - ty) * ((1.0 - tx) * self.texel(level, x0, y0) + tx * self.texel(level, x0 + 1, y0)) +
+      ty * ((1.0 - tx) * self.texel(level, x0, y0 + 1) + tx * self.texel(level, x0 + 1, y0 + 1))
+  }
+}
+
+impl Texture for ImageTexture {
+  fn value(&self, u: f32, v: f32, _p: &Vec3) -> Vec3 {
+    let full = &self.image.levels[0];
+    match self.filter {
+      Filter::Nearest => self.texel(full, (u * full.width as f32).floor() as i64, ((1.0 - v) * full.height as f32).floor() as i64),
+      Filter::Bilinear | Filter::Trilinear => self.bilinear(full, u, v)
+    }
+  }
+
+  fn filtered_value(&self, rec: &HitRecord) -> Vec3 {
+    let d = match (self.filter, rec.uv_derivatives) {
+      (Filter::Trilinear, Some(d)) => d,
+      _ => return self.value(rec.u, rec.v, &rec.p)
+    };
+
+    // The level where the longest side of the footprint is about a pixel wide
+    let (width, height) = (self.image.width as f32, self.image.height as f32);
+    let footprint = (d.dudx * width).hypot(d.dvdx * height).max((d.dudy * width).hypot(d.dvdy * height));
+    let level = footprint.max(1.0).log2().min((self.image.levels.len() - 1) as f32);
+    if !level.is_finite() {
+      return self.value(rec.u, rec.v, &rec.p);
+    }
+
+    let coarse = level.floor() as usize;
+    let fine_color = self.bilinear(&self.image.levels[coarse], rec.u, rec.v);
+    if coarse + 1 >= self.image.levels.len() {
+      return fine_color;
+    }
+
+    let t = level - coarse as f32;
+    (1.0 - t) * fine_color + t * self.bilinear(&self.image.levels[coarse + 1], rec.u, rec.v)
+  }
+}
+
+// Maps pixel index `i` onto an image `size` pixels wide
+fn wrap(i: i64, size: usize, mode: WrapMode) -> usize {
+  let n = size as i64;
+  match mode {
+    WrapMode::Repeat => i.rem_euclid(n) as usize,
+    WrapMode::Clamp => i.max(0).min(n - 1) as usize,
+    WrapMode::Mirror => {
+      let m = i.rem_euclid(2 * n);
+      (if m < n { m } else { 2 * n - 1 - m }) as usize
+    }
+  }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+  if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Images loaded from files, kept so that an image used by many textures is
+/// only loaded once. The cache can be shared between threads.
+pub struct TextureCache {
+  images: Mutex<HashMap<(PathBuf, ImageEncoding), Arc<Image>>>
+}
+
+impl TextureCache {
+  pub fn new() -> TextureCache {
+    TextureCache { images: Mutex::new(HashMap::new()) }
+  }
+
+  /// The image at `path`, loading it unless it has been loaded before.
+  /// Images that fail to load are not cached.
+  pub fn load(&self, path: &Path, encoding: ImageEncoding) -> io::Result<Arc<Image>> {
+    let key = (path.to_path_buf(), encoding);
+    if let Some(image) = self.images.lock().unwrap().get(&key) {
+      return Ok(Arc::clone(image));
+    }
+
+    // Not holding the lock while loading, at worst an image is loaded twice
+    let image = Arc::new(Image::load(path, encoding)?);
+    Ok(Arc::clone(self.images.lock().unwrap().entry(key).or_insert(image)))
+  }
+
+  /// Number of images in the cache.
+  pub fn len(&self) -> usize {
+    self.images.lock().unwrap().len()
+  }
+}
+
+fn read_high_dynamic_range((width, height, pixels): (usize, usize, Vec<Vec3>)) -> (usize, usize, Vec<[f32; 4]>) {
+  (width, height, pixels.iter().map(|p| [p[0], p[1], p[2], 1.0]).collect())
+}
+
+// Decodes a PNG image into RGBA values between 0 and 1
+fn read_png(path: &Path) -> io::Result<(usize, usize, Vec<[f32; 4]>)> {
+  let decoder = png::Decoder::new(File::open(path)?);
+  let (info, mut reader) = decoder.read_info()?;
+  let mut buffer = vec![0; info.buffer_size()];
+  reader.next_frame(&mut buffer)?;
+
+  let channels = match reader.output_color_type().0 {
+    png::ColorType::Grayscale => 1,
+    png::ColorType::GrayscaleAlpha => 2,
+    png::ColorType::RGB => 3,
+    png::ColorType::RGBA => 4,
+    png::ColorType::Indexed => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected indexed PNG data"))
+  };
+
+  let (width, height) = (info.width as usize, info.height as usize);
+  let rgba = (0..width * height).map(|i| {
+    let p: Vec<f32> = buffer[i * channels..(i + 1) * channels].iter().map(|&c| c as f32 / 255.0).collect();
+    match channels {
+      1 => [p[0], p[0], p[0], 1.0],
+      2 => [p[0], p[0], p[0], p[1]],
+      3 => [p[0], p[1], p[2], 1.0],
+      _ => [p[0], p[1], p[2], p[3]]
+    }
+  }).collect();
+
+  Ok((width, height, rgba))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::fs;
+  use ::framebuffer::write_rgb8_png;
+  use ::material::{ConstantTexture, UvDerivatives};
+
+  fn two_by_two() -> Arc<Image> {
+    Arc::new(Image::new(2, 2, vec![
+      Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+      Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 1.0, 1.0)]))
+  }
+
+  fn assert_color(expected: Vec3, actual: Vec3) {
+    assert!((expected - actual).length() < 1e-5, "expected {:?}, got {:?}", expected, actual);
+  }
+
+  #[test]
+  fn looks_up_pixels_with_v_upwards_and_repeats() {
+    let texture = ImageTexture::new(two_by_two()).with_filter(Filter::Nearest);
+    let p = Vec3::new(0.0, 0.0, 0.0);
+
+    assert_color(Vec3::new(1.0, 0.0, 0.0), texture.value(0.25, 0.75, &p));
+    assert_color(Vec3::new(0.0, 0.0, 1.0), texture.value(0.25, 0.25, &p));
+    assert_color(Vec3::new(1.0, 1.0, 1.0), texture.value(1.75, -0.75, &p));
+  }
+
+  #[test]
+  fn filters_bilinearly() {
+    let texture = ImageTexture::new(two_by_two()).with_wrap(WrapMode::Clamp);
+    let p = Vec3::new(0.0, 0.0, 0.0);
+
+    assert_color(Vec3::new(1.0, 0.0, 0.0), texture.value(0.25, 0.75, &p));
+    assert_color(Vec3::new(0.5, 0.5, 0.5), texture.value(0.5, 0.5, &p));
+    assert_color(Vec3::new(0.5, 0.5, 0.0), texture.value(0.5, 1.0, &p));
+  }
+
+  #[test]
+  fn builds_mipmap_down_to_a_pixel() {
+    let image = Image::new(5, 2, vec![Vec3::new(1.0, 0.5, 0.0); 10]);
+
+    // 5x2, 2x1 and 1x1
+    assert_eq!(3, image.mip_levels());
+    assert_eq!(1, image.levels[2].width);
+    assert_color(Vec3::new(1.0, 0.5, 0.0), image.levels[2].pixel(0, 0));
+  }
+
+  #[test]
+  fn picks_mipmap_level_from_footprint() {
+    // A checkerboard that averages to gray
+    let pixels = (0..64).map(|i| if (i % 8 + i / 8) % 2 == 0 { Vec3::new(1.0, 1.0, 1.0) } else { Vec3::new(0.0, 0.0, 0.0) }).collect();
+    let texture = ImageTexture::new(Arc::new(Image::new(8, 8, pixels)));
+    let material = ::material::Lambertian { albedo: Box::new(ConstantTexture::new(1.0, 1.0, 1.0)) };
+    let mut rec = HitRecord {
+      t: 1.0, p: Vec3::new(0.0, 0.0, 0.0), normal: Vec3::new(0.0, 0.0, 1.0), geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+      material: &material, u: 0.5 / 8.0, v: 1.0 - 0.5 / 8.0, dpdu: Vec3::new(1.0, 0.0, 0.0), dpdv: Vec3::new(0.0, 1.0, 0.0),
+      uv_derivatives: None
+    };
+
+    assert_color(Vec3::new(1.0, 1.0, 1.0), texture.filtered_value(&rec));
+    rec.uv_derivatives = Some(UvDerivatives { dudx: 1.0, dvdx: 0.0, dudy: 0.0, dvdy: 1.0 });
+    assert_color(Vec3::new(0.5, 0.5, 0.5), texture.filtered_value(&rec));
+  }
+
+  #[test]
+  fn wraps_pixel_indices() {
+    assert_eq!(vec![1, 2, 0, 1, 2, 0], (-2..4).map(|i| wrap(i, 3, WrapMode::Repeat)).collect::<Vec<_>>());
+    assert_eq!(vec![0, 0, 0, 1, 2, 2], (-2..4).map(|i| wrap(i, 3, WrapMode::Clamp)).collect::<Vec<_>>());
+    assert_eq!(vec![1, 0, 0, 1, 2, 2], (-2..4).map(|i| wrap(i, 3, WrapMode::Mirror)).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn loads_png_once_and_converts_srgb() {
+    let path = env::temp_dir().join(format!("rt_in_1_week_texture_{}.png", std::process::id()));
+    write_rgb8_png(&path, 2, 1, &[188, 188, 188, 255, 0, 0]).unwrap();
+
+    let cache = TextureCache::new();
+    let srgb = cache.load(&path, ImageEncoding::Srgb).unwrap();
+    let again = cache.load(&path, ImageEncoding::Srgb).unwrap();
+    let linear = cache.load(&path, ImageEncoding::Linear).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(Arc::ptr_eq(&srgb, &again));
+    assert_eq!(2, cache.len());
+    assert!((srgb.pixel(0, 0).x() - 0.5029).abs() < 1e-3);
+    assert_color(Vec3::new(188.0 / 255.0, 188.0 / 255.0, 188.0 / 255.0), linear.pixel(0, 0));
+    assert_color(Vec3::new(1.0, 0.0, 0.0), srgb.pixel(1, 0));
+  }
+}
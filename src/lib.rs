@@ -0,0 +1,79 @@
+//! A path tracer built from the book Ray Tracing in One Weekend and its sequels.
+//!
+//! A scene is a list of `Hitable` models, each with a `Material`, wrapped in
+//! a `Scene` together with an environment that gives the color of rays that
+//! don't hit anything. The scene is rendered from a `Camera` into a
+//! `Framebuffer` of linear colors, which can then be written to a PNG file
+//! through a `DisplayTransform` of exposure and tone mapping, or to OpenEXR, PFM or Radiance HDR files keeping the full range of light:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use rt_in_1_week::*;
+//!
+//! let world: Vec<Box<Hitable>> = vec![
+//!   Box::new(Sphere {
+//!     center: Vec3::new(0.0, 0.0, -1.0),
+//!     radius: 0.5,
+//!     material: Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.8, 0.3, 0.3)) })
+//!   })
+//! ];
+//! let scene = Scene::new(world, Box::new(SimpleSky {}), 10);
+//! let camera = Camera::new(
+//!   Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+//!   90.0, 1.0, 0.0, 1.0);
+//!
+//! let image = render(&scene, &camera, 64, 64, 16);
+//! image.write_png("sphere.png", &DisplayTransform::new(ToneMapper::Aces)).unwrap();
+//! ```
+//!
+//! Scenes can also be loaded from JSON scene description files, see
+//! `scene_file::load_scene_file`.
+
+extern crate rand;
+extern crate png;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod vec3;
+pub mod mat44;
+pub mod ray;
+pub mod geometry;
+pub mod bvh;
+pub mod camera;
+pub mod renderer;
+pub mod aabb;
+pub mod onb;
+pub mod material;
+pub mod microfacet;
+pub mod image_texture;
+pub mod hdr;
+pub mod noise_texture;
+pub mod scene;
+pub mod environment_map;
+pub mod sky;
+pub mod light;
+pub mod scene_file;
+pub mod framebuffer;
+pub mod tonemap;
+pub mod demo;
+
+pub use vec3::{Vec3, unit_vector};
+pub use mat44::Mat44;
+pub use ray::Ray;
+pub use geometry::*;
+pub use geometry::triangle::Triangle;
+pub use geometry::instance::Instance;
+pub use material::*;
+pub use microfacet::{Ggx, Conductor, ConductorPreset, RoughDielectric};
+pub use image_texture::{Image, ImageTexture, ImageEncoding, WrapMode, Filter, TextureCache};
+pub use noise_texture::{Perlin, ColorRamp, NoisePattern, NoiseTexture};
+pub use camera::Camera;
+pub use renderer::render;
+pub use scene::*;
+pub use environment_map::EnvironmentMap;
+pub use sky::PhysicalSky;
+pub use light::{PunctualLight, PointLight, SpotLight, DirectionalLight, LightProfile};
+pub use framebuffer::Framebuffer;
+pub use hdr::ExrPixelType;
+pub use tonemap::{DisplayTransform, ToneMapper};
@@ -0,0 +1,320 @@
+use std::f32;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use ::vec3::*;
+
+/// Light arriving at a point from a `PunctualLight`.
+pub struct Illumination {
+  /// Unit direction from the point towards the light
+  pub direction: Vec3,
+  /// Distance to the light, which anything closer casts a shadow within
+  pub distance: f32,
+  /// Irradiance at the point, on a surface facing the light
+  pub irradiance: Vec3
+}
+
+/// A light without size, which is never hit by rays but lights every
+/// shading point directly, unless something is in the way.
+pub trait PunctualLight : Sync + Send {
+  /// The light arriving at `p`, if any.
+  fn illuminate(&self, p: &Vec3) -> Option<Illumination>;
+}
+
+/// Light spreading out equally in all directions from a point, with
+/// `intensity` being the radiant intensity.
+pub struct PointLight {
+  pub position: Vec3,
+  pub intensity: Vec3
+}
+
+impl PunctualLight for PointLight {
+  fn illuminate(&self, p: &Vec3) -> Option<Illumination> {
+    point_illumination(&self.position, p, self.intensity)
+  }
+}
+
+fn point_illumination(position: &Vec3, p: &Vec3, intensity: Vec3) -> Option<Illumination> {
+  let to_light = *position - *p;
+  let distance_squared = to_light.squared_length();
+  if distance_squared <= 0.0 {
+    return None;
+  }
+
+  let distance = distance_squared.sqrt();
+  Some(Illumination { direction: to_light / distance, distance, irradiance: intensity / distance_squared })
+}
+
+/// Light from a point within a cone, fading out between the inner and outer
+/// angles of the cone, and optionally shaped by a profile of how the
+/// intensity varies with the angle from the cone's axis.
+pub struct SpotLight {
+  pub position: Vec3,
+  direction: Vec3,
+  /// Radiant intensity along the cone's axis
+  pub intensity: Vec3,
+  cos_inner: f32,
+  cos_outer: f32,
+  profile: Option<LightProfile>
+}
+
+impl SpotLight {
+  /// A spot light shining along `direction`, fully lit out to `angle`
+  /// degrees from its axis.
+  pub fn new(position: Vec3, direction: Vec3, intensity: Vec3, angle: f32) -> SpotLight {
+    let cos = angle.to_radians().cos();
+    SpotLight { position, direction: unit_vector(direction), intensity, cos_inner: cos, cos_outer: cos, profile: None }
+  }
+
+  /// Fades the light out from `inner` degrees from the axis to nothing at `outer` degrees.
+  pub fn with_falloff(self, inner: f32, outer: f32) -> SpotLight {
+    SpotLight { cos_inner: inner.to_radians().cos(), cos_outer: outer.to_radians().cos(), ..self }
+  }
+
+  /// Scales the intensity by `profile` of the angle from the axis.
+  pub fn with_profile(self, profile: LightProfile) -> SpotLight {
+    SpotLight { profile: Some(profile), ..self }
+  }
+
+  // The fraction of the intensity shining `cos_theta` off the axis
+  fn falloff(&self, cos_theta: f32) -> f32 {
+    let cone = if cos_theta >= self.cos_inner {
+      1.0
+    } else if cos_theta <= self.cos_outer {
+      0.0
+    } else {
+      let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+      t * t * (3.0 - 2.0 * t)
+    };
+
+    match self.profile {
+      Some(ref profile) if cone > 0.0 => cone * profile.value(cos_theta.max(-1.0).min(1.0).acos().to_degrees()),
+      _ => cone
+    }
+  }
+}
+
+impl PunctualLight for SpotLight {
+  fn illuminate(&self, p: &Vec3) -> Option<Illumination> {
+    point_illumination(&self.position, p, self.intensity).and_then(|illumination| {
+      let falloff = self.falloff(-illumination.direction.dot(self.direction));
+      if falloff > 0.0 {
+        Some(Illumination { irradiance: falloff * illumination.irradiance, ..illumination })
+      } else {
+        None
+      }
+    })
+  }
+}
+
+/// Parallel light from far away, like sunlight, shining along `direction`
+/// with `irradiance` on surfaces facing it.
+pub struct DirectionalLight {
+  direction: Vec3,
+  pub irradiance: Vec3
+}
+
+impl DirectionalLight {
+  pub fn new(direction: Vec3, irradiance: Vec3) -> DirectionalLight {
+    DirectionalLight { direction: unit_vector(direction), irradiance }
+  }
+}
+
+impl PunctualLight for DirectionalLight {
+  fn illuminate(&self, _p: &Vec3) -> Option<Illumination> {
+    Some(Illumination { direction: -self.direction, distance: f32::MAX, irradiance: self.irradiance })
+  }
+}
+
+// More angles in either direction than any real IES file has
+const MAX_IES_ANGLES: usize = 100_000;
+
+/// How a light's intensity varies with the angle from its axis, as relative
+/// values at increasing angles in degrees, interpolated linearly in between.
+/// There is no light outside of the profile's angles.
+pub struct LightProfile {
+  angles: Vec<f32>,
+  values: Vec<f32>
+}
+
+impl LightProfile {
+  pub fn new(mut points: Vec<(f32, f32)>) -> LightProfile {
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("LightProfile angle is NaN"));
+    LightProfile {
+      angles: points.iter().map(|p| p.0).collect(),
+      values: points.iter().map(|p| p.1.max(0.0)).collect()
+    }
+  }
+
+  /// Reads the profile of a luminaire from an IES LM-63 photometric file,
+  /// averaging the candela values around the axis and scaling them so that
+  /// the brightest is 1. Angles are measured from straight down in IES
+  /// files, which becomes the light's axis.
+  pub fn load_ies(path: &Path) -> io::Result<LightProfile> {
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+    LightProfile::parse_ies(&source)
+  }
+
+  fn parse_ies(source: &str) -> io::Result<LightProfile> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    // Keywords come before the tilt, and the numbers after it
+    let mut lines = source.lines();
+    loop {
+      match lines.next().map(|l| l.trim()) {
+        Some("TILT=NONE") => break,
+        Some(line) if line.starts_with("TILT=") => return Err(invalid("only IES files with TILT=NONE are supported")),
+        Some(_) => continue,
+        None => return Err(invalid("IES file has no TILT line"))
+      }
+    }
+
+    let numbers = lines.flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ',')).filter(|t| !t.is_empty())
+      .map(|t| t.parse::<f32>().ok().filter(|v| v.is_finite()).ok_or_else(|| invalid(&format!("bad number {} in IES file", t))))
+      .collect::<io::Result<Vec<f32>>>()?;
+    if numbers.len() < 13 {
+      return Err(invalid("IES file ends early"));
+    }
+
+    let count = |v: f32| if (1.0..=MAX_IES_ANGLES as f32).contains(&v) && v.fract() == 0.0 {
+      Ok(v as usize)
+    } else {
+      Err(invalid(&format!("bad number of angles {} in IES file", v)))
+    };
+    let (vertical, horizontal) = (count(numbers[3])?, count(numbers[4])?);
+    let data = &numbers[13..];
+    let end = vertical.checked_mul(horizontal).and_then(|n| n.checked_add(vertical + horizontal));
+    let end = match end {
+      Some(end) if end <= data.len() => end,
+      _ => return Err(invalid("IES file ends early"))
+    };
+
+    let angles = &data[..vertical];
+    let candela = &data[vertical + horizontal..end];
+    let averages: Vec<f32> = (0..vertical)
+      .map(|v| (0..horizontal).map(|h| candela[h * vertical + v]).sum::<f32>() / horizontal as f32)
+      .collect();
+    let peak = averages.iter().cloned().fold(0.0, f32::max);
+    if peak <= 0.0 {
+      return Err(invalid("IES file has no light"));
+    }
+
+    Ok(LightProfile::new(angles.iter().zip(averages.iter()).map(|(&a, &c)| (a, c / peak)).collect()))
+  }
+
+  /// The relative intensity at `angle` degrees from the axis.
+  pub fn value(&self, angle: f32) -> f32 {
+    let (first, last) = match (self.angles.first(), self.angles.last()) {
+      (Some(&first), Some(&last)) => (first, last),
+      _ => return 0.0
+    };
+    if angle < first || angle > last {
+      return 0.0;
+    }
+
+    let i = self.angles.iter().position(|&a| a >= angle).unwrap();
+    if i == 0 || self.angles[i] == angle {
+      return self.values[i];
+    }
+
+    let t = (angle - self.angles[i - 1]) / (self.angles[i] - self.angles[i - 1]);
+    (1.0 - t) * self.values[i - 1] + t * self.values[i]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close(expected: f32, actual: f32) {
+    assert!((expected - actual).abs() < 1e-4, "expected {}, got {}", expected, actual);
+  }
+
+  #[test]
+  fn point_light_falls_off_with_distance_squared() {
+    let light = PointLight { position: Vec3::new(0.0, 2.0, 0.0), intensity: Vec3::new(8.0, 4.0, 2.0) };
+    let illumination = light.illuminate(&Vec3::new(0.0, 0.0, 0.0)).unwrap();
+
+    assert_close(1.0, illumination.direction.y());
+    assert_close(2.0, illumination.distance);
+    assert_close(2.0, illumination.irradiance.r());
+    assert_close(0.5, illumination.irradiance.b());
+  }
+
+  #[test]
+  fn spot_light_fades_between_its_cone_angles() {
+    let light = SpotLight::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 40.0)
+      .with_falloff(20.0, 40.0);
+    let at_angle = |degrees: f32| {
+      let p = Vec3::new(degrees.to_radians().tan(), 0.0, 0.0);
+      light.illuminate(&p).map_or(0.0, |i| i.irradiance.r() * (p - light.position).squared_length())
+    };
+
+    assert_close(1.0, at_angle(0.0));
+    assert_close(1.0, at_angle(19.0));
+    assert!(at_angle(30.0) > 0.1 && at_angle(30.0) < 0.9, "{} at 30 degrees", at_angle(30.0));
+    assert_close(0.0, at_angle(41.0));
+    assert!(light.illuminate(&Vec3::new(0.0, 2.0, 0.0)).is_none());
+  }
+
+  #[test]
+  fn profiles_interpolate_and_scale_spot_lights() {
+    let profile = LightProfile::new(vec![(30.0, 0.2), (0.0, 1.0), (10.0, 0.6)]);
+    assert_close(1.0, profile.value(0.0));
+    assert_close(0.8, profile.value(5.0));
+    assert_close(0.4, profile.value(20.0));
+    assert_close(0.0, profile.value(31.0));
+
+    let light = SpotLight::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(2.0, 2.0, 2.0), 90.0)
+      .with_profile(profile);
+    let p = Vec3::new(20f32.to_radians().tan(), 0.0, 0.0);
+    let illumination = light.illuminate(&p).unwrap();
+    assert_close(0.8, illumination.irradiance.g() * (p - light.position).squared_length());
+  }
+
+  #[test]
+  fn reads_ies_candela_averaged_around_the_axis() {
+    let ies = "IESNA:LM-63-2002\n[TEST] made up\nTILT=NONE\n\
+      1 1000 1 3 2 1 2 0 0 0\n1 1 100\n\
+      0 45 90\n0 180\n\
+      200 100 0\n\
+      400, 200, 0\n";
+    let profile = LightProfile::parse_ies(ies).unwrap();
+
+    assert_close(1.0, profile.value(0.0));
+    assert_close(0.5, profile.value(45.0));
+    assert_close(0.25, profile.value(67.5));
+    assert_close(0.0, profile.value(100.0));
+
+    assert!(LightProfile::parse_ies("IESNA:LM-63-2002\nTILT=INCLUDE\n").is_err());
+  }
+
+  #[test]
+  fn rejects_malformed_ies_numbers() {
+    let ies = |counts: &str, candela: &str| format!(
+      "IESNA:LM-63-2002\nTILT=NONE\n1 1000 1 {} 1 2 0 0 0\n1 1 100\n0 45 90\n0 180\n200 100 0\n{}\n", counts, candela);
+    assert!(LightProfile::parse_ies(&ies("3 2", "400 200 0")).is_ok());
+
+    for candela in ["nan 200 0", "400 inf 0", "400 200 -infinity"].iter() {
+      assert!(LightProfile::parse_ies(&ies("3 2", candela)).is_err(), "accepted {}", candela);
+    }
+    for counts in ["-3 2", "3 -2", "0 2", "2.5 2", "3 1e30", "1e30 1e30", "4294967296 4294967296"].iter() {
+      assert!(LightProfile::parse_ies(&ies(counts, "400 200 0")).is_err(), "accepted counts {}", counts);
+    }
+  }
+
+  #[test]
+  fn directional_light_is_the_same_everywhere() {
+    let light = DirectionalLight::new(Vec3::new(1.0, -1.0, 0.0), Vec3::new(3.0, 3.0, 3.0));
+    let a = light.illuminate(&Vec3::new(0.0, 0.0, 0.0)).unwrap();
+    let b = light.illuminate(&Vec3::new(100.0, -5.0, 7.0)).unwrap();
+
+    assert_close(a.direction.x(), b.direction.x());
+    assert_close(-(0.5f32).sqrt(), a.direction.x());
+    assert_close(3.0, b.irradiance.r());
+    assert_eq!(f32::MAX, b.distance);
+  }
+}
@@ -1,35 +1,22 @@
 extern crate clap;
-extern crate rand;
-extern crate png;
+extern crate rt_in_1_week;
 
 use std::f32;
-use std::fs::File;
-use std::io::BufWriter;
+use std::io;
 use std::path::Path;
-use std::sync::Arc;
+use std::process;
 use clap::{App, Arg};
 
-mod vec3;
-mod mat44;
-mod ray;
-mod geometry;
-mod bvh;
-mod camera;
-mod renderer;
-mod aabb;
-mod material;
-mod scene;
+use rt_in_1_week::*;
+use rt_in_1_week::demo::{random_scene, cornell_box};
+use rt_in_1_week::scene_file::load_scene_file;
+use rt_in_1_week::bvh::{BvhTree, BvhBuildMethod};
 
-use png::HasParameters;
-
-use vec3::{Vec3, unit_vector};
-use mat44::Mat44;
-use ray::Ray;
-use geometry::*;
-use material::*;
-use camera::Camera;
-use renderer::*;
-use scene::*;
+struct BvhOptions {
+  method: BvhBuildMethod,
+  // SAH settings to compare the random median BVH with, when asked for a report
+  report: Option<BvhBuildMethod>
+}
 
 fn main() {
   let matches = App::new("plrt")
@@ -41,6 +28,26 @@ fn main() {
       .value_name("FILE")
       .help("image destination file")
       .takes_value(true))
+    .arg(Arg::with_name("exr_type")
+      .long("exr-type")
+      .value_name("TYPE")
+      .help("precision of the floats in OpenEXR output")
+      .possible_values(&["half", "float"])
+      .takes_value(true))
+    .arg(Arg::with_name("tonemap")
+      .long("tonemap")
+      .value_name("OPERATOR")
+      .help("tone mapping of light too bright for PNG output")
+      .possible_values(&["clamp", "reinhard", "extended-reinhard", "aces", "agx"]))
+    .arg(Arg::with_name("white")
+      .long("white")
+      .value_name("LUMINANCE")
+      .help("luminance shown as white by the extended-reinhard operator"))
+    .arg(Arg::with_name("exposure")
+      .long("exposure")
+      .value_name("EV")
+      .allow_hyphen_values(true)
+      .help("exposure adjustment in stops for PNG output"))
     .arg(Arg::with_name("width")
       .short("w")
       .long("width")
@@ -70,38 +77,99 @@ fn main() {
       .value_name("PATH")
       .help("Wavefront OBJ model file path")
       .takes_value(true))
+    .arg(Arg::with_name("scene")
+      .long("scene")
+      .value_name("FILE")
+      .help("JSON scene description file path")
+      .takes_value(true))
+    .arg(Arg::with_name("bvh")
+      .long("bvh")
+      .value_name("METHOD")
+      .help("BVH construction method")
+      .possible_values(&["sah", "median"])
+      .takes_value(true))
+    .arg(Arg::with_name("bvh_bins")
+      .long("bvh-bins")
+      .value_name("BINS")
+      .help("number of bins evaluated per axis by the SAH BVH builder")
+      .takes_value(true))
+    .arg(Arg::with_name("bvh_leaf_size")
+      .long("bvh-leaf-size")
+      .value_name("SIZE")
+      .help("maximum number of hitables per BVH leaf")
+      .takes_value(true))
+    .arg(Arg::with_name("bvh_report")
+      .long("bvh-report")
+      .help("compare the BVHs built by the different methods before rendering"))
     .get_matches();
 
   let nx = matches.value_of("width").unwrap_or("320").parse::<usize>().unwrap();
   let ny = matches.value_of("height").unwrap_or("320").parse::<usize>().unwrap();
   let ns = matches.value_of("samples").unwrap_or("25").parse::<usize>().unwrap();;
   let max_ray_depth = matches.value_of("max_ray_depth").unwrap_or("10").parse::<i32>().unwrap();;
-
-  let pixels: Vec<u8>;
-  if let Some(obj_path) = matches.value_of("obj_model") {
-    pixels = render_obj(&Path::new(obj_path), nx, ny, ns, max_ray_depth);
+  let max_leaf_size = matches.value_of("bvh_leaf_size").unwrap_or("4").parse::<usize>().unwrap();
+  let sah = BvhBuildMethod::Sah {
+    bins: matches.value_of("bvh_bins").unwrap_or("16").parse::<usize>().unwrap(),
+    max_leaf_size
+  };
+  let bvh = BvhOptions {
+    method: if matches.value_of("bvh") == Some("median") { BvhBuildMethod::RandomMedian { max_leaf_size } } else { sah },
+    report: if matches.is_present("bvh_report") { Some(sah) } else { None }
+  };
+
+  let image: Framebuffer;
+  if let Some(scene_path) = matches.value_of("scene") {
+    image = render_scene_file(&Path::new(scene_path), nx, ny, ns, max_ray_depth, &bvh);
+  } else if let Some(obj_path) = matches.value_of("obj_model") {
+    image = render_obj(&Path::new(obj_path), nx, ny, ns, max_ray_depth, &bvh);
   } else {
-    pixels = render_random(nx, ny, ns, max_ray_depth);
-    // pixels = render_cornell(nx, ny, ns, max_ray_depth);
+    image = render_random(nx, ny, ns, max_ray_depth, &bvh);
+    // image = render_cornell(nx, ny, ns, max_ray_depth, &bvh);
   }
 
   let path = matches.value_of("output").unwrap_or("a.png");
-  let file = File::create(path).unwrap();
-  let ref mut w = BufWriter::new(file);
-
-  let mut encoder = png::Encoder::new(w, nx as u32, ny as u32);
-  encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
-  let mut writer = encoder.write_header().unwrap();
-
-  writer.write_image_data(&pixels).unwrap();
+  let exr_type = if matches.value_of("exr_type") == Some("float") { ExrPixelType::Float } else { ExrPixelType::Half };
+  let white = matches.value_of("white").unwrap_or("4").parse::<f32>().unwrap();
+  let tone_mapper = match matches.value_of("tonemap") {
+    Some("reinhard") => ToneMapper::Reinhard,
+    Some("extended-reinhard") => ToneMapper::ExtendedReinhard { white },
+    Some("aces") => ToneMapper::Aces,
+    Some("agx") => ToneMapper::Agx,
+    _ => ToneMapper::Clamp
+  };
+  let transform = DisplayTransform::new(tone_mapper)
+    .with_exposure(matches.value_of("exposure").unwrap_or("0").parse::<f32>().unwrap());
+  if let Err(e) = write_image(&image, Path::new(path), exr_type, &transform) {
+    eprintln!("could not write {}: {}", path, e);
+    process::exit(1);
+  }
   println!("Image written to {:?}", path);
 }
 
-fn render_obj(path: &Path, nx: usize, ny: usize, ns: usize, max_ray_depth: i32) -> Vec<u8> {
+// Writes the image in the format named by the path's extension, keeping the
+// full range of light in EXR, PFM and HDR files, and PNG shown through the
+// display transform otherwise
+fn write_image(image: &Framebuffer, path: &Path, exr_type: ExrPixelType, transform: &DisplayTransform) -> io::Result<()> {
+  let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+  match extension.as_ref().map(|e| &e[..]) {
+    Some("exr") => image.write_exr(path, exr_type),
+    Some("pfm") => image.write_pfm(path),
+    Some("hdr") => image.write_hdr(path),
+    _ => image.write_png(path, transform)
+  }
+}
+
+fn render_obj(path: &Path, nx: usize, ny: usize, ns: usize, max_ray_depth: i32, bvh: &BvhOptions) -> Framebuffer {
   println!("Loading OBJ model from {}", path.to_str().unwrap());
-  let mut world = obj_to_hitable(path);
-  let scene = Scene::new(&mut world, Box::new(SimpleSky {}), max_ray_depth);
-  let bbox = scene.bvh.bounding_box().unwrap();
+  let world = obj_to_hitable(path).unwrap_or_else(|e| {
+    eprintln!("could not load {}: {}", path.display(), e);
+    process::exit(1);
+  });
+  let scene = new_scene(world, Box::new(SimpleSky {}), max_ray_depth, bvh);
+  let bbox = scene.bvh.bounding_box().unwrap_or_else(|| {
+    eprintln!("{} has no triangles to render", path.display());
+    process::exit(1);
+  });
 
   let lookat = Vec3::new(
     (bbox.max[0] + bbox.min[0]) / 2.,
@@ -122,7 +190,24 @@ fn render_obj(path: &Path, nx: usize, ny: usize, ns: usize, max_ray_depth: i32)
   render(&scene, &camera, nx, ny, ns)
 }
 
-fn render_random(nx: usize, ny: usize, ns: usize, max_ray_depth: i32) -> Vec<u8> {
+fn render_scene_file(path: &Path, nx: usize, ny: usize, ns: usize, max_ray_depth: i32, bvh: &BvhOptions) -> Framebuffer {
+  println!("Loading scene from {}", path.to_str().unwrap());
+  let description = match load_scene_file(path) {
+    Ok(description) => description,
+    Err(e) => {
+      eprintln!("{}: {}", path.to_str().unwrap(), e);
+      process::exit(1);
+    }
+  };
+
+  let camera = description.camera((nx as f32) / (ny as f32));
+  let scene = new_scene(description.models, description.environment, max_ray_depth, bvh)
+    .with_punctual_lights(description.lights);
+
+  render(&scene, &camera, nx, ny, ns)
+}
+
+fn render_random(nx: usize, ny: usize, ns: usize, max_ray_depth: i32, bvh: &BvhOptions) -> Framebuffer {
   let lookfrom = Vec3::new(10.0, 1.8, 2.4);
   let lookat = Vec3::new(0.0, 0.0, 0.5);
   let dist_to_focus = (lookfrom-Vec3::new(4.0, 1.0, 0.0)).length();
@@ -136,13 +221,12 @@ fn render_random(nx: usize, ny: usize, ns: usize, max_ray_depth: i32) -> Vec<u8>
     0.1,
     dist_to_focus);
 
-  let mut world = random_scene();
-  let scene = Scene::new(&mut world, Box::new(SimpleSky {}), max_ray_depth);
+  let scene = new_scene(random_scene(), Box::new(SimpleSky {}), max_ray_depth, bvh);
 
   render(&scene, &camera, nx, ny, ns)
 }
 
-fn render_cornell(nx: usize, ny: usize, ns: usize, max_ray_depth: i32) -> Vec<u8> {
+fn render_cornell(nx: usize, ny: usize, ns: usize, max_ray_depth: i32, bvh: &BvhOptions) -> Framebuffer {
   let lookfrom = Vec3::new(278.0, 278.0, -800.0);
   let lookat = Vec3::new(278.0, 278.0, 0.0);
   let dist_to_focus = 10.0;
@@ -156,101 +240,40 @@ fn render_cornell(nx: usize, ny: usize, ns: usize, max_ray_depth: i32) -> Vec<u8
     0.0,
     dist_to_focus);
 
-  let mut world = cornell_box();
-  let scene = Scene::new(&mut world, Box::new(Void {}), max_ray_depth);
+  let scene = new_scene(cornell_box(), Box::new(Void {}), max_ray_depth, bvh);
 
   render(&scene, &camera, nx, ny, ns)
 }
 
-fn random_scene() -> Vec<Box<Hitable>> {
-  let scene_c = Vec3::new(4.0, 0.0, 2.0);
-
-  let checker = CheckerTexture { odd: Box::new(ConstantTexture::new(0.2, 0.3, 0.1)), even: Box::new(ConstantTexture::new(0.9, 0.9, 0.9)) };
-
-  let mut models: Vec<Box<Hitable>> = vec![
-    Box::new(Sphere { center: Vec3::new(0.0, -1000.0, 0.0), radius: 1000.0, material: Arc::new(Lambertian { albedo: Box::new(checker) }) }),
-    Box::new(Sphere { center: Vec3::new(0.0, 1.0, 0.0), radius: 1.0, material: Arc::new(Dielectric { ref_idx: 1.5 }) }),
-    Box::new(Sphere { center: Vec3::new(-4.0, 1.0, 0.0), radius: 1.0, material: Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.4, 0.2, 0.1)) }) }),
-    Box::new(Sphere { center: Vec3::new(4.0, 1.0, 0.0), radius: 1.0, material: Arc::new(Metal { albedo: Vec3::new(0.7, 0.6, 0.5), fuzz: 0.0 }) }),
-  ];
-
-  for a in -11..11 {
-    for b in -11..11 {
-      let center = Vec3::new((a as f32) + 0.9 * rand::random::<f32>(), 0.2, (b as f32) + 0.9 * rand::random::<f32>());
+fn new_scene(world: Vec<Box<Hitable>>, environment: Box<SceneEnvironment>, max_ray_depth: i32, bvh: &BvhOptions) -> Scene {
+  let world = match bvh.report {
+    Some(sah) => print_bvh_report(world, sah),
+    None => world
+  };
 
-      if (center - scene_c).length() > 0.9 {
-        let choose_mat = rand::random::<f32>();
-        let material: Arc<Material>;
-
-        if choose_mat < 0.8 {
-          material = Arc::new(Lambertian {
-            albedo: Box::new(ConstantTexture::new(
-                          rand::random::<f32>() * rand::random::<f32>(),
-                          rand::random::<f32>() * rand::random::<f32>(),
-                          rand::random::<f32>() * rand::random::<f32>()))
-          });
-        } else if choose_mat < 0.95 {
-          material = Arc::new(Metal {
-            albedo: Vec3::new(
-              0.5 * (1.0 + rand::random::<f32>()),
-              0.5 * (1.0 + rand::random::<f32>()),
-              0.5 * (1.0 + rand::random::<f32>())),
-            fuzz: 0.5 * rand::random::<f32>(),
-          })
-        } else {
-          material = Arc::new(Dielectric { ref_idx: 1.5 })
-        }
-
-        models.push(Box::new(Sphere { center, radius: 0.2, material }));
-      }
-
-    }
-  }
-
-  let world: Vec<Box<Hitable>> = models.into_iter().map(|s| s as Box<Hitable>).collect();
-  world
+  let scene = Scene::with_bvh_method(world, environment, max_ray_depth, bvh.method);
+  println!("{}", scene.bvh);
+  scene
 }
 
-fn cornell_box() -> Vec<Box<Hitable>> {
-  let red = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.65, 0.05, 0.05)) });
-  let white: Arc<Material> = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.73, 0.73, 0.73)) });
-  let green: Arc<Material> = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.12, 0.45, 0.15)) });
-  let light = Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(16.0, 16.0, 16.0)) });
-  let dielectric: Arc<Material> = Arc::new(Dielectric { ref_idx: 1.8 });
-  let subsurface: Vec<Box<Hitable>> = vec![
-    Box::new(Sphere {center: Vec3::new(0.0, 0.0, 0.0), radius: 60.0, material: Arc::clone(&dielectric) }),
-    Box::new(ConstantMedium::new(
-      Box::new(Sphere {center: Vec3::new(0.0, 0.0, 0.0), radius: 60.0, material: Arc::clone(&white) }),
-      0.2,
-      Box::new(ConstantTexture::new(0.2, 0.4, 0.9))))
-  ];
-
-  vec![
-    Box::new(FlipNormals { hitable: Box::new(YzRect { y0: 0.0, y1: 555.0, z0: 0.0, z1: 555.0, k: 555.0, material: green }) }),
-    Box::new(YzRect { y0: 0.0, y1: 555.0, z0: 0.0, z1: 555.0, k: 0.0, material: red }),
-    Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 800.0, material: light }),
-    Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 0.0, material: Arc::clone(&white) }),
-    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 0.0, x1: 213.0, z0: 0.0, z1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
-    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 343.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
-    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 0.0, z1: 227.0, k: 555.0, material: Arc::clone(&white) }) }),
-    Box::new(FlipNormals { hitable: Box::new(XzRect { x0: 0.0, x1: 555.0, z0: 332.0, z1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
-    Box::new(FlipNormals { hitable: Box::new(XyRect { x0: 0.0, x1: 555.0, y0: 0.0, y1: 555.0, k: 555.0, material: Arc::clone(&white) }) }),
-    Box::new(Transform::new(
-      Box::new(new_box(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 165.0, 165.0), Arc::clone(&white))),
-      Mat44::translate(Vec3::new(130.0, 0.0, 65.0)) * Mat44::rotate(-18.0, Vec3::new(0.0, 1.0, 0.0))
-    )),
-    Box::new(Transform::new(
-      Box::new(new_box(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 330.0, 165.0), Arc::clone(&white))),
-      Mat44::translate(Vec3::new(265.0, 0.0, 295.0)) * Mat44::rotate(15.0, Vec3::new(0.0, 1.0, 0.0))
-    )),
-    Box::new(Transform::new(
-      Box::new(subsurface),
-      Mat44::translate(Vec3::new(130.0, 225.0, 65.0)) * Mat44::rotate(-18.0, Vec3::new(0.0, 1.0, 0.0)) *
-      Mat44::translate(Vec3::new(82.5, 0.0, 82.5))
-    )),
-    Box::new(ConstantMedium::new(
-      Box::new(Sphere {center: Vec3::new(0.0, 0.0, 0.0), radius: 700.0, material: Arc::clone(&white) }),
-      0.0002,
-      Box::new(ConstantTexture::new(1., 1., 1.))))
-  ]
+// Builds both kinds of BVH over `world` and prints how they compare, giving
+// back the hitables afterwards
+fn print_bvh_report(world: Vec<Box<Hitable>>, sah: BvhBuildMethod) -> Vec<Box<Hitable>> {
+  // Compare with random median leaves of the same size
+  let max_leaf_size = match sah {
+    BvhBuildMethod::Sah { max_leaf_size, .. } => max_leaf_size,
+    BvhBuildMethod::RandomMedian { max_leaf_size } => max_leaf_size
+  };
+  let count = world.len();
+  let median_tree = BvhTree::with_method(world, BvhBuildMethod::RandomMedian { max_leaf_size });
+  let median_stats = median_tree.stats();
+  let sah_tree = BvhTree::with_method(median_tree.into_hitables(), sah);
+  let sah_stats = sah_tree.stats();
+
+  println!("BVH comparison for {} hitables:", count);
+  println!("  random median: {}", median_stats);
+  println!("  SAH:           {}", sah_stats);
+  println!("  SAH expected cost is {:.0}% of random median", 100.0 * sah_stats.expected_cost / median_stats.expected_cost);
+
+  sah_tree.into_hitables()
 }
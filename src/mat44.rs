@@ -2,6 +2,9 @@ use std::f32;
 use std::ops::*;
 use ::vec3::{Vec3, unit_vector};
 
+/// A 4x4 matrix for affine transforms. Note that `translate` and `rotate`
+/// build the inverse of the transforms their names suggest, mapping world
+/// space into object space as used by `Transform`.
 #[derive(Copy, Clone, Debug)]
 pub struct Mat44([[f32; 4]; 4]);
 
@@ -123,7 +126,7 @@ impl Mul for Mat44 {
 mod tests {
   use super::*;
 
-  #[test()]
+  #[test]
   fn matrix_mul() {
     let a = Mat44([
       [1.0, 2.0, 3.0, 0.0],
@@ -2,31 +2,164 @@ extern crate rand;
 
 use ::vec3::{Vec3, unit_vector};
 use ::ray::Ray;
+use ::onb::Onb;
+use ::microfacet::{Ggx, RoughDielectric, shading_frame};
+use std::f32;
+use std::sync::Arc;
 
+/// Where and how a ray hit a `Hitable`.
 #[derive(Clone, Copy)]
 pub struct HitRecord<'a> {
   pub t: f32,
   pub p: Vec3,
+  /// Normal used for shading, which may be interpolated over the surface.
   pub normal: Vec3,
+  /// Normal of the actual surface, on the same side as `normal`.
+  pub geometric_normal: Vec3,
   pub material: &'a Material,
   pub u: f32,
-  pub v: f32
+  pub v: f32,
+  /// How the hit point moves with `u` and `v`, or zero if the surface has no
+  /// texture coordinates.
+  pub dpdu: Vec3,
+  pub dpdv: Vec3,
+  /// Footprint in texture space of the pixel the ray came from, if known.
+  pub uv_derivatives: Option<UvDerivatives>
 }
 
-pub struct Scatter {
-  pub color: Vec3,
-  pub ray: Option<Ray>
+/// How texture coordinates change from one pixel to the next, in x and y.
+#[derive(Clone, Copy, Debug)]
+pub struct UvDerivatives {
+  pub dudx: f32,
+  pub dvdx: f32,
+  pub dudy: f32,
+  pub dvdy: f32
 }
 
+impl<'a> HitRecord<'a> {
+  /// Finds `uv_derivatives` from the differentials of `r`, the ray that hit,
+  /// by intersecting the neighbouring pixels' rays with the surface's tangent plane.
+  pub fn compute_uv_derivatives(&mut self, r: &Ray) {
+    self.uv_derivatives = None;
+    let d = match r.differentials {
+      Some(d) => d,
+      None => return
+    };
+
+    let n = self.geometric_normal;
+    let n_dot_d = n.dot(r.direction);
+    if n_dot_d.abs() < 1e-8 {
+      return;
+    }
+
+    // How the hit point moves as the ray changes, staying in the tangent plane
+    let dpdx = d.origin_dx + self.t * d.direction_dx - (n.dot(d.origin_dx + self.t * d.direction_dx) / n_dot_d) * r.direction;
+    let dpdy = d.origin_dy + self.t * d.direction_dy - (n.dot(d.origin_dy + self.t * d.direction_dy) / n_dot_d) * r.direction;
+
+    // Least squares solution of dpdx = dudx * dpdu + dvdx * dpdv, and likewise for y
+    let (a, b, c) = (self.dpdu.dot(self.dpdu), self.dpdu.dot(self.dpdv), self.dpdv.dot(self.dpdv));
+    let det = a * c - b * b;
+    if det.abs() < 1e-12 * (a * c).max(1e-30) {
+      return;
+    }
+
+    let solve = |dp: Vec3| {
+      let (pu, pv) = (self.dpdu.dot(dp), self.dpdv.dot(dp));
+      ((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+    };
+    let (dudx, dvdx) = solve(dpdx);
+    let (dudy, dvdy) = solve(dpdy);
+
+    self.uv_derivatives = Some(UvDerivatives { dudx, dvdx, dudy, dvdy });
+  }
+
+  /// A ray leaving the hit point in `direction`. It starts slightly off the
+  /// surface, on the side it leaves towards, so it doesn't hit the surface
+  /// again because of rounding errors.
+  pub fn spawn_ray(&self, direction: Vec3) -> Ray {
+    let n = unit_vector(self.geometric_normal);
+    let scale = 1e-5 * (1.0 + self.p.x().abs().max(self.p.y().abs()).max(self.p.z().abs()));
+    let offset = if direction.dot(n) > 0.0 { scale * n } else { -scale * n };
+
+    Ray::new(self.p + offset, direction)
+  }
+}
+
+/// A direction picked by `Material::sample`.
+pub struct BsdfSample {
+  /// Direction the light arrives from, pointing away from the surface.
+  pub direction: Vec3,
+  /// Probability density of picking `direction`. Meaningless for delta lobes.
+  pub pdf: f32,
+  /// The BSDF times the cosine term, divided by `pdf`: the factor to multiply
+  /// the light arriving along `direction` by.
+  pub throughput: Vec3,
+  /// Whether `direction` was picked from a delta lobe, like a perfect mirror,
+  /// which `eval` and `pdf` will never return anything for.
+  pub is_delta: bool
+}
+
+/// Decides how light scatters off, or is emitted from, a surface.
+///
+/// Directions follow the convention that `wo` points from the surface back
+/// along the incoming ray, towards the viewer, and `wi` points from the
+/// surface towards where the light comes from. Neither needs to be normalized.
 pub trait Material : Sync + Send {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter>;
-  fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+  /// Picks a direction to continue a path in, given the ray that hit the surface.
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample>;
+
+  /// The BSDF for light arriving from `wi` leaving towards `wo`, times the cosine
+  /// of `wi` to the normal. Always zero for delta lobes.
+  fn eval(&self, _rec: &HitRecord, _wi: &Vec3, _wo: &Vec3) -> Vec3 {
     Vec3::new(0.0, 0.0, 0.0)
   }
+
+  /// The probability density of `sample` picking `wi` when called with a ray along `-wo`.
+  fn pdf(&self, _rec: &HitRecord, _wi: &Vec3, _wo: &Vec3) -> f32 {
+    0.0
+  }
+
+  /// Whether the material only has delta lobes, making light sampling pointless.
+  fn is_delta(&self) -> bool {
+    false
+  }
+
+  /// Light given off at a hit, whichever way it leaves.
+  fn emitted(&self, _rec: &HitRecord) -> Vec3 {
+    Vec3::new(0.0, 0.0, 0.0)
+  }
+
+  fn is_emissive(&self) -> bool {
+    false
+  }
+
+  /// How opaque the surface is, from 0 where it is cut away to 1 where it is
+  /// solid. Triangles are not hit where the opacity is below one half.
+  fn opacity(&self, _u: f32, _v: f32, _p: &Vec3) -> f32 {
+    1.0
+  }
 }
 
+/// A color varying over a surface, either by surface coordinates `u` and `v` or by position `p`.
 pub trait Texture : Sync + Send {
   fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
+
+  /// The color at a hit, averaged over the hit's `uv_derivatives` footprint
+  /// by textures that would otherwise alias.
+  fn filtered_value(&self, rec: &HitRecord) -> Vec3 {
+    self.value(rec.u, rec.v, &rec.p)
+  }
+}
+
+/// Shares a texture, such as a large image, between materials.
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+  fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+    (**self).value(u, v, p)
+  }
+
+  fn filtered_value(&self, rec: &HitRecord) -> Vec3 {
+    (**self).filtered_value(rec)
+  }
 }
 
 pub struct ConstantTexture {
@@ -50,19 +183,103 @@ pub struct CheckerTexture {
   pub even: Box<Texture>
 }
 
-impl Texture for CheckerTexture {
-  fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
-    let sines = 
+impl CheckerTexture {
+  fn is_odd(&self, p: &Vec3) -> bool {
+    let sines =
       (p.x()*10.0).sin() *
       (p.y()*10.0).sin() *
       (p.z()*10.0).sin();
 
-    if sines < 0.0 {
+    sines < 0.0
+  }
+}
+
+impl Texture for CheckerTexture {
+  fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+    if self.is_odd(p) {
+      self.odd.value(u, v, p)
+    } else {
+      self.even.value(u, v, p)
+    }
+  }
+
+  fn filtered_value(&self, rec: &HitRecord) -> Vec3 {
+    if self.is_odd(&rec.p) {
+      self.odd.filtered_value(rec)
+    } else {
+      self.even.filtered_value(rec)
+    }
+  }
+}
+
+/// Squares in texture space, `columns` across u and `rows` across v between 0 and 1,
+/// unlike `CheckerTexture` which is laid out by position.
+pub struct UvCheckerTexture {
+  pub odd: Box<Texture>,
+  pub even: Box<Texture>,
+  pub columns: f32,
+  pub rows: f32
+}
+
+impl UvCheckerTexture {
+  pub fn new(odd: Box<Texture>, even: Box<Texture>, columns: f32, rows: f32) -> UvCheckerTexture {
+    UvCheckerTexture { odd, even, columns, rows }
+  }
+
+  fn is_odd(&self, u: f32, v: f32) -> bool {
+    ((u * self.columns).floor() + (v * self.rows).floor()) as i64 % 2 != 0
+  }
+}
+
+// The integral from 0 to x of a function that is 1 where floor(x) is odd and 0 elsewhere
+fn odd_integral(x: f32) -> f32 {
+  (x / 2.0).floor() + 2.0 * (x / 2.0 - (x / 2.0).floor() - 0.5).max(0.0)
+}
+
+// The fraction of [x - width, x + width] where floor(x) is odd
+fn odd_fraction(x: f32, width: f32) -> f32 {
+  if width < 1e-6 {
+    return if x.floor() as i64 % 2 != 0 { 1.0 } else { 0.0 }
+  }
+
+  (odd_integral(x + width) - odd_integral(x - width)) / (2.0 * width)
+}
+
+impl Texture for UvCheckerTexture {
+  fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+    if self.is_odd(u, v) {
       self.odd.value(u, v, p)
     } else {
       self.even.value(u, v, p)
     }
   }
+
+  /// Blends the two textures by how much of the footprint each covers, so
+  /// that squares fade to gray rather than alias when they get small.
+  fn filtered_value(&self, rec: &HitRecord) -> Vec3 {
+    let d = match rec.uv_derivatives {
+      Some(d) => d,
+      None => return self.value(rec.u, rec.v, &rec.p)
+    };
+
+    let (s, t) = (rec.u * self.columns, rec.v * self.rows);
+    let ds = self.columns * d.dudx.abs().max(d.dudy.abs());
+    let dt = self.rows * d.dvdx.abs().max(d.dvdy.abs());
+    if ds < 1e-6 && dt < 1e-6 {
+      return self.value(rec.u, rec.v, &rec.p);
+    }
+
+    // A square is odd where exactly one of its column and row is
+    let (odd_s, odd_t) = (odd_fraction(s, ds), odd_fraction(t, dt));
+    let odd = odd_s + odd_t - 2.0 * odd_s * odd_t;
+    if odd <= 0.0 {
+      self.even.filtered_value(rec)
+    } else if odd >= 1.0 {
+      self.odd.filtered_value(rec)
+    } else {
+      odd * self.odd.filtered_value(rec) + (1.0 - odd) * self.even.filtered_value(rec)
+    }
+  }
 }
 
 pub struct Lambertian {
@@ -70,28 +287,49 @@ pub struct Lambertian {
 }
 
 impl Material for Lambertian {
-  fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
-      let target = rec.p + rec.normal + random_in_unit_sphere();
-      return Some(Scatter {
-        color: self.albedo.value(0.0, 0.0, &rec.p),
-        ray: Some(Ray::new(rec.p, target - rec.p))
-      });
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    let uvw = Onb::build_from_w(rec.normal);
+    let direction = uvw.local(random_cosine_direction());
+
+    Some(BsdfSample {
+      direction,
+      pdf: self.pdf(rec, &direction, &-r_in.direction),
+      // The cosine and 1 / pi of the BSDF cancel against the pdf
+      throughput: self.albedo.filtered_value(rec),
+      is_delta: false
+    })
+  }
+
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    self.pdf(rec, wi, wo) * self.albedo.filtered_value(rec)
+  }
+
+  fn pdf(&self, rec: &HitRecord, wi: &Vec3, _wo: &Vec3) -> f32 {
+    let cosine = unit_vector(rec.normal).dot(unit_vector(*wi));
+    if cosine < 0.0 { 0.0 } else { cosine / f32::consts::PI }
   }
 }
 
 pub struct Metal {
-  pub albedo: Vec3,
+  pub albedo: Box<Texture>,
   pub fuzz: f32
 }
 
 impl Material for Metal {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
     let reflected = reflect(&r_in.direction, &rec.normal);
-    let scattered = Ray::new(rec.p, reflected + self.fuzz * random_in_unit_sphere());
-    return Some(Scatter {
-      color: self.albedo,
-      ray: if scattered.direction.dot(rec.normal) > 0.0 { Some(scattered) } else { None }
-    })
+    let direction = reflected + self.fuzz * random_in_unit_sphere();
+
+    if direction.dot(rec.normal) <= 0.0 {
+      return None;
+    }
+
+    // The fuzzy reflection has no well defined density, so it is treated as a delta lobe
+    Some(BsdfSample { direction, pdf: 1.0, throughput: self.albedo.filtered_value(rec), is_delta: true })
+  }
+
+  fn is_delta(&self) -> bool {
+    true
   }
 }
 
@@ -100,7 +338,7 @@ pub struct Dielectric {
 }
 
 impl Material for Dielectric {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
     let outward_normal: Vec3;
     let ni_over_nt: f32;
     let cosine: f32;
@@ -119,16 +357,23 @@ impl Material for Dielectric {
 
     match refract(&r_in.direction, &outward_normal, ni_over_nt) {
       Some(refraction) => { 
-        // eprintln!("refraction");
         if rand::random::<f32>() > schlick(cosine, self.ref_idx) {
-          return Some(Scatter { color: albedo, ray: Some(Ray::new(rec.p, refraction)) });
+          return Some(BsdfSample { direction: refraction, pdf: 1.0, throughput: albedo, is_delta: true });
         }
       },
       None => { }
     }
     
-    // eprintln!("reflection");
-    Some(Scatter { color: albedo, ray: Some(Ray::new(rec.p, reflect(&unit_vector(r_in.direction), &rec.normal))) })
+    Some(BsdfSample {
+      direction: reflect(&unit_vector(r_in.direction), &rec.normal),
+      pdf: 1.0,
+      throughput: albedo,
+      is_delta: true
+    })
+  }
+
+  fn is_delta(&self) -> bool {
+    true
   }
 }
 
@@ -137,12 +382,361 @@ pub struct DiffuseLight {
 }
 
 impl Material for DiffuseLight {
-  fn scatter(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<Scatter> {
+  fn sample(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<BsdfSample> {
     None
   }
 
-  fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
-    self.emit.value(u, v, p)
+  fn emitted(&self, rec: &HitRecord) -> Vec3 {
+    self.emit.filtered_value(rec)
+  }
+
+  fn is_emissive(&self) -> bool {
+    true
+  }
+}
+
+/// Cuts away parts of a surface, where `alpha` is dark, from another material.
+pub struct AlphaCutout {
+  pub material: Arc<Material>,
+  pub alpha: Box<Texture>
+}
+
+impl Material for AlphaCutout {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    self.material.sample(r_in, rec)
+  }
+
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    self.material.eval(rec, wi, wo)
+  }
+
+  fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f32 {
+    self.material.pdf(rec, wi, wo)
+  }
+
+  fn is_delta(&self) -> bool {
+    self.material.is_delta()
+  }
+
+  fn emitted(&self, rec: &HitRecord) -> Vec3 {
+    self.material.emitted(rec)
+  }
+
+  fn is_emissive(&self) -> bool {
+    self.material.is_emissive()
+  }
+
+  fn opacity(&self, u: f32, v: f32, p: &Vec3) -> f32 {
+    self.alpha.value(u, v, p).x() * self.material.opacity(u, v, p)
+  }
+}
+
+/// How a `NormalMap` reads its texture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NormalMapKind {
+  /// Colors are normals in the frame of the surface's `dpdu`, `dpdv` and
+  /// normal, each component mapped from -1..1 to 0..1, with green along v.
+  TangentSpace,
+  /// The red channel is a height above the surface, scaled by `scale`.
+  Bump { scale: f32 }
+}
+
+/// Adds detail to another material by perturbing the shading normal, from
+/// either a tangent space normal map or a bump map.
+pub struct NormalMap {
+  pub material: Arc<Material>,
+  pub map: Box<Texture>,
+  pub kind: NormalMapKind
+}
+
+impl NormalMap {
+  pub fn tangent_space(material: Arc<Material>, map: Box<Texture>) -> NormalMap {
+    NormalMap { material, map, kind: NormalMapKind::TangentSpace }
+  }
+
+  pub fn bump(material: Arc<Material>, height: Box<Texture>, scale: f32) -> NormalMap {
+    NormalMap { material, map: height, kind: NormalMapKind::Bump { scale } }
+  }
+
+  // The hit with its shading normal perturbed, or as it was if the surface
+  // has no texture coordinates to orient the map by
+  fn perturb<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+    let n = unit_vector(rec.normal);
+    let tangent = rec.dpdu - rec.dpdu.dot(n) * n;
+    if tangent.squared_length() <= 1e-12 * rec.dpdu.squared_length() || rec.dpdv.squared_length() == 0.0 {
+      return *rec;
+    }
+
+    let normal = match self.kind {
+      NormalMapKind::TangentSpace => {
+        let t = unit_vector(tangent);
+        let b = if n.cross(t).dot(rec.dpdv) < 0.0 { t.cross(n) } else { n.cross(t) };
+        let c = 2.0 * self.map.filtered_value(rec) - Vec3::new(1.0, 1.0, 1.0);
+        c.x() * t + c.y() * b + c.z().max(1e-3) * n
+      },
+      NormalMapKind::Bump { scale } => {
+        // Differences in height over about the pixel's footprint, or a small step without one
+        let (du, dv) = match rec.uv_derivatives {
+          Some(d) => (0.5 * (d.dudx.abs() + d.dudy.abs()), 0.5 * (d.dvdx.abs() + d.dvdy.abs())),
+          None => (0.0, 0.0)
+        };
+        let (du, dv) = (if du > 0.0 { du } else { 5e-4 }, if dv > 0.0 { dv } else { 5e-4 });
+        // Solid textures are looked up where the step in u or v takes the point
+        let height = |u: f32, v: f32, p: Vec3| scale * self.map.value(u, v, &p).x();
+        let h = height(rec.u, rec.v, rec.p);
+        let dhdu = (height(rec.u + du, rec.v, rec.p + du * rec.dpdu) - h) / du;
+        let dhdv = (height(rec.u, rec.v + dv, rec.p + dv * rec.dpdv) - h) / dv;
+
+        // Normal of the surface moved along its normal by the height
+        let bumped = (rec.dpdu + dhdu * n).cross(rec.dpdv + dhdv * n);
+        if bumped.dot(n) < 0.0 { -bumped } else { bumped }
+      }
+    };
+
+    let mut perturbed = *rec;
+    perturbed.normal = unit_vector(normal);
+    perturbed
+  }
+}
+
+impl Material for NormalMap {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    self.material.sample(r_in, &self.perturb(rec))
+  }
+
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    self.material.eval(&self.perturb(rec), wi, wo)
+  }
+
+  fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f32 {
+    self.material.pdf(&self.perturb(rec), wi, wo)
+  }
+
+  fn is_delta(&self) -> bool {
+    self.material.is_delta()
+  }
+
+  fn emitted(&self, rec: &HitRecord) -> Vec3 {
+    self.material.emitted(rec)
+  }
+
+  fn is_emissive(&self) -> bool {
+    self.material.is_emissive()
+  }
+
+  fn opacity(&self, u: f32, v: f32, p: &Vec3) -> f32 {
+    self.material.opacity(u, v, p)
+  }
+}
+
+/// An uber material with the lobes and parameters of Disney's principled
+/// BSDF: a diffuse base with sheen, GGX specular reflection that turns into
+/// a metal with `metallic`, rough glass with `transmission`, and a clear coat
+/// on top. Every parameter is a texture, scalar ones read from its red channel.
+pub struct Principled {
+  pub base_color: Box<Texture>,
+  pub metallic: Box<Texture>,
+  /// Roughness of the specular and transmission lobes, from 0.05 up to 1.
+  pub roughness: Box<Texture>,
+  /// Strength of dielectric reflection, 0.5 corresponding to an index of refraction of 1.5.
+  pub specular: Box<Texture>,
+  pub clearcoat: Box<Texture>,
+  pub clearcoat_roughness: Box<Texture>,
+  /// Soft reflection at grazing angles, like on cloth.
+  pub sheen: Box<Texture>,
+  pub transmission: Box<Texture>,
+  /// Index of refraction of the transmission lobe.
+  pub ior: f32
+}
+
+// Smoother than this, GGX would need to be treated as a mirror; clamping keeps
+// all lobes non-delta
+const MIN_ROUGHNESS: f32 = 0.05;
+
+// Principled parameters looked up at a hit
+struct PrincipledLobes {
+  base_color: Vec3,
+  metallic: f32,
+  roughness: f32,
+  specular: f32,
+  clearcoat: f32,
+  clearcoat_roughness: f32,
+  sheen: f32,
+  transmission: f32
+}
+
+impl PrincipledLobes {
+  fn diffuse_weight(&self) -> f32 {
+    (1.0 - self.metallic) * (1.0 - self.transmission)
+  }
+
+  fn transmission_weight(&self) -> f32 {
+    (1.0 - self.metallic) * self.transmission
+  }
+
+  // Glass has reflection of its own
+  fn specular_weight(&self) -> f32 {
+    1.0 - self.transmission_weight()
+  }
+
+  fn clearcoat_weight(&self) -> f32 {
+    0.25 * self.clearcoat
+  }
+
+  // Probabilities of sampling the diffuse, specular, clear coat and transmission lobes
+  fn probabilities(&self) -> [f32; 4] {
+    let weights = [self.diffuse_weight(), self.specular_weight(), self.clearcoat_weight(), self.transmission_weight()];
+    let total: f32 = weights.iter().sum();
+
+    [weights[0] / total, weights[1] / total, weights[2] / total, weights[3] / total]
+  }
+}
+
+fn schlick_color(f0: Vec3, cosine: f32) -> Vec3 {
+  let w = (1.0 - cosine.max(0.0).min(1.0)).powi(5);
+  f0 + w * (Vec3::new(1.0, 1.0, 1.0) - f0)
+}
+
+impl Principled {
+  /// A plastic-like material of the given color, with no metal, sheen,
+  /// clear coat or transmission.
+  pub fn new(base_color: Box<Texture>) -> Principled {
+    let constant = |v: f32| -> Box<Texture> { Box::new(ConstantTexture::new(v, v, v)) };
+    Principled {
+      base_color,
+      metallic: constant(0.0),
+      roughness: constant(0.5),
+      specular: constant(0.5),
+      clearcoat: constant(0.0),
+      clearcoat_roughness: constant(0.1),
+      sheen: constant(0.0),
+      transmission: constant(0.0),
+      ior: 1.5
+    }
+  }
+
+  pub fn with_metallic(self, metallic: Box<Texture>) -> Principled {
+    Principled { metallic, ..self }
+  }
+
+  pub fn with_roughness(self, roughness: Box<Texture>) -> Principled {
+    Principled { roughness, ..self }
+  }
+
+  pub fn with_specular(self, specular: Box<Texture>) -> Principled {
+    Principled { specular, ..self }
+  }
+
+  pub fn with_clearcoat(self, clearcoat: Box<Texture>, clearcoat_roughness: Box<Texture>) -> Principled {
+    Principled { clearcoat, clearcoat_roughness, ..self }
+  }
+
+  pub fn with_sheen(self, sheen: Box<Texture>) -> Principled {
+    Principled { sheen, ..self }
+  }
+
+  pub fn with_transmission(self, transmission: Box<Texture>, ior: f32) -> Principled {
+    Principled { transmission, ior, ..self }
+  }
+
+  fn lobes(&self, rec: &HitRecord) -> PrincipledLobes {
+    let scalar = |texture: &Box<Texture>| texture.filtered_value(rec).x().max(0.0).min(1.0);
+    PrincipledLobes {
+      base_color: self.base_color.filtered_value(rec),
+      metallic: scalar(&self.metallic),
+      roughness: scalar(&self.roughness).max(MIN_ROUGHNESS),
+      specular: scalar(&self.specular),
+      clearcoat: scalar(&self.clearcoat),
+      clearcoat_roughness: scalar(&self.clearcoat_roughness).max(MIN_ROUGHNESS),
+      sheen: scalar(&self.sheen),
+      transmission: scalar(&self.transmission)
+    }
+  }
+
+  fn glass(&self, lobes: &PrincipledLobes) -> RoughDielectric {
+    RoughDielectric::new(self.ior, lobes.roughness)
+  }
+
+  // The BSDF times cosine, and pdf, summed over all lobes
+  fn eval_lobes(&self, lobes: &PrincipledLobes, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> (Vec3, f32) {
+    let mut f = Vec3::new(0.0, 0.0, 0.0);
+    let mut pdf = 0.0;
+    let probabilities = lobes.probabilities();
+
+    if lobes.transmission_weight() > 0.0 {
+      let glass = self.glass(lobes);
+      let tint = if wi.dot(rec.normal) * wo.dot(rec.normal) < 0.0 { lobes.base_color } else { Vec3::new(1.0, 1.0, 1.0) };
+      f += lobes.transmission_weight() * tint * glass.eval(rec, wi, wo);
+      pdf += probabilities[3] * glass.pdf(rec, wi, wo);
+    }
+
+    let frame = shading_frame(rec, wo);
+    let (wi, wo) = (unit_vector(frame.to_local(*wi)), unit_vector(frame.to_local(*wo)));
+    if wi.z() <= 0.0 || wo.z() <= 0.0 {
+      return (f, pdf);
+    }
+
+    let h = unit_vector(wi + wo);
+    let cos_d = wi.dot(h);
+
+    // Burley's diffuse, darkening or brightening towards grazing angles by roughness
+    let fd90 = 0.5 + 2.0 * lobes.roughness * cos_d * cos_d;
+    let retro = (1.0 + (fd90 - 1.0) * (1.0 - wi.z()).powi(5)) * (1.0 + (fd90 - 1.0) * (1.0 - wo.z()).powi(5));
+    let sheen = lobes.sheen * (1.0 - cos_d).powi(5);
+    f += (lobes.diffuse_weight() * wi.z()) * ((retro / f32::consts::PI) * lobes.base_color + Vec3::new(sheen, sheen, sheen));
+    pdf += probabilities[0] * wi.z() / f32::consts::PI;
+
+    let dielectric = 0.08 * lobes.specular;
+    let f0 = (1.0 - lobes.metallic) * Vec3::new(dielectric, dielectric, dielectric) + lobes.metallic * lobes.base_color;
+    let specular = Ggx::new(lobes.roughness, 0.0);
+    f += (lobes.specular_weight() * specular.d(&h) * specular.g(&wo, &wi) / (4.0 * wo.z())) * schlick_color(f0, cos_d);
+    pdf += probabilities[1] * specular.visible_normal_pdf(&wo, &h) / (4.0 * wo.dot(h));
+
+    if lobes.clearcoat > 0.0 {
+      let coat = Ggx::new(lobes.clearcoat_roughness, 0.0);
+      let fresnel = schlick_color(Vec3::new(0.04, 0.04, 0.04), cos_d);
+      f += (lobes.clearcoat_weight() * coat.d(&h) * coat.g(&wo, &wi) / (4.0 * wo.z())) * fresnel;
+      pdf += probabilities[2] * coat.visible_normal_pdf(&wo, &h) / (4.0 * wo.dot(h));
+    }
+
+    (f, pdf)
+  }
+}
+
+impl Material for Principled {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    let lobes = self.lobes(rec);
+    let probabilities = lobes.probabilities();
+    let wo = -r_in.direction;
+    let frame = shading_frame(rec, &wo);
+    let local_wo = unit_vector(frame.to_local(wo));
+
+    // Pick one lobe to sample, then weigh the direction by all of them
+    let choice = rand::random::<f32>();
+    let direction = if choice < probabilities[0] {
+      frame.local(random_cosine_direction())
+    } else if choice < probabilities[0] + probabilities[1] + probabilities[2] {
+      let roughness = if choice < probabilities[0] + probabilities[1] { lobes.roughness } else { lobes.clearcoat_roughness };
+      let m = Ggx::new(roughness, 0.0).sample_visible_normal(&local_wo, rand::random::<f32>(), rand::random::<f32>());
+      frame.local(2.0 * local_wo.dot(m) * m - local_wo)
+    } else {
+      self.glass(&lobes).sample(r_in, rec)?.direction
+    };
+
+    let (f, pdf) = self.eval_lobes(&lobes, rec, &direction, &wo);
+    if pdf <= 0.0 {
+      return None;
+    }
+
+    Some(BsdfSample { direction, pdf, throughput: f / pdf, is_delta: false })
+  }
+
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    self.eval_lobes(&self.lobes(rec), rec, wi, wo).0
+  }
+
+  fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f32 {
+    self.eval_lobes(&self.lobes(rec), rec, wi, wo).1
   }
 }
 
@@ -151,12 +745,23 @@ pub struct Isotropic {
 }
 
 impl Material for Isotropic {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
-    Some(Scatter {
-      color: self.albedo.value(rec.u, rec.v, &rec.p),
-      ray: Some(Ray::new(rec.p, random_in_unit_sphere()))
+  fn sample(&self, _r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    Some(BsdfSample {
+      direction: random_in_unit_sphere(),
+      pdf: 1.0 / (4.0 * f32::consts::PI),
+      throughput: self.albedo.filtered_value(rec),
+      is_delta: false
     })
   }
+
+  // The phase function has no cosine term, unlike a surface BSDF
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    self.pdf(rec, wi, wo) * self.albedo.filtered_value(rec)
+  }
+
+  fn pdf(&self, _rec: &HitRecord, _wi: &Vec3, _wo: &Vec3) -> f32 {
+    1.0 / (4.0 * f32::consts::PI)
+  }
 }
 
 fn random_in_unit_sphere() -> Vec3 {
@@ -168,6 +773,15 @@ fn random_in_unit_sphere() -> Vec3 {
   }
 }
 
+fn random_cosine_direction() -> Vec3 {
+  let r1 = rand::random::<f32>();
+  let r2 = rand::random::<f32>();
+  let phi = 2.0 * f32::consts::PI * r1;
+  let z = (1.0 - r2).sqrt();
+
+  Vec3::new(phi.cos() * r2.sqrt(), phi.sin() * r2.sqrt(), z)
+}
+
 fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
   *v - 2.0 * v.dot(*n) * *n
 }
@@ -188,3 +802,220 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
   let r0sq = r0 * r0;
   r0sq + (1.0 - r0sq) * (1.0 - cosine).powf(5.0)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hit_record(material: &Material) -> HitRecord<'_> {
+    HitRecord {
+      t: 1.0,
+      p: Vec3::new(0.0, 0.0, 0.0),
+      normal: Vec3::new(0.0, 1.0, 0.0),
+      geometric_normal: Vec3::new(0.0, 1.0, 0.0),
+      material,
+      u: 0.0,
+      v: 0.0,
+      dpdu: Vec3::new(1.0, 0.0, 0.0),
+      dpdv: Vec3::new(0.0, 0.0, 1.0),
+      uv_derivatives: None
+    }
+  }
+
+  #[test]
+  fn uv_derivatives_follow_camera_ray_footprint() {
+    let material = Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) };
+    // The floor is hit 2 units away, straight below, with u along x and v along z
+    let mut rec = hit_record(&material);
+    rec.t = 2.0;
+    let r = Ray {
+      origin: Vec3::new(0.0, 2.0, 0.0),
+      direction: Vec3::new(0.0, -1.0, 0.0),
+      differentials: Some(::ray::RayDifferentials {
+        origin_dx: Vec3::new(0.0, 0.0, 0.0),
+        direction_dx: Vec3::new(0.01, 0.0, 0.0),
+        origin_dy: Vec3::new(0.0, 0.0, 0.0),
+        direction_dy: Vec3::new(0.0, 0.0, 0.01)
+      })
+    };
+    rec.dpdu = Vec3::new(2.0, 0.0, 0.0);
+    rec.compute_uv_derivatives(&r);
+
+    let d = rec.uv_derivatives.unwrap();
+    assert!((d.dudx - 0.01).abs() < 1e-6 && d.dvdx.abs() < 1e-6);
+    assert!(d.dudy.abs() < 1e-6 && (d.dvdy - 0.02).abs() < 1e-6);
+  }
+
+  #[test]
+  fn uv_checker_follows_hit_uvs_and_fades_when_small() {
+    let checker = || Box::new(UvCheckerTexture::new(
+      Box::new(ConstantTexture::new(1.0, 1.0, 1.0)),
+      Box::new(ConstantTexture::new(0.0, 0.0, 0.0)),
+      4.0, 2.0));
+    let material = Lambertian { albedo: checker() };
+    let mut rec = hit_record(&material);
+    let wi = Vec3::new(0.0, 1.0, 0.0);
+
+    // Lambertian shades by the hit's uv, not its position
+    rec.u = 0.3;
+    rec.v = 0.2;
+    assert!(material.eval(&rec, &wi, &wi).length() > 0.1);
+    rec.u = 0.1;
+    assert!(material.eval(&rec, &wi, &wi).length() < 1e-6);
+
+    // A footprint of many squares averages them out
+    let texture = checker();
+    rec.uv_derivatives = Some(UvDerivatives { dudx: 2.0, dvdx: 0.0, dudy: 0.0, dvdy: 2.0 });
+    assert!((texture.filtered_value(&rec) - Vec3::new(0.5, 0.5, 0.5)).length() < 0.05);
+    rec.uv_derivatives = Some(UvDerivatives { dudx: 0.01, dvdx: 0.0, dudy: 0.0, dvdy: 0.01 });
+    assert!((texture.filtered_value(&rec) - texture.value(rec.u, rec.v, &rec.p)).length() < 1e-6);
+  }
+
+  // Height rising along u
+  struct Ramp;
+
+  impl Texture for Ramp {
+    fn value(&self, u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+      Vec3::new(u, u, u)
+    }
+  }
+
+  // Whether a Lambertian under the map is lit the most from `normal`
+  fn assert_shading_normal(map: &NormalMap, normal: Vec3) {
+    let rec = hit_record(map);
+    let wo = Vec3::new(0.0, 1.0, 0.0);
+    let albedo = 0.5 / f32::consts::PI;
+
+    assert!((map.eval(&rec, &normal, &wo).x() - albedo).abs() < 1e-4, "not lit the most from {:?}", normal);
+  }
+
+  #[test]
+  fn normal_maps_tilt_the_shading_normal() {
+    let lambertian = || -> Arc<Material> { Arc::new(Lambertian { albedo: constant(0.5) }) };
+
+    // Flat maps leave the normal alone
+    assert_shading_normal(&NormalMap::tangent_space(lambertian(), Box::new(ConstantTexture::new(0.5, 0.5, 1.0))), Vec3::new(0.0, 1.0, 0.0));
+    assert_shading_normal(&NormalMap::bump(lambertian(), constant(0.3), 1.0), Vec3::new(0.0, 1.0, 0.0));
+
+    // Tangent space x and y follow dpdu and dpdv, here along x and z
+    let tilted = NormalMap::tangent_space(lambertian(), Box::new(ConstantTexture::new(0.8, 0.5, 0.9)));
+    assert_shading_normal(&tilted, Vec3::new(0.6, 0.8, 0.0));
+    let tilted = NormalMap::tangent_space(lambertian(), Box::new(ConstantTexture::new(0.5, 0.2, 0.9)));
+    assert_shading_normal(&tilted, Vec3::new(0.0, 0.8, -0.6));
+
+    // A surface rising along x leans back towards -x
+    let bumped = NormalMap::bump(lambertian(), Box::new(Ramp), 1.0);
+    assert_shading_normal(&bumped, unit_vector(Vec3::new(-1.0, 1.0, 0.0)));
+  }
+
+  // Sums f over all directions, with more samples towards the poles
+  fn integrate_sphere<F>(f: F) -> Vec3 where F: Fn(Vec3) -> Vec3 {
+    let n = 300;
+    let mut sum = Vec3::new(0.0, 0.0, 0.0);
+    for i in 0..n {
+      for j in 0..n {
+        let t = (i as f32 + 0.5) / n as f32;
+        let theta = f32::consts::FRAC_PI_2 * t * t;
+        let phi = 2.0 * f32::consts::PI * (j as f32 + 0.5) / n as f32;
+        let solid_angle = theta.sin() * (f32::consts::PI * t / n as f32) * (2.0 * f32::consts::PI / n as f32);
+        for &side in [1.0, -1.0].iter() {
+          let w = Vec3::new(theta.sin() * phi.cos(), side * theta.cos(), theta.sin() * phi.sin());
+          sum += solid_angle * f(w);
+        }
+      }
+    }
+
+    sum
+  }
+
+  fn constant(v: f32) -> Box<Texture> {
+    Box::new(ConstantTexture::new(v, v, v))
+  }
+
+  #[test]
+  fn principled_samples_follow_pdf() {
+    let material = Principled::new(Box::new(ConstantTexture::new(0.8, 0.5, 0.3)))
+      .with_metallic(constant(0.3))
+      .with_roughness(constant(0.4))
+      .with_clearcoat(constant(1.0), constant(0.1))
+      .with_sheen(constant(0.5))
+      .with_transmission(constant(0.4), 1.5);
+    let rec = hit_record(&material);
+    let r_in = Ray::new(Vec3::new(-0.6, 1.0, 0.2), Vec3::new(0.6, -1.0, -0.2));
+    let wo = -r_in.direction;
+
+    let n = 20000;
+    let mut mean = Vec3::new(0.0, 0.0, 0.0);
+    let mut count = 0;
+    for _ in 0..n {
+      if let Some(sample) = material.sample(&r_in, &rec) {
+        let expected = material.eval(&rec, &sample.direction, &wo) / material.pdf(&rec, &sample.direction, &wo);
+        assert!((sample.throughput - expected).length() < 1e-3);
+        mean += unit_vector(sample.direction);
+        count += 1;
+      }
+    }
+    mean /= count as f32;
+
+    // Directions are picked as often as the pdf says, both above and below the surface
+    let total = integrate_sphere(|w| { let p = material.pdf(&rec, &w, &wo); Vec3::new(p, p, p) }).x();
+    let expected = integrate_sphere(|w| material.pdf(&rec, &w, &wo) * w) / total;
+    assert!(total <= 1.01 && total > 0.9, "pdf integrates to {}", total);
+    assert!((mean - expected).length() < 0.02, "mean {:?} expected {:?}", mean, expected);
+  }
+
+  #[test]
+  fn principled_white_furnace() {
+    // A white dielectric reflects about as much light as it receives
+    let material = Principled::new(constant(1.0));
+    let rec = hit_record(&material);
+    let r_in = Ray::new(Vec3::new(0.0, 1.0, 0.5), Vec3::new(0.0, -1.0, -0.5));
+
+    let n = 20000;
+    let albedo = (0..n).filter_map(|_| material.sample(&r_in, &rec)).map(|s| s.throughput.x()).sum::<f32>() / n as f32;
+    assert!(albedo > 0.9 && albedo < 1.1, "albedo {}", albedo);
+  }
+
+  #[test]
+  fn principled_parameters_follow_textures() {
+    // Metal on the right half, which has no diffuse lobe
+    let metallic = UvCheckerTexture::new(constant(1.0), constant(0.0), 2.0, 1.0);
+    let material = Principled::new(constant(0.8)).with_metallic(Box::new(metallic)).with_roughness(constant(0.1));
+    let mut rec = hit_record(&material);
+    let (wi, wo) = (Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 0.0));
+
+    rec.u = 0.25;
+    let plastic = material.eval(&rec, &wi, &wo);
+    rec.u = 0.75;
+    let metal = material.eval(&rec, &wi, &wo);
+
+    assert!(plastic.x() > 0.2 && metal.x() < 0.01, "plastic {:?} metal {:?}", plastic, metal);
+  }
+
+  fn assert_sample_matches_eval(material: &Material) {
+    let rec = hit_record(material);
+    let r_in = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+
+    for _ in 0..100 {
+      let sample = material.sample(&r_in, &rec).unwrap();
+      let wo = -r_in.direction;
+      let pdf = material.pdf(&rec, &sample.direction, &wo);
+      let expected = material.eval(&rec, &sample.direction, &wo) / pdf;
+
+      assert!((sample.pdf - pdf).abs() < 1e-4);
+      for i in 0..3 {
+        assert!((sample.throughput[i] - expected[i]).abs() < 1e-4);
+      }
+    }
+  }
+
+  #[test]
+  fn lambertian_sample_matches_eval() {
+    assert_sample_matches_eval(&Lambertian { albedo: Box::new(ConstantTexture::new(0.2, 0.5, 0.8)) });
+  }
+
+  #[test]
+  fn isotropic_sample_matches_eval() {
+    assert_sample_matches_eval(&Isotropic { albedo: Box::new(ConstantTexture::new(0.2, 0.5, 0.8)) });
+  }
+}
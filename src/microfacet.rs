@@ -0,0 +1,684 @@
+extern crate rand;
+
+use std::f32;
+
+use ::vec3::{Vec3, unit_vector};
+use ::ray::Ray;
+use ::onb::Onb;
+use ::material::{Material, HitRecord, BsdfSample};
+
+/// The GGX, or Trowbridge-Reitz, distribution of microfacet normals.
+///
+/// Directions are given in a local frame where z is the surface normal and
+/// x the tangent that `alpha_x` stretches the highlight along.
+#[derive(Clone, Copy, Debug)]
+pub struct Ggx {
+  pub alpha_x: f32,
+  pub alpha_y: f32
+}
+
+impl Ggx {
+  /// A distribution from perceptual roughness between 0 and 1, squared into
+  /// alpha, and anisotropy between 0 and 1 stretching the highlight along x.
+  pub fn new(roughness: f32, anisotropy: f32) -> Ggx {
+    let alpha = roughness.max(0.0).min(1.0).powi(2);
+    let aspect = (1.0 - 0.9 * anisotropy.max(0.0).min(1.0)).sqrt();
+
+    Ggx { alpha_x: alpha / aspect, alpha_y: alpha * aspect }
+  }
+
+  /// Whether the surface is so smooth that it is better treated as a perfect mirror.
+  pub fn is_smooth(&self) -> bool {
+    self.alpha_x.max(self.alpha_y) < 1e-3
+  }
+
+  /// Density of microfacets facing `m`, per unit of projected area.
+  pub fn d(&self, m: &Vec3) -> f32 {
+    if m.z() <= 0.0 {
+      return 0.0;
+    }
+
+    let e = (m.x() / self.alpha_x).powi(2) + (m.y() / self.alpha_y).powi(2) + m.z() * m.z();
+    1.0 / (f32::consts::PI * self.alpha_x * self.alpha_y * e * e)
+  }
+
+  fn lambda(&self, w: &Vec3) -> f32 {
+    let z2 = w.z() * w.z();
+    if z2 <= 0.0 {
+      return f32::INFINITY;
+    }
+
+    let tan2 = ((self.alpha_x * w.x()).powi(2) + (self.alpha_y * w.y()).powi(2)) / z2;
+    0.5 * ((1.0 + tan2).sqrt() - 1.0)
+  }
+
+  /// The fraction of microfacets visible from `w`.
+  pub fn g1(&self, w: &Vec3) -> f32 {
+    1.0 / (1.0 + self.lambda(w))
+  }
+
+  /// The fraction of microfacets visible from both `wo` and `wi`, taking
+  /// into account that they are more likely seen from both if seen from one.
+  pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+    1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+  }
+
+  /// Picks a microfacet normal among those visible from `wo`, as described
+  /// by Heitz in "Sampling the GGX Distribution of Visible Normals".
+  pub fn sample_visible_normal(&self, wo: &Vec3, u1: f32, u2: f32) -> Vec3 {
+    // Stretch the view direction so that the distribution becomes a hemisphere
+    let vh = unit_vector(Vec3::new(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()));
+    let lensq = vh.x() * vh.x() + vh.y() * vh.y();
+    let t1 = if lensq > 0.0 { Vec3::new(-vh.y(), vh.x(), 0.0) / lensq.sqrt() } else { Vec3::new(1.0, 0.0, 0.0) };
+    let t2 = vh.cross(t1);
+
+    // Pick a point on the disk the hemisphere projects onto, as seen from vh
+    let r = u1.sqrt();
+    let phi = 2.0 * f32::consts::PI * u2;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z());
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+    unit_vector(Vec3::new(self.alpha_x * nh.x(), self.alpha_y * nh.y(), nh.z().max(1e-6)))
+  }
+
+  /// The density of `sample_visible_normal` picking `m`, over directions.
+  pub fn visible_normal_pdf(&self, wo: &Vec3, m: &Vec3) -> f32 {
+    if wo.z() <= 0.0 {
+      return 0.0;
+    }
+
+    self.g1(wo) * wo.dot(*m).max(0.0) * self.d(m) / wo.z()
+  }
+}
+
+/// Fraction of unpolarized light reflected by a conductor with complex index
+/// of refraction `eta + i k`, per color channel, at an angle with cosine `cos_i`.
+pub fn fresnel_conductor(cos_i: f32, eta: &Vec3, k: &Vec3) -> Vec3 {
+  let cos2 = cos_i.max(0.0).min(1.0).powi(2);
+  let sin2 = 1.0 - cos2;
+  let mut reflectance = Vec3::new(0.0, 0.0, 0.0);
+
+  for c in 0..3 {
+    let (eta2, k2) = (eta[c] * eta[c], k[c] * k[c]);
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    reflectance[c] = 0.5 * (rs + rp);
+  }
+
+  reflectance
+}
+
+/// Measured metals, with their complex index of refraction at red, green
+/// and blue wavelengths.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConductorPreset {
+  Gold,
+  Copper,
+  Silver,
+  Aluminium
+}
+
+impl ConductorPreset {
+  /// The real and imaginary parts, `eta` and `k`, of the index of refraction.
+  pub fn ior(&self) -> (Vec3, Vec3) {
+    match *self {
+      ConductorPreset::Gold => (Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603)),
+      ConductorPreset::Copper => (Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142)),
+      ConductorPreset::Silver => (Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147)),
+      ConductorPreset::Aluminium => (Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837))
+    }
+  }
+}
+
+/// A metal, reflecting off microfacets following the GGX distribution with
+/// the Fresnel term of its complex index of refraction. Anisotropic highlights
+/// are stretched along the surface's `dpdu`.
+pub struct Conductor {
+  pub eta: Vec3,
+  pub k: Vec3,
+  pub roughness: f32,
+  pub anisotropy: f32
+}
+
+impl Conductor {
+  pub fn new(eta: Vec3, k: Vec3, roughness: f32) -> Conductor {
+    Conductor { eta, k, roughness, anisotropy: 0.0 }
+  }
+
+  pub fn preset(metal: ConductorPreset, roughness: f32) -> Conductor {
+    let (eta, k) = metal.ior();
+    Conductor::new(eta, k, roughness)
+  }
+
+  pub fn with_anisotropy(self, anisotropy: f32) -> Conductor {
+    Conductor { anisotropy, ..self }
+  }
+
+  fn distribution(&self) -> Ggx {
+    Ggx::new(self.roughness, self.anisotropy)
+  }
+}
+
+/// The shading frame at a hit, facing `wo` so that surfaces reflect from both sides.
+pub fn shading_frame(rec: &HitRecord, wo: &Vec3) -> Onb {
+  let n = if rec.normal.dot(*wo) < 0.0 { -rec.normal } else { rec.normal };
+  Onb::build_from_w_and_u(n, rec.dpdu)
+}
+
+impl Material for Conductor {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    let frame = shading_frame(rec, &-r_in.direction);
+    let wo = unit_vector(frame.to_local(-r_in.direction));
+    let distribution = self.distribution();
+
+    if distribution.is_smooth() {
+      let wi = Vec3::new(-wo.x(), -wo.y(), wo.z());
+      let throughput = fresnel_conductor(wo.z(), &self.eta, &self.k);
+      return Some(BsdfSample { direction: frame.local(wi), pdf: 1.0, throughput, is_delta: true });
+    }
+
+    let m = distribution.sample_visible_normal(&wo, rand::random::<f32>(), rand::random::<f32>());
+    let wi = 2.0 * wo.dot(m) * m - wo;
+    if wi.z() <= 0.0 {
+      return None;
+    }
+
+    Some(BsdfSample {
+      direction: frame.local(wi),
+      pdf: distribution.visible_normal_pdf(&wo, &m) / (4.0 * wo.dot(m)),
+      throughput: distribution.g(&wo, &wi) / distribution.g1(&wo) * fresnel_conductor(wo.dot(m), &self.eta, &self.k),
+      is_delta: false
+    })
+  }
+
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    let frame = shading_frame(rec, wo);
+    let (wi, wo) = (unit_vector(frame.to_local(*wi)), unit_vector(frame.to_local(*wo)));
+    let distribution = self.distribution();
+    if distribution.is_smooth() || wi.z() <= 0.0 || wo.z() <= 0.0 {
+      return Vec3::new(0.0, 0.0, 0.0);
+    }
+
+    let h = unit_vector(wi + wo);
+    (distribution.d(&h) * distribution.g(&wo, &wi) / (4.0 * wo.z())) * fresnel_conductor(wo.dot(h), &self.eta, &self.k)
+  }
+
+  fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f32 {
+    let frame = shading_frame(rec, wo);
+    let (wi, wo) = (unit_vector(frame.to_local(*wi)), unit_vector(frame.to_local(*wo)));
+    let distribution = self.distribution();
+    if distribution.is_smooth() || wi.z() <= 0.0 || wo.z() <= 0.0 {
+      return 0.0;
+    }
+
+    let h = unit_vector(wi + wo);
+    distribution.visible_normal_pdf(&wo, &h) / (4.0 * wo.dot(h))
+  }
+
+  fn is_delta(&self) -> bool {
+    self.distribution().is_smooth()
+  }
+}
+
+/// Fraction of unpolarized light reflected by a dielectric at an angle with
+/// cosine `cos_i`, where `eta` is the index of refraction on the far side of
+/// the surface divided by the one on the near side.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let cos_i = cos_i.max(0.0).min(1.0);
+  let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+  if sin2_t >= 1.0 {
+    return 1.0;
+  }
+
+  let cos_t = (1.0 - sin2_t).sqrt();
+  let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// Bends `w` through a surface with normal `m` on the same side, with `eta` as
+// in `fresnel_dielectric`, or None if it is totally reflected
+fn refract(w: &Vec3, m: &Vec3, eta: f32) -> Option<Vec3> {
+  let cos_i = w.dot(*m);
+  let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+  if sin2_t >= 1.0 {
+    return None;
+  }
+
+  Some(-*w / eta + (cos_i / eta - (1.0 - sin2_t).sqrt()) * *m)
+}
+
+// Beer's law: the fraction of light left after `distance` through an absorbing medium
+fn beer(absorption: &Vec3, distance: f32) -> Vec3 {
+  Vec3::new(
+    (-absorption.x() * distance).exp(),
+    (-absorption.y() * distance).exp(),
+    (-absorption.z() * distance).exp())
+}
+
+/// Glass, smooth or frosted, refracting and reflecting off microfacets as
+/// described by Walter et al. in "Microfacet Models for Refraction through
+/// Rough Surfaces", with exact Fresnel terms.
+///
+/// Light travelling inside is absorbed following Beer's law, which requires
+/// the object to be closed. A thin sheet, like a window pane, is instead
+/// passed straight through, reflecting light bouncing around inside it too.
+pub struct RoughDielectric {
+  pub ior: f32,
+  pub roughness: f32,
+  /// Fraction of light absorbed per unit of distance inside, per color channel.
+  pub absorption: Vec3,
+  /// The thickness of a thin sheet, or None for solid objects.
+  pub thickness: Option<f32>
+}
+
+impl RoughDielectric {
+  pub fn new(ior: f32, roughness: f32) -> RoughDielectric {
+    RoughDielectric { ior, roughness, absorption: Vec3::new(0.0, 0.0, 0.0), thickness: None }
+  }
+
+  /// Tints the glass, so that white light has turned `color` after travelling `distance` inside.
+  pub fn with_color(self, color: Vec3, distance: f32) -> RoughDielectric {
+    let absorption = Vec3::new(
+      -color.x().max(1e-6).min(1.0).ln() / distance,
+      -color.y().max(1e-6).min(1.0).ln() / distance,
+      -color.z().max(1e-6).min(1.0).ln() / distance);
+
+    RoughDielectric { absorption, ..self }
+  }
+
+  /// Makes the surface a thin sheet of glass of the given thickness.
+  pub fn with_thickness(self, thickness: f32) -> RoughDielectric {
+    RoughDielectric { thickness: Some(thickness), ..self }
+  }
+
+  fn distribution(&self) -> Ggx {
+    Ggx::new(self.roughness, 0.0)
+  }
+
+  // The relative index of refraction going from the side of `wo` to the
+  // other, and how much of the light is absorbed on its way to `wo` if that
+  // is inside the object
+  fn sides(&self, rec: &HitRecord, wo: &Vec3) -> (f32, Vec3) {
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    match self.thickness {
+      Some(_) => (self.ior, white),
+      None if wo.dot(rec.normal) < 0.0 => (1.0 / self.ior, beer(&self.absorption, rec.t * wo.length())),
+      None => (self.ior, white)
+    }
+  }
+
+  // Reflectance of a thin sheet, light bouncing back and forth inside
+  // included, and the absorption of light passing through
+  fn sheet(&self, cos_i: f32, thickness: f32) -> (f32, Vec3) {
+    let r = fresnel_dielectric(cos_i, self.ior);
+    let cos_t = (1.0 - (1.0 - cos_i * cos_i) / (self.ior * self.ior)).max(1e-4).sqrt();
+
+    (2.0 * r / (1.0 + r), beer(&self.absorption, thickness / cos_t))
+  }
+
+  // The BSDF times cosine, and pdf, of light from `wi` leaving towards `wo`,
+  // both in the local shading frame facing `wo`
+  fn eval_local(&self, wi: &Vec3, wo: &Vec3, eta: f32) -> (Vec3, f32) {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let distribution = self.distribution();
+    if distribution.is_smooth() || wo.z() <= 0.0 || wi.z() == 0.0 {
+      return (black, 0.0);
+    }
+
+    if let Some(thickness) = self.thickness {
+      // Light passing through a sheet leaves as if reflected, but on the other side
+      let transmitted = wi.z() < 0.0;
+      let wr = if transmitted { Vec3::new(wi.x(), wi.y(), -wi.z()) } else { *wi };
+      let m = unit_vector(wr + *wo);
+      let (reflectance, tint) = self.sheet(wo.dot(m), thickness);
+      let (probability, color) = if transmitted { (1.0 - reflectance, tint) } else { (reflectance, Vec3::new(1.0, 1.0, 1.0)) };
+      let f = distribution.d(&m) * distribution.g(wo, &wr) / (4.0 * wo.z());
+
+      return ((probability * f) * color, probability * distribution.visible_normal_pdf(wo, &m) / (4.0 * wo.dot(m)));
+    }
+
+    if wi.z() > 0.0 {
+      let m = unit_vector(*wi + *wo);
+      let fresnel = fresnel_dielectric(wo.dot(m), eta);
+      let f = fresnel * distribution.d(&m) * distribution.g(wo, wi) / (4.0 * wo.z());
+
+      return (Vec3::new(f, f, f), fresnel * distribution.visible_normal_pdf(wo, &m) / (4.0 * wo.dot(m)));
+    }
+
+    // The microfacet normal that refracts wo into wi
+    let mut m = unit_vector(eta * *wi + *wo);
+    if m.z() < 0.0 {
+      m = -m;
+    }
+    if wo.dot(m) <= 0.0 || wi.dot(m) >= 0.0 {
+      return (black, 0.0);
+    }
+
+    let transmittance = 1.0 - fresnel_dielectric(wo.dot(m), eta);
+    let denominator = (wi.dot(m) + wo.dot(m) / eta).powi(2);
+    // Radiance is squeezed into a smaller solid angle entering a denser medium
+    let f = transmittance * distribution.d(&m) * distribution.g(wo, wi) * (wi.dot(m) * wo.dot(m)).abs()
+      / (wo.z() * denominator * eta * eta);
+
+    (Vec3::new(f, f, f), transmittance * distribution.visible_normal_pdf(wo, &m) * wi.dot(m).abs() / denominator)
+  }
+}
+
+impl Material for RoughDielectric {
+  fn sample(&self, r_in: &Ray, rec: &HitRecord) -> Option<BsdfSample> {
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let frame = shading_frame(rec, &-r_in.direction);
+    let wo = unit_vector(frame.to_local(-r_in.direction));
+    let (eta, attenuation) = self.sides(rec, &-r_in.direction);
+    let distribution = self.distribution();
+    let smooth = distribution.is_smooth();
+
+    let m = if smooth {
+      Vec3::new(0.0, 0.0, 1.0)
+    } else {
+      distribution.sample_visible_normal(&wo, rand::random::<f32>(), rand::random::<f32>())
+    };
+    let cos_o = wo.dot(m);
+    let reflected = 2.0 * cos_o * m - wo;
+
+    let (wi, throughput) = if let Some(thickness) = self.thickness {
+      if reflected.z() <= 0.0 {
+        return None;
+      }
+
+      let (reflectance, tint) = self.sheet(cos_o, thickness);
+      if rand::random::<f32>() < reflectance {
+        (reflected, white)
+      } else {
+        (Vec3::new(reflected.x(), reflected.y(), -reflected.z()), tint)
+      }
+    } else if rand::random::<f32>() < fresnel_dielectric(cos_o, eta) {
+      if reflected.z() <= 0.0 {
+        return None;
+      }
+
+      (reflected, white)
+    } else {
+      match refract(&wo, &m, eta) {
+        Some(refracted) if refracted.z() < 0.0 => (refracted, white / (eta * eta)),
+        _ => return None
+      }
+    };
+
+    if smooth {
+      return Some(BsdfSample { direction: frame.local(wi), pdf: 1.0, throughput: throughput * attenuation, is_delta: true });
+    }
+
+    let (_, pdf) = self.eval_local(&wi, &wo, eta);
+    // The Fresnel terms cancel against the probabilities of picking each lobe
+    let masking = distribution.g(&wo, &wi) / distribution.g1(&wo);
+    Some(BsdfSample { direction: frame.local(wi), pdf, throughput: masking * throughput * attenuation, is_delta: false })
+  }
+
+  fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+    let frame = shading_frame(rec, wo);
+    let (eta, attenuation) = self.sides(rec, wo);
+    let (f, _) = self.eval_local(&unit_vector(frame.to_local(*wi)), &unit_vector(frame.to_local(*wo)), eta);
+
+    f * attenuation
+  }
+
+  fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f32 {
+    let frame = shading_frame(rec, wo);
+    let (eta, _) = self.sides(rec, wo);
+    let (_, pdf) = self.eval_local(&unit_vector(frame.to_local(*wi)), &unit_vector(frame.to_local(*wo)), eta);
+
+    pdf
+  }
+
+  fn is_delta(&self) -> bool {
+    self.distribution().is_smooth()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hit_record(material: &Material) -> HitRecord<'_> {
+    HitRecord {
+      t: 1.0,
+      p: Vec3::new(0.0, 0.0, 0.0),
+      normal: Vec3::new(0.0, 0.0, 1.0),
+      geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+      material,
+      u: 0.0,
+      v: 0.0,
+      dpdu: Vec3::new(1.0, 0.0, 0.0),
+      dpdv: Vec3::new(0.0, 1.0, 0.0),
+      uv_derivatives: None
+    }
+  }
+
+  // Sums f over the hemisphere, with more samples towards the normal where GGX peaks
+  fn integrate_hemisphere<F>(f: F) -> f32 where F: Fn(Vec3) -> f32 {
+    let n = 400;
+    let mut sum = 0.0;
+    for i in 0..n {
+      for j in 0..n {
+        let t = (i as f32 + 0.5) / n as f32;
+        let theta = f32::consts::FRAC_PI_2 * t * t;
+        let phi = 2.0 * f32::consts::PI * (j as f32 + 0.5) / n as f32;
+        let w = Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+        let solid_angle = theta.sin() * (f32::consts::PI * t / n as f32) * (2.0 * f32::consts::PI / n as f32);
+
+        sum += f(w) * solid_angle;
+      }
+    }
+
+    sum
+  }
+
+  #[test]
+  fn ggx_normals_cover_the_surface_once() {
+    // The projected area of all microfacets adds up to the surface's own
+    for &(roughness, anisotropy) in [(0.3, 0.0), (0.6, 0.0), (0.5, 0.8)].iter() {
+      let ggx = Ggx::new(roughness, anisotropy);
+      let sum = integrate_hemisphere(|m| ggx.d(&m) * m.z());
+
+      assert!((sum - 1.0).abs() < 0.01, "roughness {} anisotropy {} covers {}", roughness, anisotropy, sum);
+    }
+  }
+
+  #[test]
+  fn visible_normals_follow_their_density() {
+    let ggx = Ggx::new(0.5, 0.6);
+    let wo = unit_vector(Vec3::new(0.5, -0.3, 0.6));
+
+    // The density integrates to one over the hemisphere...
+    let sum = integrate_hemisphere(|m| ggx.visible_normal_pdf(&wo, &m));
+    assert!((sum - 1.0).abs() < 0.01, "pdf integrates to {}", sum);
+
+    // ...and the sampled normals are visible and average out like the density says
+    let n = 400;
+    let mut mean = Vec3::new(0.0, 0.0, 0.0);
+    for i in 0..n {
+      for j in 0..n {
+        let m = ggx.sample_visible_normal(&wo, (i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+        assert!(m.dot(wo) >= -1e-4 && m.z() > 0.0);
+        mean += m / (n * n) as f32;
+      }
+    }
+    for c in 0..3 {
+      let expected = integrate_hemisphere(|m| ggx.visible_normal_pdf(&wo, &m) * m[c]);
+      assert!((mean[c] - expected).abs() < 0.01, "mean {:?} differs from {} in {}", mean, expected, c);
+    }
+  }
+
+  #[test]
+  fn conductor_fresnel_matches_normal_incidence_and_grazing() {
+    let (eta, k) = ConductorPreset::Gold.ior();
+    let f = fresnel_conductor(1.0, &eta, &k);
+    for c in 0..3 {
+      let expected = ((eta[c] - 1.0).powi(2) + k[c] * k[c]) / ((eta[c] + 1.0).powi(2) + k[c] * k[c]);
+      assert!((f[c] - expected).abs() < 1e-4);
+    }
+    // Gold is yellow, and every metal turns white at grazing angles
+    assert!(f.r() > f.g() && f.g() > f.b());
+    let grazing = fresnel_conductor(0.0, &eta, &k);
+    assert!((grazing - Vec3::new(1.0, 1.0, 1.0)).length() < 1e-3);
+  }
+
+  #[test]
+  fn conductor_samples_match_eval() {
+    for &anisotropy in [0.0, 0.7].iter() {
+      let material = Conductor::preset(ConductorPreset::Copper, 0.4).with_anisotropy(anisotropy);
+      let rec = hit_record(&material);
+      let r_in = Ray::new(Vec3::new(-1.0, 0.2, 1.0), Vec3::new(1.0, -0.2, -1.0));
+      let wo = -r_in.direction;
+
+      for _ in 0..200 {
+        let sample = match material.sample(&r_in, &rec) {
+          Some(sample) => sample,
+          None => continue
+        };
+        let pdf = material.pdf(&rec, &sample.direction, &wo);
+        let expected = material.eval(&rec, &sample.direction, &wo) / pdf;
+
+        assert!(!sample.is_delta);
+        assert!((sample.pdf - pdf).abs() < 1e-3 * pdf.max(1.0));
+        assert!((sample.throughput - expected).length() < 1e-3);
+      }
+    }
+  }
+
+  #[test]
+  fn rough_conductor_reflects_no_more_than_it_receives() {
+    // A perfectly reflecting metal only loses energy where microfacets shadow each other
+    let material = Conductor::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1e4, 1e4, 1e4), 0.3);
+    let rec = hit_record(&material);
+    let r_in = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.3, 0.0, -1.0));
+
+    let n = 20000;
+    let mut sum = 0.0;
+    for _ in 0..n {
+      if let Some(sample) = material.sample(&r_in, &rec) {
+        sum += sample.throughput.x() / n as f32;
+      }
+    }
+    assert!(sum <= 1.0 && sum > 0.9, "albedo {}", sum);
+  }
+
+  #[test]
+  fn smooth_conductor_is_a_mirror() {
+    let material = Conductor::preset(ConductorPreset::Silver, 0.0);
+    let rec = hit_record(&material);
+    let sample = material.sample(&Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0)), &rec).unwrap();
+
+    assert!(material.is_delta() && sample.is_delta);
+    assert!((unit_vector(sample.direction) - unit_vector(Vec3::new(1.0, 0.0, 1.0))).length() < 1e-5);
+  }
+
+  #[test]
+  fn dielectric_fresnel_matches_normal_incidence_and_total_reflection() {
+    assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-5);
+    assert!((fresnel_dielectric(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-5);
+    assert!((fresnel_dielectric(0.0, 1.5) - 1.0).abs() < 1e-5);
+    // Beyond the critical angle of about 42 degrees, light inside glass can't get out
+    assert_eq!(1.0, fresnel_dielectric(0.7, 1.0 / 1.5));
+    assert!(fresnel_dielectric(0.8, 1.0 / 1.5) < 1.0);
+  }
+
+  fn assert_dielectric_samples_match_eval(material: &RoughDielectric, r_in: &Ray) {
+    let rec = hit_record(material);
+    let wo = -r_in.direction;
+    let (mut reflected, mut transmitted) = (0, 0);
+
+    for _ in 0..500 {
+      let sample = match material.sample(r_in, &rec) {
+        Some(sample) => sample,
+        None => continue
+      };
+      let pdf = material.pdf(&rec, &sample.direction, &wo);
+      let expected = material.eval(&rec, &sample.direction, &wo) / pdf;
+
+      if sample.direction.z() > 0.0 { reflected += 1 } else { transmitted += 1 }
+      assert!((sample.pdf - pdf).abs() < 1e-3 * pdf.max(1.0), "pdf {} expected {}", sample.pdf, pdf);
+      assert!((sample.throughput - expected).length() < 1e-3, "throughput {:?} expected {:?}", sample.throughput, expected);
+    }
+
+    assert!(reflected > 0 && transmitted > 0);
+  }
+
+  #[test]
+  fn rough_dielectric_samples_match_eval() {
+    let glass = RoughDielectric::new(1.5, 0.4).with_color(Vec3::new(0.5, 0.8, 0.9), 1.0);
+    // From outside, from inside, and through a thin sheet
+    assert_dielectric_samples_match_eval(&glass, &Ray::new(Vec3::new(-1.0, 0.2, 1.0), Vec3::new(1.0, -0.2, -1.0)));
+    assert_dielectric_samples_match_eval(&glass, &Ray::new(Vec3::new(-0.2, 0.1, -1.0), Vec3::new(0.2, -0.1, 1.0)));
+    assert_dielectric_samples_match_eval(&glass.with_thickness(0.01), &Ray::new(Vec3::new(-1.0, 0.2, 1.0), Vec3::new(1.0, -0.2, -1.0)));
+  }
+
+  #[test]
+  fn rough_dielectric_pdf_covers_the_sphere() {
+    let glass = RoughDielectric::new(1.5, 0.5);
+    let rec = hit_record(&glass);
+    let wo = unit_vector(Vec3::new(-0.4, 0.1, 1.0));
+
+    let above = integrate_hemisphere(|w| glass.pdf(&rec, &w, &wo));
+    let below = integrate_hemisphere(|w| glass.pdf(&rec, &Vec3::new(w.x(), w.y(), -w.z()), &wo));
+
+    // Reflected directions below the surface are rejected, losing a little
+    assert!(above + below <= 1.01 && above + below > 0.95, "pdf integrates to {}", above + below);
+    assert!(above < below);
+  }
+
+  #[test]
+  fn smooth_glass_absorbs_light_on_the_way_out() {
+    let glass = RoughDielectric::new(1.5, 0.0).with_color(Vec3::new(0.5, 0.5, 0.5), 1.0);
+    let mut rec = hit_record(&glass);
+    rec.t = 2.0;
+
+    // Straight out through the top, having travelled 2 units inside
+    let r_in = Ray::new(Vec3::new(0.0, 0.0, -2.0), Vec3::new(0.0, 0.0, 1.0));
+    let throughputs: Vec<Vec3> = (0..100).filter_map(|_| glass.sample(&r_in, &rec))
+      .filter(|s| s.direction.z() > 0.0)
+      .map(|s| s.throughput)
+      .collect();
+
+    assert!(!throughputs.is_empty() && glass.is_delta());
+    for t in throughputs {
+      assert!((t - Vec3::new(0.25 * 2.25, 0.25 * 2.25, 0.25 * 2.25)).length() < 1e-4, "{:?}", t);
+    }
+  }
+
+  #[test]
+  fn thin_sheet_lets_light_straight_through() {
+    let window = RoughDielectric::new(1.5, 0.0).with_thickness(0.005);
+    let rec = hit_record(&window);
+    let r_in = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0));
+
+    let n = 20000;
+    let mut reflected = 0;
+    for _ in 0..n {
+      let sample = window.sample(&r_in, &rec).unwrap();
+      if sample.direction.z() > 0.0 {
+        reflected += 1;
+      } else {
+        assert!((unit_vector(sample.direction) - unit_vector(r_in.direction)).length() < 1e-5);
+      }
+    }
+
+    // Both faces of the sheet reflect
+    let r = fresnel_dielectric(0.5f32.sqrt(), 1.5);
+    let expected = 2.0 * r / (1.0 + r);
+    assert!((reflected as f32 / n as f32 - expected).abs() < 0.01, "reflected {} expected {}", reflected as f32 / n as f32, expected);
+  }
+}
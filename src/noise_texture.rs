@@ -0,0 +1,251 @@
+extern crate rand;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use ::vec3::{Vec3, unit_vector};
+use ::material::Texture;
+
+/// Gradient noise as described by Ken Perlin, smoothly varying between about
+/// -1 and 1 with features roughly a unit apart.
+pub struct Perlin {
+  gradients: Vec<Vec3>,
+  perm_x: Vec<usize>,
+  perm_y: Vec<usize>,
+  perm_z: Vec<usize>
+}
+
+const POINT_COUNT: usize = 256;
+
+impl Perlin {
+  /// Noise with gradients picked from `seed`, the same seed always giving the same noise.
+  pub fn new(seed: u32) -> Perlin {
+    let mut rng = XorShiftRng::from_seed([seed ^ 0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb]);
+    let gradients = (0..POINT_COUNT).map(|_| unit_vector(Vec3::new(
+      2.0 * rng.gen::<f32>() - 1.0,
+      2.0 * rng.gen::<f32>() - 1.0,
+      2.0 * rng.gen::<f32>() - 1.0))).collect();
+
+    Perlin {
+      gradients,
+      perm_x: permutation(&mut rng),
+      perm_y: permutation(&mut rng),
+      perm_z: permutation(&mut rng)
+    }
+  }
+
+  pub fn noise(&self, p: &Vec3) -> f32 {
+    let (i, j, k) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (u, v, w) = (p.x() - i, p.y() - j, p.z() - k);
+    let (i, j, k) = (i as i64, j as i64, k as i64);
+
+    let mut sum = 0.0;
+    for di in 0..2 {
+      for dj in 0..2 {
+        for dk in 0..2 {
+          let gradient = self.gradients[
+            self.perm_x[((i + di) & 255) as usize] ^
+            self.perm_y[((j + dj) & 255) as usize] ^
+            self.perm_z[((k + dk) & 255) as usize]];
+          let (fi, fj, fk) = (di as f32, dj as f32, dk as f32);
+          let weight = Vec3::new(u - fi, v - fj, w - fk);
+
+          sum += (fi * smooth(u) + (1.0 - fi) * (1.0 - smooth(u))) *
+            (fj * smooth(v) + (1.0 - fj) * (1.0 - smooth(v))) *
+            (fk * smooth(w) + (1.0 - fk) * (1.0 - smooth(w))) *
+            gradient.dot(weight);
+        }
+      }
+    }
+
+    sum
+  }
+
+  /// Fractal Brownian motion: `octaves` layers of noise, each of twice the
+  /// frequency and half the amplitude of the one before.
+  pub fn fbm(&self, p: &Vec3, octaves: usize) -> f32 {
+    self.octaves(p, octaves, |n| n)
+  }
+
+  /// Like `fbm`, but summing the absolute value of each octave, giving
+  /// sharp creases where the noise changes sign.
+  pub fn turbulence(&self, p: &Vec3, octaves: usize) -> f32 {
+    self.octaves(p, octaves, |n| n.abs())
+  }
+
+  fn octaves<F>(&self, p: &Vec3, octaves: usize, f: F) -> f32 where F: Fn(f32) -> f32 {
+    let mut sum = 0.0;
+    let mut point = *p;
+    let mut weight = 1.0;
+    for _ in 0..octaves {
+      sum += weight * f(self.noise(&point));
+      weight *= 0.5;
+      point *= 2.0;
+    }
+
+    sum
+  }
+}
+
+fn permutation(rng: &mut XorShiftRng) -> Vec<usize> {
+  let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+  rng.shuffle(&mut p);
+  p
+}
+
+// Hermite smoothing of the interpolation weights, hiding the grid
+fn smooth(t: f32) -> f32 {
+  t * t * (3.0 - 2.0 * t)
+}
+
+/// Colors at positions between 0 and 1, linearly interpolated in between.
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+  stops: Vec<(f32, Vec3)>
+}
+
+impl ColorRamp {
+  pub fn new(mut stops: Vec<(f32, Vec3)>) -> ColorRamp {
+    assert!(!stops.is_empty(), "ColorRamp without colors");
+    stops.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("ColorRamp position is NaN"));
+    ColorRamp { stops }
+  }
+
+  /// Black at 0, fading to white at 1.
+  pub fn grayscale() -> ColorRamp {
+    ColorRamp::new(vec![(0.0, Vec3::new(0.0, 0.0, 0.0)), (1.0, Vec3::new(1.0, 1.0, 1.0))])
+  }
+
+  pub fn color(&self, t: f32) -> Vec3 {
+    let first = self.stops[0];
+    if t <= first.0 {
+      return first.1;
+    }
+
+    for pair in self.stops.windows(2) {
+      let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+      if t <= t1 {
+        let s = if t1 > t0 { (t - t0) / (t1 - t0) } else { 1.0 };
+        return (1.0 - s) * c0 + s * c1;
+      }
+    }
+
+    self.stops[self.stops.len() - 1].1
+  }
+}
+
+/// The shape of a `NoiseTexture`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoisePattern {
+  /// Plain Perlin noise.
+  Noise,
+  /// Fractal noise, with detail at all scales.
+  Fbm,
+  /// Billowy fractal noise, like smoke or clouds.
+  Turbulence,
+  /// Bands along z, distorted by turbulence.
+  Marble,
+  /// Rings around the y axis, distorted by noise.
+  Wood
+}
+
+/// A solid texture from noise, looked up by position so that it carves
+/// through objects like a block of stone or wood. The noise, between 0 and 1,
+/// picks a color from a `ColorRamp`.
+pub struct NoiseTexture {
+  perlin: Perlin,
+  pub pattern: NoisePattern,
+  /// Frequency of the noise; features are about `1 / scale` apart.
+  pub scale: f32,
+  /// Number of layers of detail for the fractal patterns.
+  pub octaves: usize,
+  pub ramp: ColorRamp
+}
+
+impl NoiseTexture {
+  /// A grayscale noise texture with 7 octaves of detail.
+  pub fn new(pattern: NoisePattern, scale: f32) -> NoiseTexture {
+    NoiseTexture { perlin: Perlin::new(0), pattern, scale, octaves: 7, ramp: ColorRamp::grayscale() }
+  }
+
+  pub fn with_ramp(self, ramp: ColorRamp) -> NoiseTexture {
+    NoiseTexture { ramp, ..self }
+  }
+
+  pub fn with_octaves(self, octaves: usize) -> NoiseTexture {
+    NoiseTexture { octaves, ..self }
+  }
+
+  pub fn with_seed(self, seed: u32) -> NoiseTexture {
+    NoiseTexture { perlin: Perlin::new(seed), ..self }
+  }
+
+  /// The pattern at `p`, between 0 and 1.
+  pub fn intensity(&self, p: &Vec3) -> f32 {
+    let sp = self.scale * *p;
+    let t = match self.pattern {
+      NoisePattern::Noise => 0.5 * (1.0 + self.perlin.noise(&sp)),
+      NoisePattern::Fbm => 0.5 * (1.0 + self.perlin.fbm(&sp, self.octaves)),
+      NoisePattern::Turbulence => self.perlin.turbulence(&sp, self.octaves),
+      NoisePattern::Marble => 0.5 * (1.0 + (sp.z() + 10.0 * self.perlin.turbulence(&sp, self.octaves)).sin()),
+      NoisePattern::Wood => {
+        let rings = (sp.x() * sp.x() + sp.z() * sp.z()).sqrt() + 2.0 * self.perlin.fbm(&sp, self.octaves);
+        rings - rings.floor()
+      }
+    };
+
+    t.max(0.0).min(1.0)
+  }
+}
+
+impl Texture for NoiseTexture {
+  fn value(&self, _u: f32, _v: f32, p: &Vec3) -> Vec3 {
+    self.ramp.color(self.intensity(p))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_points() -> Vec<Vec3> {
+    (0..500).map(|i| Vec3::new(i as f32 * 0.37, (i % 17) as f32 * 0.61, (i % 5) as f32 * -1.3)).collect()
+  }
+
+  #[test]
+  fn noise_is_smooth_and_repeatable() {
+    let perlin = Perlin::new(7);
+    let again = Perlin::new(7);
+
+    for p in sample_points() {
+      let n = perlin.noise(&p);
+      assert!(n.abs() <= 1.0 && n == again.noise(&p));
+      assert!((n - perlin.noise(&(p + Vec3::new(1e-3, 0.0, 0.0)))).abs() < 0.01);
+    }
+    assert!(sample_points().iter().any(|p| perlin.noise(p) != Perlin::new(8).noise(p)));
+  }
+
+  #[test]
+  fn patterns_stay_between_zero_and_one() {
+    for &pattern in [NoisePattern::Noise, NoisePattern::Fbm, NoisePattern::Turbulence, NoisePattern::Marble, NoisePattern::Wood].iter() {
+      let texture = NoiseTexture::new(pattern, 3.0);
+      let values: Vec<f32> = sample_points().iter().map(|p| texture.intensity(p)).collect();
+
+      assert!(values.iter().all(|&t| t >= 0.0 && t <= 1.0), "{:?} out of range", pattern);
+      assert!(values.iter().any(|&t| (t - values[0]).abs() > 0.1), "{:?} is flat", pattern);
+    }
+  }
+
+  #[test]
+  fn ramp_interpolates_between_sorted_stops() {
+    let ramp = ColorRamp::new(vec![
+      (1.0, Vec3::new(0.0, 0.0, 1.0)),
+      (0.0, Vec3::new(1.0, 0.0, 0.0)),
+      (0.5, Vec3::new(0.0, 1.0, 0.0))]);
+
+    assert!((ramp.color(-1.0) - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+    assert!((ramp.color(0.25) - Vec3::new(0.5, 0.5, 0.0)).length() < 1e-6);
+    assert!((ramp.color(0.75) - Vec3::new(0.0, 0.5, 0.5)).length() < 1e-6);
+    assert!((ramp.color(2.0) - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+  }
+}
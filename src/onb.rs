@@ -0,0 +1,43 @@
+use ::vec3::{Vec3, unit_vector};
+
+/// Orthonormal basis, with `w` along a given direction, typically a surface normal.
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+  pub u: Vec3,
+  pub v: Vec3,
+  pub w: Vec3
+}
+
+impl Onb {
+  pub fn build_from_w(n: Vec3) -> Onb {
+    let w = unit_vector(n);
+    let a = if w.x().abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+    let v = unit_vector(w.cross(a));
+    let u = w.cross(v);
+
+    Onb { u, v, w }
+  }
+
+  /// A basis with `w` along `n` and `u` as close as possible to `tangent`,
+  /// falling back to any `u` if the tangent is zero or along `n`.
+  pub fn build_from_w_and_u(n: Vec3, tangent: Vec3) -> Onb {
+    let w = unit_vector(n);
+    let u = tangent - tangent.dot(w) * w;
+    if u.squared_length() <= 1e-8 * tangent.squared_length() {
+      return Onb::build_from_w(n);
+    }
+
+    let u = unit_vector(u);
+    Onb { u, v: w.cross(u), w }
+  }
+
+  /// Transforms `a` from world space into the basis' local coordinates.
+  pub fn to_local(&self, a: Vec3) -> Vec3 {
+    Vec3::new(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+  }
+
+  /// Transforms `a` from the basis' local coordinates into world space.
+  pub fn local(&self, a: Vec3) -> Vec3 {
+    a.x() * self.u + a.y() * self.v + a.z() * self.w
+  }
+}
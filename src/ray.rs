@@ -3,15 +3,40 @@ use ::vec3::Vec3;
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
   pub origin: Vec3,
-  pub direction: Vec3
+  pub direction: Vec3,
+  /// How the ray changes between neighbouring pixels, for camera rays.
+  pub differentials: Option<RayDifferentials>
+}
+
+/// The change of a ray's origin and direction from one pixel to the next,
+/// in x and y, which gives the footprint of a pixel where the ray hits a surface.
+#[derive(Clone, Copy, Debug)]
+pub struct RayDifferentials {
+  pub origin_dx: Vec3,
+  pub direction_dx: Vec3,
+  pub origin_dy: Vec3,
+  pub direction_dy: Vec3
 }
 
 impl Ray {
   pub fn new(origin: Vec3, direction: Vec3) -> Ray {
-    Ray { origin, direction }
+    Ray { origin, direction, differentials: None }
   }
 
   pub fn point_at_parameter(self, t: f32) -> Vec3 {
     self.origin + t * self.direction
   }
+
+  /// The ray with its differentials scaled by `sx` in x and `sy` in y.
+  pub fn scale_differentials(self, sx: f32, sy: f32) -> Ray {
+    Ray {
+      differentials: self.differentials.map(|d| RayDifferentials {
+        origin_dx: sx * d.origin_dx,
+        direction_dx: sx * d.direction_dx,
+        origin_dy: sy * d.origin_dy,
+        direction_dy: sy * d.direction_dy
+      }),
+      ..self
+    }
+  }
 }
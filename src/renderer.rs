@@ -10,65 +10,171 @@ use self::indicatif::{ProgressBar, ProgressStyle, HumanDuration};
 use ::vec3::*;
 use ray::Ray;
 use geometry::*;
+use material::HitRecord;
 use camera::Camera;
-use scene::Scene;
-
-pub fn render(scene: &Scene, camera: &Camera, nx: usize, ny: usize, ns: usize) -> Vec<u8> {
-  println!("{}", scene.bvh);
+use scene::{Scene, LightSample};
+use framebuffer::Framebuffer;
 
+/// Renders `scene` as seen from `camera` into a `nx` by `ny` pixel image of
+/// linear radiance, taking `ns` samples per pixel.
+pub fn render(scene: &Scene, camera: &Camera, nx: usize, ny: usize, ns: usize) -> Framebuffer {
   let bar = &Box::new(ProgressBar::new((nx * ny / 64) as u64));
   bar.set_prefix("🎨  Rendering");
   bar.set_style(ProgressStyle::default_bar()
     .template("{prefix:.white} [{eta_precise}] {bar:40.cyan/blue} {percent}%"));
 
   let start = Instant::now();
+  // Texture footprints span a pixel, shrinking as more samples are averaged
+  // in it, much like PBRT does
+  let footprint = (1.0 / (ns as f32).sqrt()).max(0.125);
 
-  let pixels = (0..ny).into_par_iter().rev().flat_map(|j| (0..nx).into_par_iter().flat_map(move |i| {
+  let pixels = (0..ny).into_par_iter().rev().flat_map(|j| (0..nx).into_par_iter().map(move |i| {
     let mut col = Vec3::new(0.0, 0.0, 0.0);
     for _s in 0..ns {
       let u = ((i as f32) + rand::random::<f32>()) / (nx as f32);
       let v = ((j as f32) + rand::random::<f32>()) / (ny as f32);
 
-      let r = camera.get_ray(u, v);
-      col += color(&r, *&scene, 0);
+      let r = camera.get_ray(u, v).scale_differentials(footprint / nx as f32, footprint / ny as f32);
+      col += color(&r, *&scene, 0, None);
     }
 
     if i % 64 == 0 {
       bar.inc(1);
     }
 
-    col /= ns as f32;
-    col = Vec3::new(col[0].sqrt(), col[1].sqrt(), col[2].sqrt());
-    (0..3).into_par_iter().map(move |k| (255.99 * col[k as usize]).min(255.0) as u8)
+    col / ns as f32
   })).collect();
 
   bar.finish();
 
   println!("Finished in {}", HumanDuration(start.elapsed()));
 
-  pixels
+  Framebuffer::new(nx, ny, pixels)
 }
 
-fn color(r: &Ray, scene: &Scene, depth: i32) -> Vec3 {
+fn color(r: &Ray, scene: &Scene, depth: i32, bsdf_pdf: Option<f32>) -> Vec3 {
   let hit = scene.bvh.hit(&r, 0.001, f32::MAX);
 
   match hit {
-    Some(rec) => {
-      // println!("{:?} {:?}", depth, rec.p);
-      let emitted = rec.material.emitted(rec.u, rec.v, &rec.p);
-      if depth < scene.max_ray_depth {
-        match rec.material.scatter(&r, &rec) {
-          Some(scatter) => {
-            if let Some(bounce) = scatter.ray {
-              return emitted + scatter.color * color(&bounce, scene, depth + 1)
-            }
-          },
-          None => {}
+    Some(mut rec) => {
+      rec.compute_uv_derivatives(r);
+
+      let mut emitted = rec.material.emitted(&rec);
+      if let Some(pdf) = bsdf_pdf {
+        // This light could also have been reached by light sampling at the
+        // previous hit, so weigh the two strategies against each other
+        if rec.material.is_emissive() {
+          emitted *= power_heuristic(pdf, scene.light_pdf(&r.origin, &r.direction));
         }
       }
 
-      return emitted;
+      // Lights are sampled directly whether or not the material gives a
+      // direction to continue in. At the last bounce there is no bounce ray
+      // that could find them instead, so light samples aren't weighed then.
+      let last_bounce = depth >= scene.max_ray_depth;
+      let direct = if rec.material.is_delta() {
+        Vec3::new(0.0, 0.0, 0.0)
+      } else {
+        sample_lights(r, &rec, scene, !last_bounce) + punctual_lights(r, &rec, scene)
+      };
+
+      if !last_bounce {
+        if let Some(sample) = rec.material.sample(&r, &rec) {
+          let bounce = rec.spawn_ray(sample.direction);
+          let bsdf_pdf = if sample.is_delta { None } else { Some(sample.pdf) };
+          return emitted + direct + sample.throughput * color(&bounce, scene, depth + 1, bsdf_pdf);
+        }
+      }
+
+      return emitted + direct;
+    },
+    None => {
+      let mut color = scene.environment.color(&r);
+      if let Some(pdf) = bsdf_pdf {
+        // The environment is also sampled as a light, when it is one
+        let environment_pdf = scene.environment_pdf(&r.direction);
+        if environment_pdf > 0.0 {
+          color *= power_heuristic(pdf, environment_pdf);
+        }
+      }
+      return color;
+    }
+  }
+}
+
+// Light arriving at the hit directly from a randomly picked light and
+// reflected along the incoming ray, weighed against the material's own
+// sampling finding the light when `mis` is set.
+fn sample_lights(r: &Ray, rec: &HitRecord, scene: &Scene, mis: bool) -> Vec3 {
+  let black = Vec3::new(0.0, 0.0, 0.0);
+
+  match scene.sample_light(&rec.p) {
+    Some(LightSample::Hitable(light, direction)) => {
+      let wo = -r.direction;
+      let light_pdf = scene.light_pdf(&rec.p, &direction);
+      let bsdf_pdf = rec.material.pdf(rec, &direction, &wo);
+
+      if light_pdf <= 0.0 || bsdf_pdf <= 0.0 {
+        return black;
+      }
+
+      let shadow_ray = rec.spawn_ray(direction);
+      match light.hit(&shadow_ray, 0.001, f32::MAX) {
+        // Only check for occluders in front of the light. Another light in
+        // front of this one also counts as an occluder, which under-estimates
+        // the light in the rare case of lights overlapping each other.
+        Some(light_rec) if !scene.bvh.any_hit(&shadow_ray, 0.001, light_rec.t * (1.0 - 1e-4)) => {
+          let weight = if mis { power_heuristic(light_pdf, bsdf_pdf) } else { 1.0 };
+          let bsdf = rec.material.eval(rec, &direction, &wo);
+          light_rec.material.emitted(&light_rec) * bsdf * (weight / light_pdf)
+        },
+        _ => black
+      }
     },
-    None => return scene.environment.color(&r)
+    Some(LightSample::Environment(direction)) => {
+      let wo = -r.direction;
+      let environment_pdf = scene.environment_pdf(&direction);
+      let bsdf_pdf = rec.material.pdf(rec, &direction, &wo);
+
+      if environment_pdf <= 0.0 || bsdf_pdf <= 0.0 {
+        return black;
+      }
+
+      let shadow_ray = rec.spawn_ray(direction);
+      if scene.bvh.any_hit(&shadow_ray, 0.001, f32::MAX) {
+        return black;
+      }
+
+      let weight = if mis { power_heuristic(environment_pdf, bsdf_pdf) } else { 1.0 };
+      let bsdf = rec.material.eval(rec, &direction, &wo);
+      scene.environment.color(&shadow_ray) * bsdf * (weight / environment_pdf)
+    },
+    None => black
   }
 }
+
+// Light arriving at the hit from every punctual light that isn't shadowed,
+// reflected along the incoming ray.
+fn punctual_lights(r: &Ray, rec: &HitRecord, scene: &Scene) -> Vec3 {
+  let mut color = Vec3::new(0.0, 0.0, 0.0);
+  let wo = -r.direction;
+  for light in scene.punctual_lights.iter() {
+    if let Some(illumination) = light.illuminate(&rec.p) {
+      let bsdf = rec.material.eval(rec, &illumination.direction, &wo);
+      if bsdf.squared_length() <= 0.0 {
+        continue;
+      }
+
+      let shadow_ray = rec.spawn_ray(illumination.direction);
+      if !scene.bvh.any_hit(&shadow_ray, 0.001, illumination.distance * (1.0 - 1e-4)) {
+        color += bsdf * illumination.irradiance;
+      }
+    }
+  }
+
+  color
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+  pdf * pdf / (pdf * pdf + other_pdf * other_pdf)
+}
@@ -1,10 +1,30 @@
+extern crate rand;
+
 use vec3::*;
 use ray::Ray;
 use geometry::Hitable;
-use bvh::BvhTree;
+use bvh::{BvhTree, BvhBuildMethod};
+use light::PunctualLight;
 
-pub trait SceneEnvironment : Sync {
+/// The color seen by rays that don't hit anything in the scene.
+pub trait SceneEnvironment : Sync + Send {
   fn color(&self, r: &Ray) -> Vec3;
+
+  /// Whether the environment is sampled directly when shading, like the
+  /// scene's lights.
+  fn is_light(&self) -> bool {
+    false
+  }
+
+  /// A random direction towards the environment, for environments that are lights.
+  fn sample(&self) -> Option<Vec3> {
+    None
+  }
+
+  /// The probability density of `sample` returning `direction`.
+  fn pdf(&self, _direction: &Vec3) -> f32 {
+    0.0
+  }
 }
 
 pub struct SimpleSky { }
@@ -25,18 +45,78 @@ impl SceneEnvironment for Void {
   }
 }
 
-pub struct Scene<'a> {
-  pub bvh: BvhTree<'a>,
+/// What `Scene::sample_light` picked to sample.
+pub enum LightSample<'a> {
+  /// One of the scene's lights, with a direction towards a random point on it.
+  Hitable(&'a Box<Hitable>, Vec3),
+  /// A direction towards the environment, which is only lit if nothing is in the way.
+  Environment(Vec3)
+}
+
+/// The models to render, organized in a BVH, together with their environment
+/// and any punctual lights.
+pub struct Scene {
+  pub bvh: BvhTree,
   pub environment: Box<SceneEnvironment>,
+  /// Lights without size, which light every shading point
+  pub punctual_lights: Vec<Box<PunctualLight>>,
   pub max_ray_depth: i32
 }
 
-impl<'a> Scene<'a> {
-  pub fn new(models: &'a mut Vec<Box<Hitable>>, environment: Box<SceneEnvironment>, max_ray_depth: i32) -> Scene<'a> {
+impl Scene {
+  pub fn new(models: Vec<Box<Hitable>>, environment: Box<SceneEnvironment>, max_ray_depth: i32) -> Scene {
+    Scene::with_bvh_method(models, environment, max_ray_depth, BvhBuildMethod::RandomMedian { max_leaf_size: 1 })
+  }
+
+  pub fn with_bvh_method(models: Vec<Box<Hitable>>, environment: Box<SceneEnvironment>, max_ray_depth: i32, bvh_method: BvhBuildMethod) -> Scene {
     Scene {
-      bvh: BvhTree::new(models),
+      bvh: BvhTree::with_method(models, bvh_method),
       environment,
+      punctual_lights: Vec::new(),
       max_ray_depth
     }
   }
-}
\ No newline at end of file
+
+  pub fn with_punctual_lights(self, punctual_lights: Vec<Box<PunctualLight>>) -> Scene {
+    Scene { punctual_lights, ..self }
+  }
+
+  /// Models emitting light, which are sampled directly when shading.
+  pub fn lights(&self) -> Vec<&Box<Hitable>> {
+    self.bvh.lights()
+  }
+
+  /// The probability density of `sample_light` returning direction `v` from
+  /// `o` towards one of the lights, where every light is equally likely to be
+  /// picked.
+  pub fn light_pdf(&self, o: &Vec3, v: &Vec3) -> f32 {
+    (1.0 - self.environment_probability()) * self.bvh.pdf_value(o, v)
+  }
+
+  /// The probability density of `sample_light` returning direction `v`
+  /// towards the environment.
+  pub fn environment_pdf(&self, v: &Vec3) -> f32 {
+    let probability = self.environment_probability();
+    if probability > 0.0 { probability * self.environment.pdf(v) } else { 0.0 }
+  }
+
+  /// Picks a random light, and a direction from `o` towards a random point on
+  /// it, or a direction towards the environment when it is a light.
+  pub fn sample_light(&self, o: &Vec3) -> Option<LightSample<'_>> {
+    if rand::random::<f32>() < self.environment_probability() {
+      return self.environment.sample().map(LightSample::Environment);
+    }
+
+    self.bvh.random_light().map(|light| LightSample::Hitable(light, light.random(o)))
+  }
+
+  // How likely the environment is to be sampled rather than one of the
+  // lights, when both are there to be sampled
+  fn environment_probability(&self) -> f32 {
+    match (self.environment.is_light(), self.bvh.light_count()) {
+      (false, _) => 0.0,
+      (true, 0) => 1.0,
+      (true, _) => 0.5
+    }
+  }
+}
@@ -0,0 +1,1155 @@
+extern crate serde_json;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::vec3::Vec3;
+use ::mat44::Mat44;
+use ::camera::Camera;
+use ::geometry::*;
+use ::geometry::triangle::Triangle;
+use ::geometry::instance::Instance;
+use ::material::*;
+use ::image_texture::*;
+use ::microfacet::{Conductor, ConductorPreset, RoughDielectric};
+use ::noise_texture::{NoiseTexture, NoisePattern, ColorRamp};
+use ::scene::*;
+use ::environment_map::{EnvironmentMap, ENVIRONMENT_ENCODING};
+use ::sky::{PhysicalSky, sun_direction, solar_direction};
+use ::light::*;
+use ::bvh::{BvhTree, BvhBuildMethod};
+
+// A scene file is a JSON document along these lines:
+//
+// {
+//   "camera": { "look_from": [278, 278, -800], "look_at": [278, 278, 0], "vfov": 38 },
+//   "environment": { "type": "void" },
+//   "materials": {
+//     "white": { "type": "lambertian", "albedo": { "type": "constant", "color": [0.73, 0.73, 0.73] } }
+//   },
+//   "objects": [
+//     { "type": "sphere", "center": [0, 0, 0], "radius": 60, "material": "white" }
+//   ]
+// }
+//
+// Materials can either be referenced by name from the "materials" section,
+// or be given inline where an object expects one.
+
+/// An error loading a scene file, with the line of the file it was found on when known.
+#[derive(Debug)]
+pub struct SceneFileError {
+  pub line: Option<usize>,
+  pub message: String
+}
+
+impl SceneFileError {
+  fn new(line: Option<usize>, message: String) -> SceneFileError {
+    SceneFileError { line, message }
+  }
+}
+
+impl fmt::Display for SceneFileError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.line {
+      Some(line) => write!(f, "line {}: {}", line, self.message),
+      None => write!(f, "{}", self.message)
+    }
+  }
+}
+
+impl Error for SceneFileError { }
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+  camera: CameraDesc,
+  #[serde(default)]
+  environment: EnvironmentDesc,
+  #[serde(default)]
+  materials: HashMap<String, MaterialDesc>,
+  /// Geometry that instances refer to by name, built once however many instances there are
+  #[serde(default)]
+  geometry: HashMap<String, ObjectDesc>,
+  objects: Vec<ObjectDesc>,
+  /// Point, spot and directional lights, which aren't objects of their own
+  #[serde(default)]
+  lights: Vec<LightDesc>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+  look_from: [f32; 3],
+  look_at: [f32; 3],
+  #[serde(default = "default_vup")]
+  vup: [f32; 3],
+  vfov: f32,
+  #[serde(default)]
+  aperture: f32,
+  focus_distance: Option<f32>
+}
+
+fn default_vup() -> [f32; 3] {
+  [0.0, 1.0, 0.0]
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum EnvironmentDesc {
+  SimpleSky,
+  Void,
+  /// An equirectangular HDR image, turned `rotation` degrees about the y axis
+  Image {
+    path: String,
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32
+  },
+  /// Daylight, with the sun placed by angles or by the time of day
+  Sky {
+    sun: SunDesc,
+    #[serde(default = "default_turbidity")]
+    turbidity: f32,
+    #[serde(default = "default_ground_albedo")]
+    ground_albedo: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LightDesc {
+  Point { position: [f32; 3], intensity: [f32; 3] },
+  /// Fully lit out to `inner_angle` degrees from its axis, fading out until `angle`
+  Spot {
+    position: [f32; 3],
+    direction: [f32; 3],
+    intensity: [f32; 3],
+    angle: f32,
+    inner_angle: Option<f32>,
+    profile: Option<ProfileDesc>
+  },
+  /// Shining along `direction`, like sunlight
+  Directional { direction: [f32; 3], irradiance: [f32; 3] }
+}
+
+/// Relative intensities at angles from a spot light's axis, or an IES file to read them from
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ProfileDesc {
+  Points(Vec<(f32, f32)>),
+  Ies(String)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+enum SunDesc {
+  /// Degrees above the horizon and clockwise from north, which is -z
+  Angles { elevation: f32, azimuth: f32 },
+  /// Degrees of latitude north, day of the year and local solar time
+  Time { latitude: f32, day: f32, hour: f32 }
+}
+
+fn default_intensity() -> f32 {
+  1.0
+}
+
+fn default_turbidity() -> f32 {
+  3.0
+}
+
+fn default_ground_albedo() -> f32 {
+  0.3
+}
+
+impl Default for EnvironmentDesc {
+  fn default() -> EnvironmentDesc {
+    EnvironmentDesc::SimpleSky
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+  Constant { color: [f32; 3] },
+  Checker { odd: Box<TextureDesc>, even: Box<TextureDesc> },
+  UvChecker {
+    odd: Box<TextureDesc>,
+    even: Box<TextureDesc>,
+    #[serde(default = "default_checks")]
+    columns: f32,
+    #[serde(default = "default_checks")]
+    rows: f32
+  },
+  Image {
+    path: String,
+    #[serde(default = "default_wrap")]
+    wrap: WrapMode,
+    #[serde(default = "default_filter")]
+    filter: Filter,
+    /// Whether the image holds sRGB encoded colors, rather than linear values
+    #[serde(default = "default_srgb")]
+    srgb: bool
+  },
+  Noise {
+    pattern: NoisePattern,
+    #[serde(default = "default_noise_scale")]
+    scale: f32,
+    #[serde(default = "default_octaves")]
+    octaves: usize,
+    #[serde(default)]
+    seed: u32,
+    /// Colors at positions between 0 and 1, black to white if not given
+    ramp: Option<Vec<(f32, [f32; 3])>>
+  }
+}
+
+fn default_bump_scale() -> f32 {
+  1.0
+}
+
+fn default_ior() -> f32 {
+  1.5
+}
+
+fn default_color_distance() -> f32 {
+  1.0
+}
+
+fn default_checks() -> f32 {
+  8.0
+}
+
+fn default_noise_scale() -> f32 {
+  1.0
+}
+
+fn default_octaves() -> usize {
+  7
+}
+
+fn default_wrap() -> WrapMode {
+  WrapMode::Repeat
+}
+
+fn default_filter() -> Filter {
+  Filter::Trilinear
+}
+
+fn default_srgb() -> bool {
+  true
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+  Lambertian { albedo: TextureDesc },
+  Metal { albedo: [f32; 3], #[serde(default)] fuzz: f32 },
+  Dielectric { ref_idx: f32 },
+  /// A rough metal, either one of the measured `metal`s or with a complex index of refraction `eta` and `k`
+  Conductor {
+    metal: Option<ConductorPreset>,
+    eta: Option<[f32; 3]>,
+    k: Option<[f32; 3]>,
+    #[serde(default)]
+    roughness: f32,
+    #[serde(default)]
+    anisotropy: f32
+  },
+  /// Glass that may be frosted, tinted so that white light has turned `color` after `color_distance` inside,
+  /// or a thin sheet of the given `thickness`
+  RoughDielectric {
+    ior: f32,
+    #[serde(default)]
+    roughness: f32,
+    color: Option<[f32; 3]>,
+    #[serde(default = "default_color_distance")]
+    color_distance: f32,
+    thickness: Option<f32>
+  },
+  /// Disney's principled BSDF, each parameter a number or a texture
+  Principled {
+    base_color: TextureDesc,
+    metallic: Option<ParamDesc>,
+    roughness: Option<ParamDesc>,
+    specular: Option<ParamDesc>,
+    clearcoat: Option<ParamDesc>,
+    clearcoat_roughness: Option<ParamDesc>,
+    sheen: Option<ParamDesc>,
+    transmission: Option<ParamDesc>,
+    #[serde(default = "default_ior")]
+    ior: f32
+  },
+  /// Another material with its shading normals taken from a tangent space normal map
+  NormalMap { material: Box<MaterialRef>, map: TextureDesc },
+  /// Another material with its shading normals bent by a height map
+  BumpMap {
+    material: Box<MaterialRef>,
+    height: TextureDesc,
+    #[serde(default = "default_bump_scale")]
+    scale: f32
+  },
+  DiffuseLight { emit: TextureDesc },
+  Isotropic { albedo: TextureDesc }
+}
+
+/// A material parameter, either a plain number or a texture
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ParamDesc {
+  Value(f32),
+  Texture(TextureDesc)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum MaterialRef {
+  Named(String),
+  Inline(MaterialDesc)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDesc {
+  Translate([f32; 3]),
+  Rotate { angle: f32, axis: [f32; 3] }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDesc {
+  Sphere { center: [f32; 3], radius: f32, material: MaterialRef },
+  XyRect { x0: f32, x1: f32, y0: f32, y1: f32, k: f32, material: MaterialRef },
+  XzRect { x0: f32, x1: f32, z0: f32, z1: f32, k: f32, material: MaterialRef },
+  YzRect { y0: f32, y1: f32, z0: f32, z1: f32, k: f32, material: MaterialRef },
+  Box { min: [f32; 3], max: [f32; 3], material: MaterialRef },
+  Triangle { v0: [f32; 3], v1: [f32; 3], v2: [f32; 3], material: MaterialRef },
+  FlipNormals { object: Box<ObjectDesc> },
+  Transform { transforms: Vec<TransformDesc>, object: Box<ObjectDesc> },
+  ConstantMedium { boundary: Box<ObjectDesc>, density: f32, albedo: TextureDesc },
+  Group { objects: Vec<ObjectDesc> },
+  Obj { path: String },
+  Instance {
+    geometry: String,
+    #[serde(default)]
+    transforms: Vec<TransformDesc>,
+    #[serde(default)]
+    material: Option<MaterialRef>
+  }
+}
+
+// How BVHs are built for groups, models and geometry nested inside other objects
+const NESTED_BVH: BvhBuildMethod = BvhBuildMethod::Sah { bins: 16, max_leaf_size: 4 };
+
+/// Everything needed to render a scene loaded from a scene file,
+/// except for the image dimensions which are given when creating the camera.
+pub struct SceneDescription {
+  pub models: Vec<Box<Hitable>>,
+  pub environment: Box<SceneEnvironment>,
+  pub lights: Vec<Box<PunctualLight>>,
+  camera: CameraDesc
+}
+
+impl SceneDescription {
+  pub fn camera(&self, aspect: f32) -> Camera {
+    let lookfrom = vec3(self.camera.look_from);
+    let lookat = vec3(self.camera.look_at);
+    let dist_to_focus = self.camera.focus_distance.unwrap_or_else(|| (lookfrom - lookat).length());
+
+    Camera::new(
+      lookfrom,
+      lookat,
+      vec3(self.camera.vup),
+      self.camera.vfov,
+      aspect,
+      self.camera.aperture,
+      dist_to_focus)
+  }
+}
+
+/// Reads a JSON scene description file. Paths in the file, like OBJ models,
+/// are relative to the file's directory.
+pub fn load_scene_file(path: &Path) -> Result<SceneDescription, SceneFileError> {
+  let mut source = String::new();
+  File::open(path)
+    .and_then(|mut f| f.read_to_string(&mut source))
+    .map_err(|e| SceneFileError::new(None, format!("could not read {}: {}", path.display(), e)))?;
+
+  let base_dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+  parse_scene(&source, &base_dir)
+}
+
+/// Parses a JSON scene description, resolving relative paths from `base_dir`.
+pub fn parse_scene(source: &str, base_dir: &Path) -> Result<SceneDescription, SceneFileError> {
+  let desc: SceneDesc = serde_json::from_str(source)
+    .map_err(|e| SceneFileError::new(Some(e.line()), strip_position(&e.to_string())))?;
+
+  let mut builder = Builder {
+    lines: value_lines(source),
+    base_dir: base_dir.to_path_buf(),
+    named_materials: HashMap::new(),
+    named_geometry: HashMap::new(),
+    textures: TextureCache::new()
+  };
+
+  // Normal and bump maps may be built on other named materials, which are built first
+  for name in builder.dependency_order("/materials", "material", &desc.materials, material_references)? {
+    let built = builder.material(&desc.materials[&name], &child("/materials", &name))?;
+    builder.named_materials.insert(name, built);
+  }
+
+  // Geometry may place instances of other geometry, which is built first
+  for name in builder.dependency_order("/geometry", "geometry", &desc.geometry, geometry_references)? {
+    let built = Arc::new(BvhTree::with_method(builder.parts(&desc.geometry[&name], &child("/geometry", &name))?, NESTED_BVH));
+    builder.named_geometry.insert(name, built);
+  }
+
+  let mut models: Vec<Box<Hitable>> = Vec::new();
+  for (i, object) in desc.objects.iter().enumerate() {
+    // Groups, boxes and OBJ models at the top level are flattened, so that
+    // their parts end up as separate leaves in the BVH.
+    models.append(&mut builder.objects(object, &child("/objects", &i.to_string()))?);
+  }
+
+  let environment: Box<SceneEnvironment> = match desc.environment {
+    EnvironmentDesc::SimpleSky => Box::new(SimpleSky {}),
+    EnvironmentDesc::Void => Box::new(Void {}),
+    EnvironmentDesc::Image { ref path, rotation, intensity } => {
+      let image_path = builder.base_dir.join(path);
+      let image = builder.textures.load(&image_path, ENVIRONMENT_ENCODING).map_err(|e| SceneFileError::new(
+        builder.line_at("/environment/path"),
+        format!("could not load environment {}: {}", image_path.display(), e)))?;
+      Box::new(EnvironmentMap::new(image).with_rotation(rotation).with_intensity(intensity))
+    },
+    EnvironmentDesc::Sky { ref sun, turbidity, ground_albedo, intensity } => {
+      let sun = match *sun {
+        SunDesc::Angles { elevation, azimuth } => sun_direction(elevation, azimuth),
+        SunDesc::Time { latitude, day, hour } => solar_direction(latitude, day, hour)
+      };
+      Box::new(PhysicalSky::new(sun, turbidity, ground_albedo).with_intensity(intensity))
+    }
+  };
+
+  let mut lights: Vec<Box<PunctualLight>> = Vec::new();
+  for (i, light) in desc.lights.iter().enumerate() {
+    lights.push(builder.light(light, &child("/lights", &i.to_string()))?);
+  }
+
+  Ok(SceneDescription { models, environment, lights, camera: desc.camera })
+}
+
+// Builds the scene from its description. Each part is built along with its
+// JSON pointer in the file, like "/objects/2/material", to find the line of
+// anything that goes wrong.
+struct Builder {
+  lines: HashMap<String, usize>,
+  base_dir: PathBuf,
+  named_materials: HashMap<String, Arc<Material>>,
+  named_geometry: HashMap<String, Arc<BvhTree>>,
+  textures: TextureCache
+}
+
+impl Builder {
+  fn texture(&self, desc: &TextureDesc, at: &str) -> Result<Box<Texture>, SceneFileError> {
+    Ok(match *desc {
+      TextureDesc::Constant { color } => Box::new(ConstantTexture::new(color[0], color[1], color[2])),
+      TextureDesc::Checker { ref odd, ref even } =>
+        Box::new(CheckerTexture { odd: self.texture(odd, &child(at, "odd"))?, even: self.texture(even, &child(at, "even"))? }),
+      TextureDesc::UvChecker { ref odd, ref even, columns, rows } =>
+        Box::new(UvCheckerTexture::new(self.texture(odd, &child(at, "odd"))?, self.texture(even, &child(at, "even"))?, columns, rows)),
+      TextureDesc::Image { ref path, wrap, filter, srgb } => {
+        let image_path = self.base_dir.join(path);
+        let encoding = if srgb { ImageEncoding::Srgb } else { ImageEncoding::Linear };
+        let image = self.textures.load(&image_path, encoding).map_err(|e| SceneFileError::new(
+          self.line_at(&child(at, "path")),
+          format!("could not load image {}: {}", image_path.display(), e)))?;
+        Box::new(ImageTexture::new(image).with_wrap(wrap).with_filter(filter))
+      },
+      TextureDesc::Noise { pattern, scale, octaves, seed, ref ramp } => {
+        let texture = NoiseTexture::new(pattern, scale).with_octaves(octaves).with_seed(seed);
+        match *ramp {
+          Some(ref stops) if !stops.is_empty() =>
+            Box::new(texture.with_ramp(ColorRamp::new(stops.iter().map(|&(t, c)| (t, vec3(c))).collect()))),
+          _ => Box::new(texture)
+        }
+      }
+    })
+  }
+
+  fn light(&self, desc: &LightDesc, at: &str) -> Result<Box<PunctualLight>, SceneFileError> {
+    Ok(match *desc {
+      LightDesc::Point { position, intensity } => Box::new(PointLight { position: vec3(position), intensity: vec3(intensity) }),
+      LightDesc::Spot { position, direction, intensity, angle, inner_angle, ref profile } => {
+        let mut light = SpotLight::new(vec3(position), vec3(direction), vec3(intensity), angle)
+          .with_falloff(inner_angle.unwrap_or(angle), angle);
+        match *profile {
+          Some(ProfileDesc::Points(ref points)) => light = light.with_profile(LightProfile::new(points.clone())),
+          Some(ProfileDesc::Ies(ref path)) => {
+            let ies_path = self.base_dir.join(path);
+            let profile = LightProfile::load_ies(&ies_path).map_err(|e| SceneFileError::new(
+              self.line_at(&child(at, "profile")),
+              format!("could not load light profile {}: {}", ies_path.display(), e)))?;
+            light = light.with_profile(profile);
+          },
+          None => ()
+        }
+        Box::new(light)
+      },
+      LightDesc::Directional { direction, irradiance } => Box::new(DirectionalLight::new(vec3(direction), vec3(irradiance)))
+    })
+  }
+
+  fn param(&self, desc: &ParamDesc, at: &str) -> Result<Box<Texture>, SceneFileError> {
+    match *desc {
+      ParamDesc::Value(v) => Ok(Box::new(ConstantTexture::new(v, v, v))),
+      ParamDesc::Texture(ref texture) => self.texture(texture, at)
+    }
+  }
+
+  fn material(&self, desc: &MaterialDesc, at: &str) -> Result<Arc<Material>, SceneFileError> {
+    Ok(match *desc {
+      MaterialDesc::Lambertian { ref albedo } => Arc::new(Lambertian { albedo: self.texture(albedo, &child(at, "albedo"))? }),
+      MaterialDesc::Metal { albedo, fuzz } => Arc::new(Metal { albedo: Box::new(ConstantTexture::new(albedo[0], albedo[1], albedo[2])), fuzz }),
+      MaterialDesc::Dielectric { ref_idx } => Arc::new(Dielectric { ref_idx }),
+      MaterialDesc::Conductor { metal, eta, k, roughness, anisotropy } => {
+        let conductor = match (metal, eta, k) {
+          (Some(metal), None, None) => Conductor::preset(metal, roughness),
+          (None, Some(eta), Some(k)) => Conductor::new(vec3(eta), vec3(k), roughness),
+          _ => return Err(SceneFileError::new(
+            self.line_at(at),
+            "conductor needs either a metal, or both eta and k".to_string()))
+        };
+        Arc::new(conductor.with_anisotropy(anisotropy))
+      },
+      MaterialDesc::RoughDielectric { ior, roughness, color, color_distance, thickness } => {
+        let mut glass = RoughDielectric::new(ior, roughness);
+        if let Some(color) = color {
+          glass = glass.with_color(vec3(color), color_distance);
+        }
+        if let Some(thickness) = thickness {
+          glass = glass.with_thickness(thickness);
+        }
+        Arc::new(glass)
+      },
+      MaterialDesc::Principled {
+        ref base_color, ref metallic, ref roughness, ref specular, ref clearcoat,
+        ref clearcoat_roughness, ref sheen, ref transmission, ior
+      } => {
+        let mut principled = Principled::new(self.texture(base_color, &child(at, "base_color"))?);
+        principled.ior = ior;
+        if let Some(ref p) = *metallic { principled.metallic = self.param(p, &child(at, "metallic"))?; }
+        if let Some(ref p) = *roughness { principled.roughness = self.param(p, &child(at, "roughness"))?; }
+        if let Some(ref p) = *specular { principled.specular = self.param(p, &child(at, "specular"))?; }
+        if let Some(ref p) = *clearcoat { principled.clearcoat = self.param(p, &child(at, "clearcoat"))?; }
+        if let Some(ref p) = *clearcoat_roughness { principled.clearcoat_roughness = self.param(p, &child(at, "clearcoat_roughness"))?; }
+        if let Some(ref p) = *sheen { principled.sheen = self.param(p, &child(at, "sheen"))?; }
+        if let Some(ref p) = *transmission { principled.transmission = self.param(p, &child(at, "transmission"))?; }
+        Arc::new(principled)
+      },
+      MaterialDesc::NormalMap { ref material, ref map } =>
+        Arc::new(NormalMap::tangent_space(self.material_ref(material, &child(at, "material"))?, self.texture(map, &child(at, "map"))?)),
+      MaterialDesc::BumpMap { ref material, ref height, scale } =>
+        Arc::new(NormalMap::bump(self.material_ref(material, &child(at, "material"))?, self.texture(height, &child(at, "height"))?, scale)),
+      MaterialDesc::DiffuseLight { ref emit } => Arc::new(DiffuseLight { emit: self.texture(emit, &child(at, "emit"))? }),
+      MaterialDesc::Isotropic { ref albedo } => Arc::new(Isotropic { albedo: self.texture(albedo, &child(at, "albedo"))? })
+    })
+  }
+
+  fn material_ref(&self, material: &MaterialRef, at: &str) -> Result<Arc<Material>, SceneFileError> {
+    match *material {
+      MaterialRef::Named(ref name) => match self.named_materials.get(name) {
+        Some(m) => Ok(Arc::clone(m)),
+        None => Err(SceneFileError::new(
+          self.line_at(at),
+          format!("unknown material \"{}\"", name)))
+      },
+      MaterialRef::Inline(ref desc) => self.material(desc, at)
+    }
+  }
+
+  fn objects(&self, desc: &ObjectDesc, at: &str) -> Result<Vec<Box<Hitable>>, SceneFileError> {
+    match *desc {
+      ObjectDesc::Box { min, max, ref material } =>
+        Ok(new_box(vec3(min), vec3(max), self.material_ref(material, &child(at, "material"))?)),
+      ObjectDesc::Group { ref objects } => {
+        let mut hitables: Vec<Box<Hitable>> = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+          hitables.append(&mut self.objects(object, &child(&child(at, "objects"), &i.to_string()))?);
+        }
+        Ok(hitables)
+      },
+      ObjectDesc::Obj { ref path } => {
+        let obj_path = self.base_dir.join(path);
+        obj_to_hitable_with_cache(&obj_path, &self.textures).map_err(|e| SceneFileError::new(
+          self.line_at(&child(at, "path")),
+          format!("could not load OBJ model {}: {}", obj_path.display(), e)))
+      },
+      _ => Ok(vec![self.object(desc, at)?])
+    }
+  }
+
+  // The parts of a group or model that's placed as a single object, which
+  // needs something in it to have a bounding box
+  fn parts(&self, desc: &ObjectDesc, at: &str) -> Result<Vec<Box<Hitable>>, SceneFileError> {
+    let parts = self.objects(desc, at)?;
+    if parts.is_empty() {
+      return Err(SceneFileError::new(self.line_at(at), "nothing in this group or model to place".to_string()));
+    }
+    Ok(parts)
+  }
+
+  fn object(&self, desc: &ObjectDesc, at: &str) -> Result<Box<Hitable>, SceneFileError> {
+    Ok(match *desc {
+      ObjectDesc::Sphere { center, radius, ref material } =>
+        Box::new(Sphere { center: vec3(center), radius, material: self.material_ref(material, &child(at, "material"))? }),
+      ObjectDesc::XyRect { x0, x1, y0, y1, k, ref material } =>
+        Box::new(XyRect { x0, x1, y0, y1, k, material: self.material_ref(material, &child(at, "material"))? }),
+      ObjectDesc::XzRect { x0, x1, z0, z1, k, ref material } =>
+        Box::new(XzRect { x0, x1, z0, z1, k, material: self.material_ref(material, &child(at, "material"))? }),
+      ObjectDesc::YzRect { y0, y1, z0, z1, k, ref material } =>
+        Box::new(YzRect { y0, y1, z0, z1, k, material: self.material_ref(material, &child(at, "material"))? }),
+      ObjectDesc::Triangle { v0, v1, v2, ref material } =>
+        Box::new(Triangle::new(vec3(v0), vec3(v1), vec3(v2), self.material_ref(material, &child(at, "material"))?)),
+      ObjectDesc::FlipNormals { ref object } =>
+        Box::new(FlipNormals { hitable: self.object(object, &child(at, "object"))? }),
+      ObjectDesc::Transform { ref transforms, ref object } =>
+        Box::new(Transform::new(self.object(object, &child(at, "object"))?, transform(transforms))),
+      ObjectDesc::ConstantMedium { ref boundary, density, ref albedo } =>
+        Box::new(ConstantMedium::new(self.object(boundary, &child(at, "boundary"))?, density, self.texture(albedo, &child(at, "albedo"))?)),
+      ObjectDesc::Instance { ref geometry, ref transforms, ref material } => {
+        let shared = match self.named_geometry.get(geometry) {
+          Some(g) => Arc::clone(g),
+          None => return Err(SceneFileError::new(
+            self.line_at(&child(at, "geometry")),
+            format!("unknown geometry \"{}\"", geometry)))
+        };
+        match *material {
+          Some(ref material) =>
+            Box::new(Instance::with_material(shared, transform(transforms), self.material_ref(material, &child(at, "material"))?)),
+          None => Box::new(Instance::new(shared, transform(transforms)))
+        }
+      },
+      ObjectDesc::Box { .. } | ObjectDesc::Group { .. } | ObjectDesc::Obj { .. } => {
+        // Nested below the top level, the parts get a BVH of their own
+        Box::new(BvhTree::with_method(self.parts(desc, at)?, NESTED_BVH))
+      }
+    })
+  }
+
+  fn line_at(&self, at: &str) -> Option<usize> {
+    self.lines.get(at).cloned()
+  }
+
+  // Orders the names of the `definitions` in `section` so that each comes
+  // after the ones it refers to, as listed by `references`, and otherwise in
+  // the order of the file
+  fn dependency_order<T, F>(&self, section: &str, kind: &str, definitions: &HashMap<String, T>, references: F)
+    -> Result<Vec<String>, SceneFileError> where F: Fn(&T, &mut Vec<String>) {
+    let mut names: Vec<&String> = definitions.keys().collect();
+    names.sort_by_key(|&name| (self.line_at(&child(section, name)), name));
+
+    let mut order = Vec::new();
+    let mut done = HashSet::new();
+    for name in names {
+      visit_dependencies(name, definitions, &references, &mut vec![], &mut done, &mut order).map_err(|name| SceneFileError::new(
+        self.line_at(&child(section, &name)),
+        format!("{} \"{}\" refers back to itself", kind, name)))?;
+    }
+    Ok(order)
+  }
+}
+
+// Adds `name` to `order` after everything it refers to, or gives back a
+// name found to refer back to itself
+fn visit_dependencies<T, F>(name: &str, definitions: &HashMap<String, T>, references: &F,
+  visiting: &mut Vec<String>, done: &mut HashSet<String>, order: &mut Vec<String>) -> Result<(), String>
+  where F: Fn(&T, &mut Vec<String>) {
+  if done.contains(name) {
+    return Ok(());
+  }
+  if visiting.iter().any(|n| n == name) {
+    return Err(name.to_string());
+  }
+  // Unknown names are reported when the definition is built
+  let definition = match definitions.get(name) {
+    Some(definition) => definition,
+    None => return Ok(())
+  };
+
+  visiting.push(name.to_string());
+  let mut names = Vec::new();
+  references(definition, &mut names);
+  for referenced in names.iter() {
+    visit_dependencies(referenced, definitions, references, visiting, done, order)?;
+  }
+  visiting.pop();
+
+  done.insert(name.to_string());
+  order.push(name.to_string());
+  Ok(())
+}
+
+// Named materials that `desc` is built on
+fn material_references(desc: &MaterialDesc, names: &mut Vec<String>) {
+  match *desc {
+    MaterialDesc::NormalMap { ref material, .. } | MaterialDesc::BumpMap { ref material, .. } => match **material {
+      MaterialRef::Named(ref name) => names.push(name.clone()),
+      MaterialRef::Inline(ref inner) => material_references(inner, names)
+    },
+    _ => ()
+  }
+}
+
+// Named geometry that `desc` places instances of
+fn geometry_references(desc: &ObjectDesc, names: &mut Vec<String>) {
+  match *desc {
+    ObjectDesc::Instance { ref geometry, .. } => names.push(geometry.clone()),
+    ObjectDesc::Group { ref objects } => for object in objects.iter() {
+      geometry_references(object, names);
+    },
+    ObjectDesc::FlipNormals { ref object } | ObjectDesc::Transform { ref object, .. } => geometry_references(object, names),
+    ObjectDesc::ConstantMedium { ref boundary, .. } => geometry_references(boundary, names),
+    _ => ()
+  }
+}
+
+// The JSON pointer of `key`, a field or index, inside the value at `at`
+fn child(at: &str, key: &str) -> String {
+  format!("{}/{}", at, key.replace('~', "~0").replace('/', "~1"))
+}
+
+// The line each value of a JSON document starts on, by JSON pointer. The
+// document must already have been parsed successfully.
+fn value_lines(source: &str) -> HashMap<String, usize> {
+  let mut scanner = LineScanner { bytes: source.as_bytes(), pos: 0, line: 1, lines: HashMap::new() };
+  scanner.value(String::new());
+  scanner.lines
+}
+
+struct LineScanner<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  line: usize,
+  lines: HashMap<String, usize>
+}
+
+impl<'a> LineScanner<'a> {
+  fn value(&mut self, at: String) {
+    self.skip_whitespace();
+    self.lines.insert(at.clone(), self.line);
+
+    match self.peek() {
+      Some(b'{') => {
+        self.pos += 1;
+        while self.next_item(b'}') {
+          let key = self.string();
+          self.skip_whitespace();
+          // Past the colon
+          self.pos += 1;
+          self.value(child(&at, &key));
+        }
+      },
+      Some(b'[') => {
+        self.pos += 1;
+        let mut i = 0;
+        while self.next_item(b']') {
+          self.value(child(&at, &i.to_string()));
+          i += 1;
+        }
+      },
+      Some(b'"') => { self.string(); },
+      _ => {
+        while self.peek().is_some_and(|b| !(b.is_ascii_whitespace() || b == b',' || b == b'}' || b == b']')) {
+          self.pos += 1;
+        }
+      }
+    }
+  }
+
+  // Moves past a separating comma, telling whether there is another item
+  // before the `end` of the object or array
+  fn next_item(&mut self, end: u8) -> bool {
+    self.skip_whitespace();
+    match self.peek() {
+      Some(b',') => {
+        self.pos += 1;
+        self.skip_whitespace();
+        true
+      },
+      Some(b) if b == end => {
+        self.pos += 1;
+        false
+      },
+      Some(_) => true,
+      None => false
+    }
+  }
+
+  fn string(&mut self) -> String {
+    let start = self.pos;
+    self.pos += 1;
+    while let Some(b) = self.peek() {
+      self.pos += if b == b'\\' { 2 } else { 1 };
+      if b == b'"' {
+        break;
+      }
+    }
+    let quoted = String::from_utf8_lossy(&self.bytes[start..self.pos.min(self.bytes.len())]);
+    serde_json::from_str(&quoted).unwrap_or_default()
+  }
+
+  fn skip_whitespace(&mut self) {
+    while let Some(b) = self.peek() {
+      if !b.is_ascii_whitespace() {
+        break;
+      }
+      if b == b'\n' {
+        self.line += 1;
+      }
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.bytes.get(self.pos).cloned()
+  }
+}
+
+// Transforms are multiplied in the order given, just like
+// Mat44::translate(...) * Mat44::rotate(...) would be in code.
+fn transform(transforms: &[TransformDesc]) -> Mat44 {
+  transforms.iter().fold(Mat44::identity(), |m, t| m * match *t {
+    TransformDesc::Translate(d) => Mat44::translate(vec3(d)),
+    TransformDesc::Rotate { angle, axis } => Mat44::rotate(angle, vec3(axis))
+  })
+}
+
+fn vec3(v: [f32; 3]) -> Vec3 {
+  Vec3::new(v[0], v[1], v[2])
+}
+
+// serde_json appends " at line X column Y" to its messages; since the line
+// is reported separately, drop it from the message.
+fn strip_position(message: &str) -> String {
+  match message.rfind(" at line ") {
+    Some(i) => message[..i].to_string(),
+    None => message.to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f32;
+  use ::ray::Ray;
+
+  const CORNELL_LIKE: &str = r#"{
+  "camera": { "look_from": [278, 278, -800], "look_at": [278, 278, 0], "vfov": 38 },
+  "environment": { "type": "void" },
+  "materials": {
+    "white": { "type": "lambertian", "albedo": { "type": "constant", "color": [0.73, 0.73, 0.73] } }
+  },
+  "objects": [
+    { "type": "box", "min": [0, 0, 0], "max": [165, 165, 165], "material": "white" },
+    { "type": "transform",
+      "transforms": [{ "translate": [130, 0, 65] }, { "rotate": { "angle": -18, "axis": [0, 1, 0] } }],
+      "object": { "type": "sphere", "center": [0, 0, 0], "radius": 60, "material": { "type": "dielectric", "ref_idx": 1.5 } } }
+  ]
+}"#;
+
+  #[test]
+  fn parses_scene() {
+    let scene = parse_scene(CORNELL_LIKE, Path::new(".")).unwrap();
+
+    // The box is flattened into its six sides
+    assert_eq!(7, scene.models.len());
+  }
+
+  #[test]
+  fn parses_noise_textures() {
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "objects": [
+    { "type": "sphere", "center": [0, 0, -1], "radius": 0.5, "material": { "type": "lambertian",
+      "albedo": { "type": "noise", "pattern": "marble", "scale": 4, "ramp": [[0, [0.1, 0.1, 0.1]], [1, [0.9, 0.9, 0.8]]] } } },
+    { "type": "constant_medium", "density": 100,
+      "boundary": { "type": "sphere", "center": [0, 0, -3], "radius": 1, "material": { "type": "dielectric", "ref_idx": 1.0 } },
+      "albedo": { "type": "noise", "pattern": "turbulence", "octaves": 4 } }
+  ]
+}"#;
+    let scene = parse_scene(source, Path::new(".")).unwrap();
+    let ray = Ray::new(Vec3::new(0.1, 0.2, 0.0), Vec3::new(0.0, 0.0, -1.0));
+    let assert_same = |expected: Vec3, actual: Vec3| assert!((expected - actual).length() < 1e-4, "expected {:?}, got {:?}", expected, actual);
+
+    // Lambertian reflection straight back along the normal is the albedo over pi
+    let rec = scene.models[0].hit(&ray, 0.001, f32::MAX).unwrap();
+    let albedo = f32::consts::PI * rec.material.eval(&rec, &rec.normal, &rec.normal);
+    let marble = NoiseTexture::new(NoisePattern::Marble, 4.0)
+      .with_ramp(ColorRamp::new(vec![(0.0, Vec3::new(0.1, 0.1, 0.1)), (1.0, Vec3::new(0.9, 0.9, 0.8))]));
+    assert_same(marble.filtered_value(&rec), albedo);
+    assert!((albedo - NoiseTexture::new(NoisePattern::Marble, 4.0).filtered_value(&rec)).length() > 1e-3);
+
+    // The medium scatters equally in all directions, with the albedo spread over the sphere
+    let rec = scene.models[1].hit(&ray, 0.001, f32::MAX).unwrap();
+    let albedo = 4.0 * f32::consts::PI * rec.material.eval(&rec, &ray.direction, &-ray.direction);
+    let turbulence = NoiseTexture::new(NoisePattern::Turbulence, 1.0);
+    assert_same(turbulence.with_octaves(4).filtered_value(&rec), albedo);
+    assert!((albedo - NoiseTexture::new(NoisePattern::Turbulence, 1.0).filtered_value(&rec)).length() > 1e-4);
+  }
+
+  #[test]
+  fn parses_sky_with_sun_by_angles_or_time() {
+    let scene = |sun: &str| format!(r#"{{
+  "camera": {{ "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 }},
+  "environment": {{ "type": "sky", "sun": {}, "turbidity": 2.5 }},
+  "objects": []
+}}"#, sun);
+
+    let by_angles = parse_scene(&scene(r#"{ "elevation": 30, "azimuth": 90 }"#), Path::new(".")).unwrap();
+    assert!(by_angles.environment.is_light());
+    let by_time = parse_scene(&scene(r#"{ "latitude": 40, "day": 100, "hour": 16 }"#), Path::new(".")).unwrap();
+    assert!(by_time.environment.is_light());
+
+    assert!(parse_scene(&scene(r#"{ "elevation": 30 }"#), Path::new(".")).is_err());
+  }
+
+  #[test]
+  fn reads_png_environments_as_srgb() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("rt_in_1_week_png_environment_{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    ::framebuffer::write_rgb8_png(dir.join("gray.png"), 2, 1, &[188; 6]).unwrap();
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "environment": { "type": "image", "path": "gray.png" },
+  "objects": []
+}"#;
+    let scene = parse_scene(source, &dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // The same as EnvironmentMap::load gives, 188 being about half in linear light
+    let color = scene.unwrap().environment.color(&Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.3, 0.5, -1.0)));
+    assert!((color.x() - 0.5029).abs() < 1e-3, "{:?}", color);
+  }
+
+  #[test]
+  fn parses_bundled_cornell_scene() {
+    let scene = parse_scene(include_str!("../scenes/cornell.json"), Path::new("scenes")).unwrap();
+
+    assert_eq!(13, scene.models.len());
+  }
+
+  #[test]
+  fn parses_instances_of_shared_geometry() {
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "geometry": {
+    "pair": { "type": "group", "objects": [
+      { "type": "sphere", "center": [0, 0, 0], "radius": 0.5, "material": { "type": "metal", "albedo": [0.8, 0.8, 0.8] } },
+      { "type": "sphere", "center": [1, 0, 0], "radius": 0.5, "material": { "type": "metal", "albedo": [0.8, 0.8, 0.8] } }
+    ] }
+  },
+  "objects": [
+    { "type": "instance", "geometry": "pair", "transforms": [{ "translate": [0, 0, -2] }] },
+    { "type": "instance", "geometry": "pair", "transforms": [{ "translate": [0, 0, -4] }],
+      "material": { "type": "dielectric", "ref_idx": 1.5 } }
+  ]
+}"#;
+    let scene = parse_scene(source, Path::new(".")).unwrap();
+
+    assert_eq!(2, scene.models.len());
+    let hit = scene.models.hit(&Ray::new(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+    assert!((hit.t - 1.5).abs() < 1e-4);
+  }
+
+  #[test]
+  fn reports_empty_nested_groups() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"objects\": [\n    { \"type\": \"group\", \"objects\": [] },\n    { \"type\": \"flip_normals\", \"object\":\n      { \"type\": \"group\", \"objects\": [] } }\n  ]\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+
+    // Empty at the top level is fine, there's just nothing there
+    assert_eq!("line 6: nothing in this group or model to place", error.to_string());
+  }
+
+  #[test]
+  fn builds_geometry_after_the_geometry_it_places() {
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "geometry": {
+    "a_row": { "type": "group", "objects": [
+      { "type": "instance", "geometry": "z_ball" },
+      { "type": "transform", "transforms": [{ "translate": [2, 0, 0] }],
+        "object": { "type": "instance", "geometry": "z_ball" } }
+    ] },
+    "z_ball": { "type": "sphere", "center": [0, 0, 0], "radius": 0.5, "material": { "type": "metal", "albedo": [0.8, 0.8, 0.8] } }
+  },
+  "objects": [
+    { "type": "instance", "geometry": "a_row", "transforms": [{ "translate": [0, 0, -2] }] }
+  ]
+}"#;
+    let scene = parse_scene(source, Path::new(".")).unwrap();
+    let hit = scene.models.hit(&Ray::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::MAX).unwrap();
+    assert!((hit.t - 1.5).abs() < 1e-4);
+
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"geometry\": {\n    \"a\": { \"type\": \"instance\", \"geometry\": \"b\" },\n    \"b\": { \"type\": \"group\", \"objects\": [{ \"type\": \"instance\", \"geometry\": \"a\" }] }\n  },\n  \"objects\": []\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+    assert_eq!("line 4: geometry \"a\" refers back to itself", error.to_string());
+  }
+
+  #[test]
+  fn reports_unknown_geometry_line() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"objects\": [\n    { \"type\": \"instance\", \"geometry\": \"teapot\" }\n  ]\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+
+    assert_eq!("line 4: unknown geometry \"teapot\"", error.to_string());
+  }
+
+  #[test]
+  fn reports_missing_image_line() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"materials\": {\n    \"wood\": { \"type\": \"lambertian\", \"albedo\": { \"type\": \"image\", \"path\": \"missing.png\", \"wrap\": \"mirror\" } }\n  },\n  \"objects\": []\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+
+    assert_eq!(Some(4), error.line);
+    assert!(error.message.starts_with("could not load image"));
+  }
+
+  #[test]
+  fn reports_unloadable_obj_line() {
+    use std::env;
+    use std::fs;
+
+    let source = |path: &str| format!("{{\n  \"camera\": {{ \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 }},\n  \"objects\": [\n    {{ \"type\": \"obj\", \"path\": \"{}\" }}\n  ]\n}}", path);
+    let error = parse_scene(&source("missing.obj"), Path::new(".")).err().unwrap();
+    assert_eq!(Some(4), error.line);
+    assert!(error.message.starts_with("could not load OBJ model"));
+
+    let dir = env::temp_dir().join(format!("rt_in_1_week_bad_obj_{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("bad.obj"), "v 0 0 zero\nf 1 1 1\n").unwrap();
+    let error = parse_scene(&source("bad.obj"), &dir).err();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(Some(4), error.unwrap().line);
+  }
+
+  #[test]
+  fn parses_principled_parameters_as_numbers_or_textures() {
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "materials": {
+    "car_paint": { "type": "principled", "base_color": { "type": "constant", "color": [0.6, 0.05, 0.05] },
+      "metallic": 0.2, "roughness": { "type": "noise", "pattern": "fbm", "scale": 10 }, "clearcoat": 1 }
+  },
+  "objects": [
+    { "type": "sphere", "center": [0, 0, -1], "radius": 0.5, "material": "car_paint" }
+  ]
+}"#;
+    let scene = parse_scene(source, Path::new(".")).unwrap();
+
+    assert_eq!(1, scene.models.len());
+  }
+
+  #[test]
+  fn builds_materials_after_the_materials_they_wrap() {
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "materials": {
+    "a_bumpy": { "type": "bump_map", "material": "m_tilted",
+      "height": { "type": "noise", "pattern": "fbm", "scale": 20 }, "scale": 0.5 },
+    "m_tilted": { "type": "normal_map", "material": "z_base", "map": { "type": "constant", "color": [0.6, 0.5, 0.8] } },
+    "z_base": { "type": "lambertian", "albedo": { "type": "constant", "color": [0.5, 0.5, 0.5] } }
+  },
+  "objects": [
+    { "type": "sphere", "center": [0, 0, -1], "radius": 0.5, "material": "a_bumpy" }
+  ]
+}"#;
+    let scene = parse_scene(source, Path::new(".")).unwrap();
+    assert_eq!(1, scene.models.len());
+
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"materials\": {\n    \"a\": { \"type\": \"normal_map\", \"material\": \"b\", \"map\": { \"type\": \"constant\", \"color\": [0.5, 0.5, 1] } },\n    \"b\": { \"type\": \"bump_map\", \"material\": { \"type\": \"bump_map\", \"material\": \"a\",\n      \"height\": { \"type\": \"constant\", \"color\": [0, 0, 0] } }, \"height\": { \"type\": \"constant\", \"color\": [0, 0, 0] } }\n  },\n  \"objects\": []\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+    assert_eq!("line 4: material \"a\" refers back to itself", error.to_string());
+  }
+
+  #[test]
+  fn reports_conductor_without_ior() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"materials\": {\n    \"odd\": { \"type\": \"conductor\", \"metal\": \"gold\", \"eta\": [1, 1, 1] }\n  },\n  \"objects\": []\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+
+    assert_eq!("line 4: conductor needs either a metal, or both eta and k", error.to_string());
+  }
+
+  #[test]
+  fn reports_the_line_of_the_bad_one_of_many() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"materials\": {\n    \"gold\": { \"type\": \"conductor\", \"metal\": \"gold\" },\n    \"odd\": { \"type\": \"conductor\", \"eta\": [1, 1, 1] }\n  },\n  \"objects\": []\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+    assert_eq!(Some(5), error.line);
+
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"objects\": [\n    { \"type\": \"sphere\", \"center\": [0, 0, -1], \"radius\": 0.5, \"material\": { \"type\": \"dielectric\", \"ref_idx\": 1.5 } },\n    { \"type\": \"sphere\", \"center\": [0, 0, -2], \"radius\": 0.5,\n      \"material\": \"dielectric\" }\n  ]\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+    assert_eq!("line 6: unknown material \"dielectric\"", error.to_string());
+  }
+
+  #[test]
+  fn finds_lines_of_values_by_pointer() {
+    let lines = value_lines("{\n  \"a/b\": [1,\n    { \"c\": \"x\\\"y\" },\n    [] ],\n  \"d\":\n    true\n}");
+
+    assert_eq!(Some(&1), lines.get(""));
+    assert_eq!(Some(&2), lines.get("/a~1b"));
+    assert_eq!(Some(&2), lines.get("/a~1b/0"));
+    assert_eq!(Some(&3), lines.get("/a~1b/1/c"));
+    assert_eq!(Some(&4), lines.get("/a~1b/2"));
+    assert_eq!(Some(&6), lines.get("/d"));
+  }
+
+  #[test]
+  fn parses_punctual_lights() {
+    let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "objects": [],
+  "lights": [
+    { "type": "point", "position": [0, 2, 0], "intensity": [10, 10, 10] },
+    { "type": "spot", "position": [0, 2, 0], "direction": [0, -1, 0], "intensity": [5, 5, 5],
+      "angle": 40, "inner_angle": 30, "profile": [[0, 1], [40, 0.2]] },
+    { "type": "directional", "direction": [1, -1, 0], "irradiance": [2, 2, 2] },
+    { "type": "spot", "position": [0, 2, 0], "direction": [0, -1, 0], "intensity": [5, 5, 5],
+      "angle": 60, "profile": "missing.ies" }
+  ]
+}"#;
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+    assert_eq!(Some(10), error.line);
+
+    let source = source.replace(r#",
+    { "type": "spot", "position": [0, 2, 0], "direction": [0, -1, 0], "intensity": [5, 5, 5],
+      "angle": 60, "profile": "missing.ies" }"#, "");
+    let scene = parse_scene(&source, Path::new(".")).unwrap();
+    assert_eq!(3, scene.lights.len());
+    let spot = scene.lights[1].illuminate(&Vec3::new(0.0, 0.0, 0.0)).unwrap();
+    assert!((spot.irradiance.r() - 5.0 / 4.0).abs() < 1e-4);
+  }
+
+  #[test]
+  fn reports_syntax_error_line() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"objects\": [\n    { \"type\": \"cone\" }\n  ]\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+
+    assert_eq!(Some(4), error.line);
+  }
+
+  #[test]
+  fn reports_unknown_material_line() {
+    let source = "{\n  \"camera\": { \"look_from\": [0, 0, 0], \"look_at\": [0, 0, -1], \"vfov\": 40 },\n  \"objects\": [\n    { \"type\": \"sphere\", \"center\": [0, 0, -1], \"radius\": 0.5, \"material\": \"gold\" }\n  ]\n}";
+    let error = parse_scene(source, Path::new(".")).err().unwrap();
+
+    assert_eq!(Some(4), error.line);
+    assert_eq!("line 4: unknown material \"gold\"", error.to_string());
+  }
+}
@@ -0,0 +1,361 @@
+extern crate rand;
+
+use std::f32;
+
+use ::vec3::*;
+use ray::Ray;
+use onb::Onb;
+use environment_map::{EnvironmentMap, luminance};
+use scene::SceneEnvironment;
+
+/// Angular radius of the sun's disc, in radians.
+const SUN_ANGULAR_RADIUS: f32 = 0.004_65;
+/// Luminance of the sun above the atmosphere, in cd/m².
+const SUN_LUMINANCE: f32 = 1.6e9;
+/// Radiance per cd/m² of luminance, chosen so that a white surface lit by a
+/// clear noon sky comes out at about 1.
+const RADIANCE_PER_NIT: f32 = 2.5e-5;
+/// Wavelengths taken to stand for red, green and blue, in micrometers.
+const WAVELENGTHS: [f32; 3] = [0.68, 0.55, 0.44];
+/// How far below the horizon the sun is, in degrees, when the sky has faded
+/// to black, at the end of civil twilight.
+const TWILIGHT_DEPTH: f32 = 6.0;
+
+/// Daylight from the analytic model of Preetham, Shirley and Smits, "A
+/// Practical Analytic Model for Daylight", with the sun as a small, very
+/// bright disc. Below the horizon is flat ground, lit by the sun and sky.
+///
+/// Both the sun and the sky are sampled like lights, the sky by a tabulated
+/// copy of it.
+pub struct PhysicalSky {
+  model: Preetham,
+  sun_radiance: Vec3,
+  ground: Vec3,
+  pub intensity: f32,
+  sky_sampler: EnvironmentMap,
+  // How likely the sun is to be sampled rather than the sky
+  sun_probability: f32
+}
+
+impl PhysicalSky {
+  /// The sky with the sun towards `sun_direction`, seen through air of
+  /// `turbidity`, from 2 for a very clear day to 10 for haze, over ground
+  /// reflecting `ground_albedo` of the light falling on it.
+  ///
+  /// The model only holds for a sun above the horizon. Below it, the sky
+  /// keeps the colors of sunset and fades to black by the time the sun is
+  /// 6° down.
+  pub fn new(sun_direction: Vec3, turbidity: f32, ground_albedo: f32) -> PhysicalSky {
+    let model = Preetham::new(unit_vector(sun_direction), turbidity.max(1.7).min(10.0));
+    let sun = model.sun;
+    let sun_radiance = if sun.y() > -SUN_ANGULAR_RADIUS { model.sun_radiance() } else { Vec3::new(0.0, 0.0, 0.0) };
+    let sun_solid_angle = 2.0 * f32::consts::PI * sun_cone();
+
+    // Light falling on the ground from the sky above and from the sun
+    let (n, m) = (64, 128);
+    let (mut irradiance, mut sky_power) = (Vec3::new(0.0, 0.0, 0.0), 0.0);
+    for i in 0..n {
+      let theta = 0.5 * f32::consts::PI * (i as f32 + 0.5) / n as f32;
+      let solid_angle = theta.sin() * (0.5 * f32::consts::PI / n as f32) * (2.0 * f32::consts::PI / m as f32);
+      for j in 0..m {
+        let phi = 2.0 * f32::consts::PI * (j as f32 + 0.5) / m as f32;
+        let color = model.sky(&Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()));
+        irradiance += color * (theta.cos() * solid_angle);
+        sky_power += luminance(&color) * solid_angle;
+      }
+    }
+    irradiance += sun_radiance * (sun_solid_angle * sun.y().max(0.0));
+    let ground = irradiance * (ground_albedo / f32::consts::PI);
+    sky_power += luminance(&ground) * 2.0 * f32::consts::PI;
+
+    let sun_power = luminance(&sun_radiance) * sun_solid_angle;
+    let sky_sampler = EnvironmentMap::tabulate(256, 128, |d| if d.y() < 0.0 { ground } else { model.sky(d) });
+
+    PhysicalSky {
+      model,
+      sun_radiance,
+      ground,
+      intensity: 1.0,
+      sky_sampler,
+      // Nothing is left to sample at night
+      sun_probability: if sun_power > 0.0 { sun_power / (sun_power + sky_power) } else { 0.0 }
+    }
+  }
+
+  pub fn with_intensity(self, intensity: f32) -> PhysicalSky {
+    PhysicalSky { intensity, ..self }
+  }
+
+  /// Unit direction towards the sun.
+  pub fn sun_direction(&self) -> Vec3 {
+    self.model.sun
+  }
+
+  fn in_sun(&self, d: &Vec3) -> bool {
+    // The cross product stays accurate for the tiny angles involved
+    d.y() >= 0.0 && d.dot(self.model.sun) > 0.0 && d.cross(self.model.sun).squared_length() <= SUN_ANGULAR_RADIUS.sin().powi(2)
+  }
+}
+
+impl SceneEnvironment for PhysicalSky {
+  fn color(&self, r: &Ray) -> Vec3 {
+    let d = unit_vector(r.direction);
+    let mut color = if d.y() < 0.0 { self.ground } else { self.model.sky(&d) };
+    if self.in_sun(&d) {
+      color += self.sun_radiance;
+    }
+
+    self.intensity * color
+  }
+
+  fn is_light(&self) -> bool {
+    self.sun_probability > 0.0 || self.sky_sampler.is_light()
+  }
+
+  fn sample(&self) -> Option<Vec3> {
+    if rand::random::<f32>() >= self.sun_probability {
+      return self.sky_sampler.sample();
+    }
+
+    // Uniformly within the cone of the sun's disc, working with one minus the
+    // cosine to keep its precision
+    let t = rand::random::<f32>() * sun_cone();
+    let phi = 2.0 * f32::consts::PI * rand::random::<f32>();
+    let sin_theta = (t * (2.0 - t)).sqrt();
+    Some(Onb::build_from_w(self.model.sun).local(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), 1.0 - t)))
+  }
+
+  fn pdf(&self, direction: &Vec3) -> f32 {
+    let d = unit_vector(*direction);
+    let sun_pdf = if self.in_sun(&d) { 1.0 / (2.0 * f32::consts::PI * sun_cone()) } else { 0.0 };
+    self.sun_probability * sun_pdf + (1.0 - self.sun_probability) * self.sky_sampler.pdf(&d)
+  }
+}
+
+// One minus the cosine of the sun's angular radius
+fn sun_cone() -> f32 {
+  2.0 * (0.5 * SUN_ANGULAR_RADIUS).sin().powi(2)
+}
+
+/// Unit direction towards the sun at `elevation` degrees above the horizon
+/// and `azimuth` degrees clockwise from north, where north is -z and east +x.
+pub fn sun_direction(elevation: f32, azimuth: f32) -> Vec3 {
+  let (e, a) = (elevation.to_radians(), azimuth.to_radians());
+  Vec3::new(e.cos() * a.sin(), e.sin(), -e.cos() * a.cos())
+}
+
+/// Unit direction towards the sun at `latitude` degrees north, on `day` of
+/// the year and at `hour` of local solar time, so that the sun is highest at
+/// 12, with north being -z.
+pub fn solar_direction(latitude: f32, day: f32, hour: f32) -> Vec3 {
+  let declination = -23.44f32.to_radians() * (2.0 * f32::consts::PI * (day + 10.0) / 365.0).cos();
+  let hour_angle = (15.0 * (hour - 12.0)).to_radians();
+  let latitude = latitude.to_radians();
+
+  let sin_elevation = latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+  let azimuth = (-hour_angle.sin() * declination.cos())
+    .atan2(declination.sin() * latitude.cos() - declination.cos() * latitude.sin() * hour_angle.cos());
+  sun_direction(sin_elevation.max(-1.0).min(1.0).asin().to_degrees(), azimuth.to_degrees())
+}
+
+// The sky's luminance and chromaticity, fitted by Preetham et al. as
+// functions of the turbidity and the sun's angle from the zenith
+struct Preetham {
+  sun: Vec3,
+  turbidity: f32,
+  // Angle of the sun from the zenith, no further than the horizon
+  theta_sun: f32,
+  // How much of the sky's light is left, fading out as the sun sets further
+  // below the horizon
+  twilight: f32,
+  // Coefficients of Perez' sky function for luminance, x and y
+  perez: [[f32; 5]; 3],
+  // Luminance, in kcd/m², x and y at the zenith
+  zenith: [f32; 3]
+}
+
+impl Preetham {
+  fn new(sun: Vec3, t: f32) -> Preetham {
+    let theta = sun.y().max(0.0).min(1.0).acos();
+    // How far through twilight the sun is, from 0 above the horizon to 1
+    let depth = ((-sun.y()).min(1.0).asin().to_degrees() / TWILIGHT_DEPTH).max(0.0);
+    let twilight = if depth < 1.0 { (1.0 - depth) * (1.0 - depth) * (1.0 + 2.0 * depth) } else { 0.0 };
+    let (theta2, theta3) = (theta * theta, theta * theta * theta);
+
+    let chi = (4.0 / 9.0 - t / 120.0) * (f32::consts::PI - 2.0 * theta);
+    let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+    let x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta) +
+      t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394) +
+      (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+    let y = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta) +
+      t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516) +
+      (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+
+    Preetham {
+      sun,
+      turbidity: t,
+      theta_sun: theta,
+      twilight,
+      perez: [
+        [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+        [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+        [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]
+      ],
+      zenith: [luminance, x, y]
+    }
+  }
+
+  fn perez(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    (1.0 + c[0] * (c[1] / cos_theta.max(1e-3)).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+  }
+
+  // Radiance of the sky, not counting the sun, in a direction above the horizon
+  fn sky(&self, d: &Vec3) -> Vec3 {
+    let gamma = d.dot(self.sun).max(-1.0).min(1.0).acos();
+    let mut values = [0.0; 3];
+    for (i, value) in values.iter_mut().enumerate() {
+      *value = self.zenith[i] * Preetham::perez(&self.perez[i], d.y(), gamma) /
+        Preetham::perez(&self.perez[i], 1.0, self.theta_sun);
+    }
+
+    let (luminance, x, y) = (self.twilight * values[0] * 1000.0 * RADIANCE_PER_NIT, values[1], values[2]);
+    xyz_to_rgb(x / y * luminance, luminance, (1.0 - x - y) / y * luminance)
+  }
+
+  // Radiance of the sun, dimmed and reddened by the air it shines through:
+  // Rayleigh scattering by the air itself and Ångström's model of haze
+  fn sun_radiance(&self) -> Vec3 {
+    let degrees = self.theta_sun.to_degrees();
+    let air_mass = 1.0 / (self.theta_sun.cos() + 0.15 * (93.885 - degrees).powf(-1.253));
+    let beta = 0.04608 * self.turbidity - 0.04586;
+
+    let mut transmittance = [0.0; 3];
+    for (i, &lambda) in WAVELENGTHS.iter().enumerate() {
+      let rayleigh = 0.008735 * lambda.powf(-4.08);
+      let haze = beta * lambda.powf(-1.3);
+      transmittance[i] = (-air_mass * (rayleigh + haze)).exp();
+    }
+
+    SUN_LUMINANCE * RADIANCE_PER_NIT * Vec3::new(transmittance[0], transmittance[1], transmittance[2])
+  }
+}
+
+// CIE XYZ to linear sRGB, leaving out colors outside of it
+fn xyz_to_rgb(x: f32, y: f32, z: f32) -> Vec3 {
+  Vec3::new(
+    (3.2406 * x - 1.5372 * y - 0.4986 * z).max(0.0),
+    (-0.9689 * x + 1.8758 * y + 0.0415 * z).max(0.0),
+    (0.0557 * x - 0.2040 * y + 1.0570 * z).max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ray(d: Vec3) -> Ray {
+    Ray::new(Vec3::new(0.0, 0.0, 0.0), d)
+  }
+
+  #[test]
+  fn sky_is_blue_and_brightest_around_the_sun() {
+    let sky = PhysicalSky::new(sun_direction(40.0, 180.0), 3.0, 0.3);
+
+    let zenith = sky.color(&ray(Vec3::new(0.0, 1.0, 0.0)));
+    assert!(zenith.b() > zenith.r(), "zenith is {:?}", zenith);
+
+    let near_sun = sky.color(&ray(sun_direction(45.0, 180.0)));
+    let away = sky.color(&ray(sun_direction(45.0, 0.0)));
+    assert!(luminance(&near_sun) > 2.0 * luminance(&away), "{:?} next to the sun, {:?} away from it", near_sun, away);
+
+    let sun = sky.color(&ray(sky.sun_direction()));
+    assert!(luminance(&sun) > 1000.0 * luminance(&near_sun), "sun is {:?}", sun);
+  }
+
+  #[test]
+  fn setting_sun_is_redder_and_dimmer() {
+    let noon = PhysicalSky::new(sun_direction(70.0, 180.0), 3.0, 0.3);
+    let sunset = PhysicalSky::new(sun_direction(2.0, 270.0), 3.0, 0.3);
+    let (noon, sunset) = (noon.sun_radiance, sunset.sun_radiance);
+
+    assert!(luminance(&sunset) < 0.5 * luminance(&noon));
+    assert!(sunset.r() / sunset.b() > 2.0 * noon.r() / noon.b(), "noon sun {:?}, setting sun {:?}", noon, sunset);
+  }
+
+  #[test]
+  fn sky_fades_to_black_after_sunset() {
+    let brightness = |elevation: f32| {
+      let sky = PhysicalSky::new(sun_direction(elevation, 270.0), 3.0, 0.3);
+      (luminance(&sky.color(&ray(Vec3::new(0.0, 1.0, 0.0)))), luminance(&sky.color(&ray(Vec3::new(0.0, -1.0, 0.0)))))
+    };
+
+    let (sunset, sunset_ground) = brightness(0.0);
+    let (dusk, dusk_ground) = brightness(-3.0);
+    assert!(dusk > 0.0 && dusk < 0.75 * sunset, "zenith {} at sunset, {} at dusk", sunset, dusk);
+    assert!(dusk_ground < 0.75 * sunset_ground, "ground {} at sunset, {} at dusk", sunset_ground, dusk_ground);
+
+    let night = PhysicalSky::new(sun_direction(-30.0, 0.0), 3.0, 0.3);
+    assert_eq!((0.0, 0.0), brightness(-30.0));
+    assert!(!night.is_light());
+    assert!(night.sample().is_none());
+  }
+
+  #[test]
+  fn ground_reflects_the_light_falling_on_it() {
+    let sky = PhysicalSky::new(sun_direction(50.0, 120.0), 2.5, 0.4);
+
+    // Estimating the irradiance with the sky's own sampling
+    let n = 200000;
+    let mut irradiance = Vec3::new(0.0, 0.0, 0.0);
+    for _ in 0..n {
+      let d = sky.sample().unwrap();
+      if d.y() > 0.0 {
+        irradiance += sky.color(&ray(d)) * (d.y() / sky.pdf(&d));
+      }
+    }
+    irradiance /= n as f32;
+
+    let expected = irradiance * (0.4 / f32::consts::PI);
+    let ground = sky.color(&ray(Vec3::new(0.3, -1.0, 0.2)));
+    for c in 0..3 {
+      assert!((ground[c] - expected[c]).abs() < 0.03 * expected[c], "ground {:?}, expected {:?}", ground, expected);
+    }
+  }
+
+  #[test]
+  fn sampling_covers_the_sphere_and_the_sun() {
+    let sky = PhysicalSky::new(sun_direction(30.0, 60.0), 4.0, 0.2);
+
+    // Expected values of 1 / pdf, over all samples and over those in the sun
+    let n = 200000;
+    let (mut sphere, mut sun) = (0.0, 0.0);
+    for _ in 0..n {
+      let d = sky.sample().unwrap();
+      let pdf = sky.pdf(&d);
+      assert!(pdf > 0.0, "sampled a direction of no density {:?}", d);
+      sphere += 1.0 / pdf;
+      // Checked just like `pdf` does, so that directions at the very edge of
+      // the sun agree on which side of it they are
+      if sky.in_sun(&unit_vector(d)) {
+        sun += 1.0 / pdf;
+      }
+    }
+
+    let (sphere, sun) = (sphere / n as f32, sun / n as f32);
+    assert!((sphere - 4.0 * f32::consts::PI).abs() < 0.05 * 4.0 * f32::consts::PI, "sphere of {} sr", sphere);
+    let disc = 2.0 * f32::consts::PI * sun_cone();
+    assert!((sun - disc).abs() < 0.02 * disc, "sun of {} sr, expected {}", sun, disc);
+  }
+
+  #[test]
+  fn sun_follows_the_time_of_day() {
+    // Overhead at noon on the equator at an equinox
+    let d = solar_direction(0.0, 80.0, 12.0);
+    assert!(d.y() > 0.999, "{:?}", d);
+
+    // Due south at noon in a northern summer, rising in the east
+    let d = solar_direction(60.0, 172.0, 12.0);
+    assert!((d.y().asin().to_degrees() - 53.44).abs() < 0.1 && d.z() > 0.0 && d.x().abs() < 1e-4, "{:?}", d);
+    let d = solar_direction(60.0, 172.0, 6.0);
+    assert!(d.x() > 0.5, "{:?}", d);
+  }
+}
@@ -0,0 +1,216 @@
+//! Turning the linear light of a render into colors for a display: exposure,
+//! a tone mapping operator compressing bright light into the displayable
+//! range, and the sRGB transfer function.
+
+use ::vec3::Vec3;
+use ::environment_map::luminance;
+
+/// How light beyond what a display shows is brought into its range.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMapper {
+  /// No tone mapping, anything too bright is clipped.
+  Clamp,
+  /// Reinhard's operator on luminance, `L / (1 + L)`, which never quite
+  /// reaches white.
+  Reinhard,
+  /// Reinhard's operator extended so that luminance `white` and above is
+  /// mapped to white.
+  ExtendedReinhard { white: f32 },
+  /// Stephen Hill's fit of the ACES filmic reference and output transforms,
+  /// with contrasty, saturated highlights.
+  Aces,
+  /// A fit of Troy Sobotka's AgX, which desaturates highlights towards white
+  /// the way film does.
+  Agx
+}
+
+/// Exposure and tone mapping, taking linear light to display colors.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DisplayTransform {
+  /// Exposure adjustment in stops, each doubling the light
+  pub exposure: f32,
+  pub tone_mapper: ToneMapper
+}
+
+impl Default for DisplayTransform {
+  fn default() -> DisplayTransform {
+    DisplayTransform { exposure: 0.0, tone_mapper: ToneMapper::Clamp }
+  }
+}
+
+impl DisplayTransform {
+  pub fn new(tone_mapper: ToneMapper) -> DisplayTransform {
+    DisplayTransform { tone_mapper, ..DisplayTransform::default() }
+  }
+
+  pub fn with_exposure(self, exposure: f32) -> DisplayTransform {
+    DisplayTransform { exposure, ..self }
+  }
+
+  /// The linear display color, between 0 and 1, showing `color`.
+  pub fn apply(&self, color: Vec3) -> Vec3 {
+    let c = 2f32.powf(self.exposure) * Vec3::new(color[0].max(0.0), color[1].max(0.0), color[2].max(0.0));
+    let mapped = match self.tone_mapper {
+      ToneMapper::Clamp => c,
+      ToneMapper::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+      ToneMapper::ExtendedReinhard { white } => scale_luminance(c, |l| l * (1.0 + l / (white * white)) / (1.0 + l)),
+      ToneMapper::Aces => aces(c),
+      ToneMapper::Agx => agx(c)
+    };
+
+    Vec3::new(saturate(mapped[0]), saturate(mapped[1]), saturate(mapped[2]))
+  }
+
+  /// The 8-bit sRGB encoded display color showing `color`.
+  pub fn to_srgb8(&self, color: Vec3) -> [u8; 3] {
+    let c = self.apply(color);
+    let encode = |v: f32| (255.0 * linear_to_srgb(v) + 0.5) as u8;
+    [encode(c[0]), encode(c[1]), encode(c[2])]
+  }
+}
+
+/// The sRGB transfer function, encoding a linear value between 0 and 1 the
+/// way displays expect it.
+pub fn linear_to_srgb(v: f32) -> f32 {
+  if v <= 0.003_130_8 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn saturate(v: f32) -> f32 {
+  if v.is_nan() { 0.0 } else { v.max(0.0).min(1.0) }
+}
+
+// Maps the luminance of `c` by `f`, keeping its hue and saturation
+fn scale_luminance<F: Fn(f32) -> f32>(c: Vec3, f: F) -> Vec3 {
+  let l = luminance(&c);
+  if l <= 0.0 { c } else { c * (f(l) / l) }
+}
+
+fn multiply(m: &[[f32; 3]; 3], c: Vec3) -> Vec3 {
+  Vec3::new(
+    m[0][0] * c[0] + m[0][1] * c[1] + m[0][2] * c[2],
+    m[1][0] * c[0] + m[1][1] * c[1] + m[1][2] * c[2],
+    m[2][0] * c[0] + m[2][1] * c[1] + m[2][2] * c[2])
+}
+
+fn aces(c: Vec3) -> Vec3 {
+  // From linear sRGB to the ACES working space, with the reference
+  // transform's saturation tweak, and back
+  const INPUT: [[f32; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777]];
+  const OUTPUT: [[f32; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602]];
+
+  let v = multiply(&INPUT, c);
+  let curve = |v: f32| (v * (v + 0.024_578_6) - 0.000_090_537) / (v * (0.983_729 * v + 0.432_951) + 0.238_081);
+  multiply(&OUTPUT, Vec3::new(curve(v[0]), curve(v[1]), curve(v[2])))
+}
+
+fn agx(c: Vec3) -> Vec3 {
+  // Into AgX's slightly desaturated working space, then a sigmoid over a
+  // range of stops around middle grey, then back to linear light
+  const INSET: [[f32; 3]; 3] = [
+    [0.842_479, 0.078_434, 0.079_224],
+    [0.042_328, 0.878_469, 0.079_166],
+    [0.042_376, 0.078_434, 0.879_143]];
+  const OUTSET: [[f32; 3]; 3] = [
+    [1.196_879, -0.098_021, -0.099_030],
+    [-0.052_897, 1.151_903, -0.098_961],
+    [-0.052_972, -0.098_043, 1.151_074]];
+  const MIN_EV: f32 = -12.473_93;
+  const MAX_EV: f32 = 4.026_069;
+
+  let v = multiply(&INSET, c);
+  let curve = |v: f32| {
+    let x = (v.max(1e-10).log2().max(MIN_EV).min(MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+    let (x2, x4) = (x * x, x * x * x * x);
+    15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+  };
+
+  let display = multiply(&OUTSET, Vec3::new(curve(v[0]), curve(v[1]), curve(v[2])));
+  Vec3::new(display[0].max(0.0).powf(2.2), display[1].max(0.0).powf(2.2), display[2].max(0.0).powf(2.2))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close(expected: f32, actual: f32, tolerance: f32) {
+    assert!((expected - actual).abs() < tolerance, "expected {}, got {}", expected, actual);
+  }
+
+  fn gray(v: f32) -> Vec3 {
+    Vec3::new(v, v, v)
+  }
+
+  const MAPPERS: [ToneMapper; 5] = [
+    ToneMapper::Clamp, ToneMapper::Reinhard, ToneMapper::ExtendedReinhard { white: 4.0 }, ToneMapper::Aces, ToneMapper::Agx];
+
+  #[test]
+  fn encodes_srgb() {
+    assert_close(0.0, linear_to_srgb(0.0), 1e-6);
+    assert_close(1.0, linear_to_srgb(1.0), 1e-5);
+    assert_close(0.7354, linear_to_srgb(0.5), 1e-4);
+    // Both pieces meet
+    assert_close(12.92 * 0.003_130_8, 1.055 * 0.003_130_8f32.powf(1.0 / 2.4) - 0.055, 1e-6);
+
+    let transform = DisplayTransform::default();
+    assert_eq!([188, 0, 255], transform.to_srgb8(Vec3::new(0.5029, -1.0, 7.0)));
+  }
+
+  #[test]
+  fn exposure_doubles_light_per_stop() {
+    let transform = DisplayTransform::default().with_exposure(2.0);
+    assert_close(0.4, transform.apply(gray(0.1))[0], 1e-6);
+    assert_close(0.05, transform.with_exposure(-1.0).apply(gray(0.1))[1], 1e-6);
+  }
+
+  #[test]
+  fn reinhard_operators_compress_luminance() {
+    let reinhard = DisplayTransform::new(ToneMapper::Reinhard);
+    assert_close(0.5, reinhard.apply(gray(1.0))[0], 1e-5);
+    assert_close(0.9, reinhard.apply(gray(9.0))[0], 1e-5);
+
+    // Hue is kept
+    let orange = reinhard.apply(Vec3::new(1.0, 0.5, 0.0));
+    assert_close(0.5, orange[1] / orange[0], 1e-5);
+
+    let extended = DisplayTransform::new(ToneMapper::ExtendedReinhard { white: 4.0 });
+    assert_close(1.0, extended.apply(gray(4.0))[0], 1e-5);
+    assert!(extended.apply(gray(1.0))[0] > reinhard.apply(gray(1.0))[0]);
+  }
+
+  #[test]
+  fn filmic_operators_roll_off_highlights() {
+    for &mapper in &[ToneMapper::Aces, ToneMapper::Agx] {
+      let transform = DisplayTransform::new(mapper);
+      assert!(transform.apply(gray(0.0))[0] < 0.01, "{:?} lifts black", mapper);
+      assert!(transform.apply(gray(100.0))[0] > 0.95, "{:?} doesn't reach white", mapper);
+
+      // Middle grey comes out somewhere in the middle
+      let middle = transform.apply(gray(0.18));
+      assert!(middle[0] > 0.08 && middle[0] < 0.3, "{:?} maps middle grey to {:?}", mapper, middle);
+      assert_close(middle[0], middle[2], 0.01);
+    }
+
+    // AgX takes saturated highlights towards white
+    let agx = DisplayTransform::new(ToneMapper::Agx).apply(Vec3::new(50.0, 5.0, 0.5));
+    assert!(agx[2] > 0.3, "{:?}", agx);
+  }
+
+  #[test]
+  fn tone_mapping_is_monotonic() {
+    for &mapper in MAPPERS.iter() {
+      let transform = DisplayTransform::new(mapper);
+      let mut previous = -1.0;
+      for i in 0..200 {
+        let v = transform.apply(gray(2f32.powf(i as f32 / 10.0 - 10.0)))[0];
+        assert!(v >= previous - 1e-4, "{:?} decreases at step {}", mapper, i);
+        previous = v;
+      }
+    }
+  }
+}
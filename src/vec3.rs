@@ -193,7 +193,7 @@ pub fn unit_vector(v: Vec3) -> Vec3 {
 mod tests {
   use super::*;
 
-  #[test()]
+  #[test]
   fn cross_product1() {
     let a = Vec3::new(1.0, 0.0, 0.0);
     let b = Vec3::new(0.0, 1.0, 0.0);
@@ -204,7 +204,7 @@ mod tests {
     assert_eq!(1.0, c.z());
   }
 
-  #[test()]
+  #[test]
   fn cross_product2() {
     let a = Vec3::new(2.0, 3.0, 4.0);
     let b = Vec3::new(5.0, 6.0, 7.0);
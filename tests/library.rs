@@ -0,0 +1,118 @@
+extern crate rt_in_1_week;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use rt_in_1_week::*;
+use rt_in_1_week::scene_file::{load_scene_file, parse_scene};
+use rt_in_1_week::bvh::BvhTree;
+
+fn camera_looking_down_z(aspect: f32) -> Camera {
+  Camera::new(
+    Vec3::new(0.0, 0.0, 0.0),
+    Vec3::new(0.0, 0.0, -1.0),
+    Vec3::new(0.0, 1.0, 0.0),
+    90.0,
+    aspect,
+    0.0,
+    1.0)
+}
+
+fn assert_color(expected: Vec3, actual: Vec3) {
+  assert!((expected - actual).length() < 1e-5, "expected {:?}, got {:?}", expected, actual);
+}
+
+#[test]
+fn renders_framebuffer_of_requested_size() {
+  let world: Vec<Box<Hitable>> = vec![
+    Box::new(Sphere {
+      center: Vec3::new(0.0, 0.0, -1.0),
+      radius: 0.5,
+      material: Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) })
+    })
+  ];
+  let scene = Scene::new(world, Box::new(SimpleSky {}), 5);
+
+  let image = render(&scene, &camera_looking_down_z(2.0), 16, 8, 1);
+
+  assert_eq!(16, image.width);
+  assert_eq!(8, image.height);
+  assert_eq!(16 * 8, image.pixels.len());
+}
+
+#[test]
+fn renders_emitter_and_environment() {
+  // A light filling the middle of the view, surrounded by black
+  let world: Vec<Box<Hitable>> = vec![
+    Box::new(XyRect {
+      x0: -0.5, x1: 0.5, y0: -0.5, y1: 0.5, k: -1.0,
+      material: Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(1.0, 0.25, 0.0)) })
+    })
+  ];
+  let scene = Scene::new(world, Box::new(Void {}), 5);
+
+  let image = render(&scene, &camera_looking_down_z(1.0), 8, 8, 4);
+
+  assert_color(Vec3::new(1.0, 0.25, 0.0), image.pixel(4, 4));
+  assert_color(Vec3::new(0.0, 0.0, 0.0), image.pixel(0, 0));
+}
+
+// The light from `renders_emitter_and_environment`, shared by two nested BVHs
+fn nested_light_scene() -> Scene {
+  let light: Vec<Box<Hitable>> = vec![
+    Box::new(XyRect {
+      x0: -0.5, x1: 0.5, y0: -0.5, y1: 0.5, k: -1.0,
+      material: Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(1.0, 0.25, 0.0)) })
+    })
+  ];
+  let shared = Arc::new(BvhTree::new(light));
+  let world: Vec<Box<Hitable>> = vec![
+    Box::new(shared.clone()),
+    Box::new(BvhTree::new(vec![Box::new(shared) as Box<Hitable>]))
+  ];
+
+  Scene::new(world, Box::new(Void {}), 5)
+}
+
+#[test]
+fn renders_nested_bvhs_on_another_thread() {
+  let scene = nested_light_scene();
+  assert_eq!(2, scene.lights().len());
+
+  let image = thread::spawn(move || render(&scene, &camera_looking_down_z(1.0), 8, 8, 4)).join().unwrap();
+
+  assert_color(Vec3::new(1.0, 0.25, 0.0), image.pixel(4, 4));
+  assert_color(Vec3::new(0.0, 0.0, 0.0), image.pixel(0, 0));
+}
+
+#[test]
+fn renders_scene_file() {
+  let description = load_scene_file(Path::new("scenes/cornell.json")).unwrap();
+  let camera = description.camera(1.0);
+  let scene = Scene::new(description.models, description.environment, 3);
+
+  let image = render(&scene, &camera, 4, 4, 1);
+  let path = env::temp_dir().join("rt-in-1-week-library-test.png");
+  image.write_png(&path, &DisplayTransform::default()).unwrap();
+
+  assert!(fs::metadata(&path).unwrap().len() > 0);
+  fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn renders_scene_with_no_objects() {
+  let source = r#"{
+  "camera": { "look_from": [0, 0, 0], "look_at": [0, 0, -1], "vfov": 40 },
+  "environment": { "type": "sky", "sun": { "elevation": 30, "azimuth": 90 } },
+  "objects": []
+}"#;
+  let description = parse_scene(source, Path::new(".")).unwrap();
+  let camera = description.camera(1.0);
+  let scene = Scene::new(description.models, description.environment, 3);
+
+  let image = render(&scene, &camera, 2, 2, 1);
+  assert!(image.pixel(0, 0)[2] > 0.0);
+}
@@ -0,0 +1,147 @@
+extern crate rt_in_1_week;
+
+use std::sync::Arc;
+
+use rt_in_1_week::*;
+
+// Renders a single pixel looking straight down at a white-ish floor, lit only
+// by `light`, and returns its radiance.
+fn floor_radiance(light: Box<Hitable>) -> f32 {
+  floor_radiance_in(vec![light], Box::new(Void {}))
+}
+
+fn floor_radiance_in(world: Vec<Box<Hitable>>, environment: Box<SceneEnvironment>) -> f32 {
+  floor_radiance_lit_by(world, environment, vec![])
+}
+
+fn floor_radiance_lit_by(mut world: Vec<Box<Hitable>>, environment: Box<SceneEnvironment>, lights: Vec<Box<PunctualLight>>) -> f32 {
+  let floor = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.5, 0.5, 0.5)) });
+  world.push(Box::new(XzRect { x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0, material: floor }));
+  let scene = Scene::new(world, environment, 5).with_punctual_lights(lights);
+  let camera = Camera::new(
+    Vec3::new(0.0, 0.5, 0.0),
+    Vec3::new(0.0, 0.0, 0.0),
+    Vec3::new(0.0, 0.0, 1.0),
+    1.0,
+    1.0,
+    0.0,
+    0.5);
+
+  let image = render(&scene, &camera, 1, 1, 4096);
+  image.pixel(0, 0)[0]
+}
+
+#[test]
+fn rect_light_matches_form_factor() {
+  let light = Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(2.0, 2.0, 2.0)) });
+  let radiance = floor_radiance(Box::new(XzRect { x0: -0.5, x1: 0.5, z0: -0.5, z1: 0.5, k: 1.0, material: light }));
+
+  // Form factor from a point to a parallel 1x1 square centered one unit above it
+  let x = 0.5f32 / (1.0f32 + 0.25).sqrt();
+  let form_factor = 4.0 * 2.0 * x * x.atan() / (2.0 * std::f32::consts::PI);
+  let expected = 0.5 * 2.0 * form_factor;
+
+  assert!((radiance - expected).abs() < 0.01, "expected {}, got {}", expected, radiance);
+}
+
+#[test]
+fn sphere_light_matches_form_factor() {
+  let light = Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(8.0, 8.0, 8.0)) });
+  let radiance = floor_radiance(Box::new(Sphere { center: Vec3::new(0.0, 1.0, 0.0), radius: 0.25, material: light }));
+
+  // Form factor from a point to a sphere straight above it is (r / h)^2
+  let expected = 0.5 * 8.0 * 0.25 * 0.25;
+
+  assert!((radiance - expected).abs() < 0.01, "expected {}, got {}", expected, radiance);
+}
+
+#[test]
+fn environment_map_matches_irradiance() {
+  // A dim environment with a bright patch high up in the sky
+  let (width, height) = (64, 32);
+  let mut pixels = vec![Vec3::new(0.2, 0.2, 0.2); width * height];
+  for y in 2..6 {
+    for x in 10..20 {
+      pixels[y * width + x] = Vec3::new(20.0, 20.0, 20.0);
+    }
+  }
+
+  // Irradiance from the upper half of the environment, pixel by pixel
+  let mut irradiance = 0.0;
+  for y in 0..height / 2 {
+    let theta = std::f32::consts::PI * (y as f32 + 0.5) / height as f32;
+    let solid_angle = (2.0 * std::f32::consts::PI / width as f32) * (std::f32::consts::PI / height as f32) * theta.sin();
+    for x in 0..width {
+      irradiance += pixels[y * width + x][0] * theta.cos() * solid_angle;
+    }
+  }
+  let expected = 0.5 * irradiance / std::f32::consts::PI;
+
+  let environment = EnvironmentMap::new(Arc::new(Image::new(width, height, pixels)));
+  let radiance = floor_radiance_in(vec![], Box::new(environment));
+
+  assert!((radiance - expected).abs() < 0.02, "expected {}, got {}", expected, radiance);
+}
+
+#[test]
+fn lights_and_environment_map_add_up() {
+  let x = 0.5f32 / (1.0f32 + 0.25).sqrt();
+  let form_factor = 4.0 * 2.0 * x * x.atan() / (2.0 * std::f32::consts::PI);
+
+  // The light hides the part of the environment behind it
+  let environment = EnvironmentMap::new(Arc::new(Image::new(8, 4, vec![Vec3::new(0.4, 0.4, 0.4); 32])));
+  let light = Arc::new(DiffuseLight { emit: Box::new(ConstantTexture::new(2.0, 2.0, 2.0)) });
+  let radiance = floor_radiance_in(
+    vec![Box::new(XzRect { x0: -0.5, x1: 0.5, z0: -0.5, z1: 0.5, k: 1.0, material: light })],
+    Box::new(environment));
+  let expected = 0.5 * (2.0 * form_factor + 0.4 * (1.0 - form_factor));
+
+  assert!((radiance - expected).abs() < 0.01, "expected {}, got {}", expected, radiance);
+}
+
+#[test]
+fn punctual_lights_light_the_floor_unless_shadowed() {
+  // A point light two units straight above, and a directional light at 60
+  // degrees from the floor's normal
+  let lights: Vec<Box<PunctualLight>> = vec![
+    Box::new(PointLight { position: Vec3::new(0.0, 2.0, 0.0), intensity: Vec3::new(2.0, 2.0, 2.0) }),
+    Box::new(DirectionalLight::new(Vec3::new(3f32.sqrt(), -1.0, 0.0), Vec3::new(1.0, 1.0, 1.0)))
+  ];
+  let radiance = floor_radiance_lit_by(vec![], Box::new(Void {}), lights);
+  let expected = 0.5 / std::f32::consts::PI * (2.0 / 4.0 + 0.5);
+  assert!((radiance - expected).abs() < 0.01, "expected {}, got {}", expected, radiance);
+
+  // A small sphere above the camera, between the floor and the point light
+  let blocker = Arc::new(Lambertian { albedo: Box::new(ConstantTexture::new(0.0, 0.0, 0.0)) });
+  let lights: Vec<Box<PunctualLight>> = vec![
+    Box::new(PointLight { position: Vec3::new(0.0, 2.0, 0.0), intensity: Vec3::new(2.0, 2.0, 2.0) })
+  ];
+  let shadowed = floor_radiance_lit_by(
+    vec![Box::new(Sphere { center: Vec3::new(0.0, 1.5, 0.0), radius: 0.05, material: blocker })],
+    Box::new(Void {}), lights);
+  assert!(shadowed < 1e-3, "got {} in the shadow", shadowed);
+}
+
+#[test]
+fn rough_conductor_reflects_all_of_a_point_light() {
+  // Seen at a grazing angle, many of the directions a rough metal picks for
+  // itself point into the floor and are thrown away, which mustn't lose the
+  // light sampled directly
+  let metal = Arc::new(Conductor::preset(ConductorPreset::Silver, 0.8));
+  let floor = XzRect { x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0, material: metal.clone() };
+  let light_position = Vec3::new(0.0, 1.0, -1.0);
+  let eye = Vec3::new(0.0, 0.3, 2.0);
+
+  let scene = Scene::new(vec![Box::new(floor)], Box::new(Void {}), 5)
+    .with_punctual_lights(vec![Box::new(PointLight { position: light_position, intensity: Vec3::new(4.0, 4.0, 4.0) })]);
+  let camera = Camera::new(eye, Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.1, 1.0, 0.0, 1.0);
+  let radiance = render(&scene, &camera, 1, 1, 256).pixel(0, 0)[0];
+
+  // With nothing else around, all of the light is reflected straight from the light
+  let rec = scene.bvh.hit(&Ray::new(eye, -eye), 0.001, std::f32::MAX).unwrap();
+  let to_light = light_position - rec.p;
+  let expected = metal.eval(&rec, &unit_vector(to_light), &unit_vector(eye - rec.p))[0] * 4.0 / to_light.squared_length();
+
+  assert!(expected > 0.01);
+  assert!((radiance - expected).abs() < 0.02 * expected, "expected {}, got {}", expected, radiance);
+}